@@ -0,0 +1,133 @@
+//! ublk-null throughput and io_uring_enter() count per IO
+//!
+//! Run as root with ublk driver loaded:
+//!
+//!     cargo bench --bench null -- <secs> <jobs> [per-cqe]
+//!
+//! Each job reads 4K randomly from /dev/ublkbN by O_DIRECT, and the
+//! reported `enters/IO` is the number of io_uring_enter() called by the
+//! queue pthread for each IO.
+//!
+//! COMMIT_AND_FETCH_REQ commands of all CQEs reaped in one batch are
+//! submitted once. `per-cqe` mode emulates submitting for each CQE as
+//! baseline, by submitting SQEs queued so far from the IO closure, and
+//! these submissions are counted in `enters/IO` too.
+
+use libublk::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
+use libublk::{ctrl::UblkCtrl, sys, UblkError};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEV_SIZE: u64 = 1_u64 << 30;
+const BS: usize = 4096;
+
+fn rand_read(dev_path: &str, dur: Duration, ios: &AtomicU64) {
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(dev_path)
+        .unwrap();
+    let buf = libublk::ublk_alloc_buf(BS, BS);
+    let slice = unsafe { std::slice::from_raw_parts_mut(buf, BS) };
+    let mut seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+        | 1;
+    let start = Instant::now();
+
+    while start.elapsed() < dur {
+        // xorshift
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let off = (seed % (DEV_SIZE / BS as u64)) * BS as u64;
+
+        f.read_exact_at(slice, off).unwrap();
+        ios.fetch_add(1, Ordering::Relaxed);
+    }
+    libublk::ublk_dealloc_buf(buf, BS, BS);
+}
+
+/// wait until /dev/ublkbN is created by udev after the device is started
+fn wait_bdev(dev_path: &str) {
+    let start = Instant::now();
+
+    while !Path::new(dev_path).exists() {
+        assert!(start.elapsed() < Duration::from_secs(5), "no {}", dev_path);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn main() {
+    // cargo passes `--bench` to the bench binary
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let secs = args.first().and_then(|s| s.parse().ok()).unwrap_or(5_u64);
+    let jobs = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(4_usize);
+    let per_cqe = args.get(2).is_some_and(|s| s == "per-cqe");
+
+    let sess = libublk::UblkSessionBuilder::default()
+        .name("null")
+        .depth(128_u32)
+        .nr_queues(1_u32)
+        .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+        .build()
+        .unwrap();
+    let tgt_init = |dev: &mut UblkDev| {
+        dev.set_default_params(DEV_SIZE);
+        Ok(serde_json::json!({}))
+    };
+    let submits = Arc::new(AtomicU64::new(0));
+    let _submits = submits.clone();
+    let handle_io = move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+        let iod = ctx.get_iod(io.get_tag());
+
+        if per_cqe {
+            io.get_ring()
+                .submit()
+                .map_err(UblkError::UringSubmissionError)?;
+            _submits.fetch_add(1, Ordering::Relaxed);
+        }
+        io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+        Ok(0)
+    };
+    let ios = Arc::new(AtomicU64::new(0));
+    let _ios = ios.clone();
+
+    let wh = {
+        let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+            let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
+
+            wait_bdev(&dev_path);
+            std::thread::scope(|s| {
+                for _ in 0..jobs {
+                    s.spawn(|| rand_read(&dev_path, Duration::from_secs(secs), &_ios));
+                }
+            });
+            ctrl.del().unwrap();
+        })
+        .unwrap()
+    };
+    wh.join().unwrap();
+
+    let stats = sess.get_stats();
+    let nr_ios = ios.load(Ordering::Relaxed);
+    let reads = stats.op_ios[sys::UBLK_IO_OP_READ as usize].max(1);
+    let enters = stats.ring_enters + submits.load(Ordering::Relaxed);
+    println!(
+        "{} jobs {} iops {} enters {} enters/IO {:.3}",
+        if per_cqe { "per-cqe" } else { "batch" },
+        jobs,
+        nr_ios / secs,
+        enters,
+        enters as f64 / reads as f64
+    );
+}
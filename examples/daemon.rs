@@ -0,0 +1,83 @@
+use libublk::daemon::{UblkDaemonBuilder, UblkDaemonNotifier, UblkFork};
+use libublk::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
+use libublk::{ctrl::UblkCtrl, UblkError};
+
+fn null_add(notifier: &UblkDaemonNotifier) -> Result<(), UblkError> {
+    let sess = libublk::UblkSessionBuilder::default()
+        .name("null")
+        .depth(64_u32)
+        .nr_queues(2_u32)
+        .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+        .build()
+        .unwrap();
+
+    let tgt_init = |dev: &mut UblkDev| {
+        dev.set_default_params(32_u64 << 20);
+        Ok(serde_json::json!({}))
+    };
+    let handle_io = move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+        let iod = ctx.get_iod(io.get_tag());
+
+        io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+        Ok(0)
+    };
+
+    let (mut ctrl, dev) = sess.create_devices(tgt_init)?;
+    notifier.notify_dev_id(ctrl.dev_info.dev_id)?;
+
+    let n = notifier.clone();
+    let wh = sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+        n.notify_live(dev_id as u32).unwrap();
+    })?;
+    wh.join().unwrap();
+    Ok(())
+}
+
+/// add one ublk-null device in daemon, which writes its pid to `pidfile`,
+/// and redirects stdio to `log` if it is passed
+///
+/// The parent prints device id after the device becomes LIVE, and fails
+/// if the daemon can't be set up or the device can't be added.
+fn test_add() {
+    let pidfile = std::env::args().nth(2).unwrap();
+    let mut b = UblkDaemonBuilder::default();
+
+    b.pidfile(pidfile);
+    if let Some(log) = std::env::args().nth(3) {
+        b.stdio(log);
+    }
+    match b.build().unwrap().daemonize().unwrap() {
+        UblkFork::Parent(mut p) => match p.wait_live() {
+            Ok(dev_id) => println!("dev id {}", dev_id),
+            Err(e) => {
+                eprintln!("ublk daemon failed: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        UblkFork::Daemon(d) => {
+            let notifier = d.notifier();
+
+            if let Err(e) = null_add(&notifier) {
+                notifier.notify_failure(&e).unwrap();
+            }
+        }
+    }
+}
+
+fn test_del() {
+    let s = std::env::args().nth(2).unwrap_or_else(|| "0".to_string());
+    let dev_id = s.parse::<i32>().unwrap();
+    let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+
+    ctrl.del_dev().unwrap();
+}
+
+fn main() {
+    if let Some(cmd) = std::env::args().nth(1) {
+        match cmd.as_str() {
+            "add" => test_add(),
+            "del" => test_del(),
+            _ => todo!(),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use io_uring::{opcode, squeue, types};
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
+use libublk::daemon::{UblkDaemonBuilder, UblkDaemonNotifier, UblkFork};
+use libublk::io::{UblkDev, UblkIOChain, UblkIOCtx, UblkQueueCtx};
 use libublk::{ctrl::UblkCtrl, UblkError};
 use log::trace;
 use serde::Serialize;
@@ -65,7 +66,6 @@ fn loop_queue_tgt_io(
     let op = iod.op_flags & 0xff;
     let data = UblkIOCtx::build_user_data(tag as u16, op, 0, true);
     let buf_addr = io.io_buf_addr();
-    let r = io.get_ring();
 
     if op == libublk::sys::UBLK_IO_OP_WRITE_ZEROES || op == libublk::sys::UBLK_IO_OP_DISCARD {
         return Err(UblkError::OtherError(-libc::EINVAL));
@@ -73,33 +73,52 @@ fn loop_queue_tgt_io(
 
     match op {
         libublk::sys::UBLK_IO_OP_FLUSH => {
-            let sqe = &opcode::SyncFileRange::new(types::Fixed(1), bytes)
+            let sqe = opcode::SyncFileRange::new(types::Fixed(1), bytes)
                 .offset(off)
                 .build()
                 .flags(squeue::Flags::FIXED_FILE)
                 .user_data(data);
             unsafe {
-                r.submission().push(sqe).expect("submission fail");
+                io.push_async(sqe);
             }
         }
         libublk::sys::UBLK_IO_OP_READ => {
-            let sqe = &opcode::Read::new(types::Fixed(1), buf_addr, bytes)
+            let sqe = opcode::Read::new(types::Fixed(1), buf_addr, bytes)
                 .offset(off)
                 .build()
                 .flags(squeue::Flags::FIXED_FILE)
                 .user_data(data);
             unsafe {
-                r.submission().push(sqe).expect("submission fail");
+                io.push_async(sqe);
+            }
+        }
+        libublk::sys::UBLK_IO_OP_WRITE if (iod.op_flags & libublk::sys::UBLK_IO_F_FUA) != 0 => {
+            // FUA: write followed by fdatasync
+            let chain = UblkIOChain::new()
+                .link(
+                    opcode::Write::new(types::Fixed(1), buf_addr, bytes)
+                        .offset(off)
+                        .build()
+                        .flags(squeue::Flags::FIXED_FILE),
+                )
+                .link(
+                    opcode::Fsync::new(types::Fixed(1))
+                        .flags(types::FsyncFlags::DATASYNC)
+                        .build()
+                        .flags(squeue::Flags::FIXED_FILE),
+                );
+            unsafe {
+                io.submit_chain(chain, op, 0)?;
             }
         }
         libublk::sys::UBLK_IO_OP_WRITE => {
-            let sqe = &opcode::Write::new(types::Fixed(1), buf_addr, bytes)
+            let sqe = opcode::Write::new(types::Fixed(1), buf_addr, bytes)
                 .offset(off)
                 .build()
                 .flags(squeue::Flags::FIXED_FILE)
                 .user_data(data);
             unsafe {
-                r.submission().push(sqe).expect("submission fail");
+                io.push_async(sqe);
             }
         }
         _ => return Err(UblkError::OtherError(-libc::EINVAL)),
@@ -114,11 +133,17 @@ fn _lo_handle_io(ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkError
     // our IO on backing file is done
     if i.is_tgt_io() {
         let user_data = i.user_data();
-        let res = i.result();
+        let mut res = i.result();
         let cqe_tag = UblkIOCtx::user_data_to_tag(user_data);
 
         assert!(cqe_tag == tag);
 
+        // result of FUA write chain is from fdatasync
+        let iod = unsafe { &*ctx.get_iod(tag) };
+        if res == 0 && (iod.op_flags & libublk::sys::UBLK_IO_F_FUA) != 0 {
+            res = (iod.nr_sectors << 9) as i32;
+        }
+
         if res != -(libc::EAGAIN) {
             i.complete_io(res);
 
@@ -133,42 +158,62 @@ fn _lo_handle_io(ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkError
     loop_queue_tgt_io(i, tag, iod)
 }
 
+fn lo_add(back_file: &str, notifier: &UblkDaemonNotifier) -> Result<(), UblkError> {
+    // LooTgt has to live in the whole device lifetime
+    let lo = LoopTgt {
+        back_file: std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(back_file)
+            .map_err(UblkError::OtherIOError)?,
+        direct_io: 1,
+        back_file_path: back_file.to_string(),
+    };
+    let wh = {
+        let sess = libublk::UblkSessionBuilder::default()
+            .name("loop")
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let tgt_init = |dev: &mut UblkDev| lo_init_tgt(dev, &lo);
+        let (mut ctrl, dev) = sess.create_devices(tgt_init)?;
+        notifier.notify_dev_id(ctrl.dev_info.dev_id)?;
+        let lo_handle_io = move |ctx: &UblkQueueCtx,
+                                 io: &mut UblkIOCtx|
+              -> Result<i32, UblkError> { _lo_handle_io(ctx, io) };
+
+        let notifier = notifier.clone();
+        sess.run(&mut ctrl, &dev, lo_handle_io, move |dev_id| {
+            let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+            d_ctrl.dump();
+            notifier.notify_live(dev_id as u32).unwrap();
+        })?
+    };
+    wh.join().unwrap();
+    Ok(())
+}
+
 fn test_add() {
-    let back_file = std::env::args().nth(2).unwrap();
-    let _pid = unsafe { libc::fork() };
-
-    if _pid == 0 {
-        // LooTgt has to live in the whole device lifetime
-        let lo = LoopTgt {
-            back_file: std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&back_file)
-                .unwrap(),
-            direct_io: 1,
-            back_file_path: back_file.clone(),
-        };
-        let wh = {
-            let sess = libublk::UblkSessionBuilder::default()
-                .name("loop")
-                .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
-                .build()
-                .unwrap();
-
-            let tgt_init = |dev: &mut UblkDev| lo_init_tgt(dev, &lo);
-            let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
-            let lo_handle_io =
-                move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
-                    _lo_handle_io(ctx, io)
-                };
-
-            sess.run(&mut ctrl, &dev, lo_handle_io, |dev_id| {
-                let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-                d_ctrl.dump();
-            })
-            .unwrap()
-        };
-        wh.join().unwrap();
+    // the daemon runs in `/`, so resolve relative path before forking
+    let back_file = std::fs::canonicalize(std::env::args().nth(2).unwrap())
+        .unwrap()
+        .display()
+        .to_string();
+    let daemon = UblkDaemonBuilder::default().build().unwrap();
+
+    match daemon.daemonize().unwrap() {
+        UblkFork::Parent(mut p) => {
+            p.wait_live().unwrap();
+        }
+        UblkFork::Daemon(d) => {
+            let notifier = d.notifier();
+
+            // parent gets the failure from wait_live()
+            if let Err(e) = lo_add(&back_file, &notifier) {
+                notifier.notify_failure(&e).unwrap();
+            }
+        }
     }
 }
 
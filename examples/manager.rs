@@ -0,0 +1,73 @@
+use libublk::config::{UblkConfig, UblkDevConfig, UblkTargetRegistry, UblkTgtConfig};
+use libublk::manager::UblkManager;
+use std::io::Write;
+
+/// add `nr` ublk-null devices handling signals by UblkManager, and print
+/// id of each device; devices are quiesced by ublk driver after this
+/// process is killed if `recovery` is true
+///
+/// All devices are stopped and deleted by one SIGTERM sent to this
+/// process, then it exits with failure if any queue fails.
+fn test_add(nr: u32, recovery: bool) {
+    let cfg = UblkConfig {
+        devices: (0..nr)
+            .map(|_| UblkDevConfig {
+                id: -1,
+                nr_queues: 2,
+                depth: 64,
+                io_buf_bytes: 512 << 10,
+                ctrl_flags: if recovery {
+                    libublk::sys::UBLK_F_USER_RECOVERY as u64
+                } else {
+                    0
+                },
+                dev_flags: libublk::UBLK_DEV_F_ADD_DEV,
+                handle_signals: true,
+                target: UblkTgtConfig {
+                    tgt_type: "null".to_string(),
+                    options: serde_json::json!({ "size": 32_u64 << 20 }),
+                },
+            })
+            .collect(),
+    };
+    let mgr = UblkManager::new(UblkTargetRegistry::new());
+
+    for res in mgr.add_all(&cfg) {
+        match res {
+            Ok(dev_id) => println!("dev id {}", dev_id),
+            Err(e) => {
+                eprintln!("add device failed: {:?}", e);
+                mgr.shutdown();
+                std::process::exit(1);
+            }
+        }
+    }
+    std::io::stdout().flush().unwrap();
+
+    let mut failed = false;
+    while !mgr.list().is_empty() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        for (dev_id, res) in mgr.reap() {
+            if !res.is_ok_and(|r| r.iter().all(|q| q.is_ok())) {
+                eprintln!("dev-{} failed", dev_id);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    if let Some(cmd) = std::env::args().nth(1) {
+        match cmd.as_str() {
+            "add" => {
+                let s = std::env::args().nth(2).unwrap_or_else(|| "1".to_string());
+                let recovery = std::env::args().nth(3).is_some_and(|s| s == "recovery");
+                test_add(s.parse::<u32>().unwrap(), recovery);
+            }
+            _ => todo!(),
+        }
+    }
+}
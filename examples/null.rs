@@ -1,7 +1,8 @@
+use libublk::daemon::{UblkDaemonBuilder, UblkDaemonNotifier, UblkFork};
 use libublk::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
 use libublk::{ctrl::UblkCtrl, UblkError};
 
-fn null_add(dev_id: i32, comp_batch: bool) {
+fn null_add(dev_id: i32, comp_batch: bool, notifier: &UblkDaemonNotifier) -> Result<(), UblkError> {
     let dflags = if comp_batch {
         libublk::UBLK_DEV_F_COMP_BATCH
     } else {
@@ -23,7 +24,9 @@ fn null_add(dev_id: i32, comp_batch: bool) {
     };
 
     let wh = {
-        let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        let (mut ctrl, dev) = sess.create_devices(tgt_init)?;
+        notifier.notify_dev_id(ctrl.dev_info.dev_id)?;
+        let notifier = notifier.clone();
         let handle_io_batch =
             move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
                 let iod = ctx.get_iod(io.get_tag());
@@ -48,14 +51,15 @@ fn null_add(dev_id: i32, comp_batch: bool) {
             } else {
                 handle_io
             },
-            |dev_id| {
+            move |dev_id| {
                 let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
                 d_ctrl.dump();
+                notifier.notify_live(dev_id as u32).unwrap();
             },
-        )
-        .unwrap()
+        )?
     };
     wh.join().unwrap();
+    Ok(())
 }
 
 fn null_del() {
@@ -75,9 +79,19 @@ fn main() {
                 let s3 = std::env::args().nth(3).unwrap_or_else(|| "0".to_string());
                 let batch = s3.parse::<i32>().unwrap();
 
-                let _pid = unsafe { libc::fork() };
-                if _pid == 0 {
-                    null_add(dev_id, batch != 0);
+                let daemon = UblkDaemonBuilder::default().build().unwrap();
+                match daemon.daemonize().unwrap() {
+                    UblkFork::Parent(mut p) => {
+                        p.wait_live().unwrap();
+                    }
+                    UblkFork::Daemon(d) => {
+                        let notifier = d.notifier();
+
+                        // parent gets the failure from wait_live()
+                        if let Err(e) = null_add(dev_id, batch != 0, &notifier) {
+                            notifier.notify_failure(&e).unwrap();
+                        }
+                    }
                 }
             }
             "del" => null_del(),
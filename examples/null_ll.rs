@@ -1,3 +1,4 @@
+use libublk::daemon::{UblkDaemonBuilder, UblkDaemonNotifier, UblkFork};
 use libublk::io::{UblkDev, UblkIOCtx, UblkQueue, UblkQueueCtx};
 use libublk::{ctrl::UblkCtrl, UblkError};
 use std::sync::Arc;
@@ -14,7 +15,7 @@ fn null_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx, park: bool) -> Result<
         Ok(libublk::io::UBLK_IO_S_COMP_BATCH)
     }
 }
-fn test_add(dev_id: i32) {
+fn test_add(dev_id: i32, notifier: &UblkDaemonNotifier) -> Result<(), UblkError> {
     let s = std::env::args().nth(3).unwrap_or_else(|| "0".to_string());
     let park = s.parse::<i32>().unwrap();
     let nr_queues = 2; //two queues
@@ -26,16 +27,15 @@ fn test_add(dev_id: i32) {
         512 << 10,
         0,
         libublk::UBLK_DEV_F_ADD_DEV,
-    )
-    .unwrap();
+    )?;
+    notifier.notify_dev_id(ctrl.dev_info.dev_id)?;
 
     //target specific initialization is done in this closure
     let tgt_init = |dev: &mut UblkDev| {
         dev.set_default_params(250_u64 << 30);
         Ok(serde_json::json!({}))
     };
-    let ublk_dev =
-        std::sync::Arc::new(UblkDev::new("null".to_string(), tgt_init, &mut ctrl).unwrap());
+    let ublk_dev = std::sync::Arc::new(UblkDev::new("null".to_string(), tgt_init, &mut ctrl)?);
     let mut threads = Vec::new();
 
     println!("park completed IO {}", park);
@@ -53,12 +53,14 @@ fn test_add(dev_id: i32) {
             queue.wait_and_handle_io(io_handler);
         }));
     }
-    ctrl.start_dev(&ublk_dev).unwrap();
+    ctrl.start_dev(&ublk_dev)?;
     ctrl.dump();
+    notifier.notify_live(ctrl.dev_info.dev_id)?;
     for qh in threads {
         qh.join().unwrap();
     }
-    ctrl.stop_dev(&ublk_dev).unwrap();
+    ctrl.stop_dev(&ublk_dev)?;
+    Ok(())
 }
 
 fn test_del() {
@@ -75,9 +77,19 @@ fn main() {
             "add" => {
                 let s = std::env::args().nth(2).unwrap_or_else(|| "-1".to_string());
                 let dev_id = s.parse::<i32>().unwrap();
-                let _pid = unsafe { libc::fork() };
-                if _pid == 0 {
-                    test_add(dev_id);
+                let daemon = UblkDaemonBuilder::default().build().unwrap();
+                match daemon.daemonize().unwrap() {
+                    UblkFork::Parent(mut p) => {
+                        p.wait_live().unwrap();
+                    }
+                    UblkFork::Daemon(d) => {
+                        let notifier = d.notifier();
+
+                        // parent gets the failure from wait_live()
+                        if let Err(e) = test_add(dev_id, &notifier) {
+                            notifier.notify_failure(&e).unwrap();
+                        }
+                    }
                 }
             }
             "del" => test_del(),
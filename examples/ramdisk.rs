@@ -1,3 +1,4 @@
+use libublk::daemon::{UblkDaemonBuilder, UblkDaemonNotifier, UblkFork};
 use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
 use libublk::{ctrl::UblkCtrl, UblkError};
 
@@ -35,7 +36,13 @@ fn handle_io(
 
 ///run this ramdisk ublk daemon completely in single context with
 ///async control command, no need Rust async any more
-fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
+fn rd_add_dev(
+    dev_id: i32,
+    buf_addr: u64,
+    size: u64,
+    for_add: bool,
+    notifier: &UblkDaemonNotifier,
+) -> Result<(), UblkError> {
     let depth = 128;
     let nr_queues = 1;
     let mut ctrl = UblkCtrl::new(
@@ -49,8 +56,8 @@ fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
         } else {
             libublk::UBLK_DEV_F_RECOVER_DEV
         },
-    )
-    .unwrap();
+    )?;
+    notifier.notify_dev_id(ctrl.dev_info.dev_id)?;
     let ublk_dev = UblkDev::new(
         "ramdisk".to_string(),
         |dev: &mut UblkDev| {
@@ -58,10 +65,9 @@ fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
             Ok(serde_json::json!({}))
         },
         &mut ctrl,
-    )
-    .unwrap();
+    )?;
 
-    let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+    let mut queue = UblkQueue::new(0, &ublk_dev)?;
     let ctx = queue.make_queue_ctx();
     let qc = move |i: &mut UblkIOCtx| {
         let _iod = ctx.get_iod(i.get_tag());
@@ -69,13 +75,23 @@ fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
 
         handle_io(i, iod, buf_addr)
     };
-    ctrl.configure_queue(&ublk_dev, 0, unsafe { libc::gettid() })
-        .unwrap();
+    ctrl.configure_queue(&ublk_dev, 0, unsafe { libc::gettid() })?;
 
-    ctrl.start_dev_in_queue(&ublk_dev, &mut queue, &qc).unwrap();
+    ctrl.start_dev_in_queue(&ublk_dev, &mut queue, &qc)?;
     ctrl.dump();
+    notifier.notify_live(ctrl.dev_info.dev_id)?;
     queue.wait_and_handle_io(&qc);
-    ctrl.stop_dev(&ublk_dev).unwrap();
+    ctrl.stop_dev(&ublk_dev)?;
+    Ok(())
+}
+
+fn rd_recover_size(dev_id: i32) -> Result<u64, UblkError> {
+    assert!(dev_id >= 0);
+    let mut ctrl = UblkCtrl::new_simple(dev_id, 0)?;
+    let size = rd_get_device_size(&mut ctrl);
+
+    ctrl.start_user_recover()?;
+    Ok(size)
 }
 
 fn rd_get_device_size(ctrl: &mut UblkCtrl) -> u64 {
@@ -95,22 +111,32 @@ fn test_add(recover: usize) {
     let s = std::env::args().nth(3).unwrap_or_else(|| "32".to_string());
     let mb = s.parse::<u64>().unwrap();
 
-    let _pid = unsafe { libc::fork() };
-    if _pid == 0 {
-        let mut size = (mb << 20) as u64;
-
-        if recover > 0 {
-            assert!(dev_id >= 0);
-            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-            size = rd_get_device_size(&mut ctrl);
-
-            ctrl.start_user_recover().unwrap();
+    let daemon = UblkDaemonBuilder::default().build().unwrap();
+    match daemon.daemonize().unwrap() {
+        // return after the device becomes LIVE
+        UblkFork::Parent(mut p) => {
+            p.wait_live().unwrap();
+        }
+        UblkFork::Daemon(d) => {
+            let notifier = d.notifier();
+            let size = if recover > 0 {
+                rd_recover_size(dev_id)
+            } else {
+                Ok((mb << 20) as u64)
+            };
+
+            // parent gets the failure from wait_live()
+            let res = size.and_then(|size| {
+                let buf = libublk::ublk_alloc_buf(size as usize, 4096);
+                let res = rd_add_dev(dev_id, buf as u64, size, recover == 0, &notifier);
+
+                libublk::ublk_dealloc_buf(buf, size as usize, 4096);
+                res
+            });
+            if let Err(e) = res {
+                notifier.notify_failure(&e).unwrap();
+            }
         }
-        let buf = libublk::ublk_alloc_buf(size as usize, 4096);
-
-        rd_add_dev(dev_id, buf as u64, size, recover == 0);
-
-        libublk::ublk_dealloc_buf(buf, size as usize, 4096);
     }
 }
 
@@ -0,0 +1,272 @@
+//! Declarative device configuration
+//!
+//! One config file describes one or more ublk devices, and each device is
+//! mapped to one `UblkSession` and one target created by the constructor
+//! registered in `UblkTargetRegistry` for the device's target type.
+//!
+//! JSON config:
+//!
+//! ```json
+//! {
+//!     "devices": [
+//!         { "id": 0, "nr_queues": 2, "depth": 128,
+//!           "target": { "type": "null", "options": { "size": 1073741824 } } }
+//!     ]
+//! }
+//! ```
+//!
+//! TOML config is supported if feature `toml` is enabled.
+
+use super::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
+use super::{UblkDeviceHandle, UblkError, UblkSession, UblkSessionBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Target of one device: target type and its options
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UblkTgtConfig {
+    /// target type, which is used for finding the target constructor
+    #[serde(rename = "type")]
+    pub tgt_type: String,
+
+    /// target specific options, passed to the target constructor
+    #[serde(default = "empty_options")]
+    pub options: serde_json::Value,
+}
+
+fn empty_options() -> serde_json::Value {
+    serde_json::json!({})
+}
+
+/// Config of one ublk device, see `UblkSession` for each field
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UblkDevConfig {
+    #[serde(default = "UblkDevConfig::default_id")]
+    pub id: i32,
+
+    #[serde(default = "UblkDevConfig::default_nr_queues")]
+    pub nr_queues: u32,
+
+    #[serde(default = "UblkDevConfig::default_depth")]
+    pub depth: u32,
+
+    #[serde(default = "UblkDevConfig::default_io_buf_bytes")]
+    pub io_buf_bytes: u32,
+
+    #[serde(default)]
+    pub ctrl_flags: u64,
+
+    /// libublk feature flags: UBLK_DEV_F_*, and `UBLK_DEV_F_ADD_DEV`
+    /// is the default
+    #[serde(default = "UblkDevConfig::default_dev_flags")]
+    pub dev_flags: u32,
+
+    /// handle SIGTERM/SIGINT, SIGHUP and SIGUSR1, and every device with
+    /// it set in this process handles the same signal
+    #[serde(default)]
+    pub handle_signals: bool,
+
+    pub target: UblkTgtConfig,
+}
+
+impl UblkDevConfig {
+    fn default_id() -> i32 {
+        -1
+    }
+    fn default_nr_queues() -> u32 {
+        1
+    }
+    fn default_depth() -> u32 {
+        64
+    }
+    fn default_io_buf_bytes() -> u32 {
+        524288
+    }
+    fn default_dev_flags() -> u32 {
+        super::UBLK_DEV_F_ADD_DEV
+    }
+
+    /// Build `UblkSession` for this device
+    pub fn to_session(&self) -> Result<UblkSession, UblkError> {
+        UblkSessionBuilder::default()
+            .name(self.target.tgt_type.clone())
+            .id(self.id)
+            .nr_queues(self.nr_queues)
+            .depth(self.depth)
+            .io_buf_bytes(self.io_buf_bytes)
+            .ctrl_flags(self.ctrl_flags)
+            .dev_flags(self.dev_flags)
+            .handle_signals(self.handle_signals)
+            .build()
+            .map_err(|e| UblkError::ConfigError(e.to_string()))
+    }
+}
+
+/// Config of ublk devices
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UblkConfig {
+    #[serde(default)]
+    pub devices: Vec<UblkDevConfig>,
+}
+
+impl UblkConfig {
+    /// Parse config from JSON string
+    pub fn from_json_str(s: &str) -> Result<UblkConfig, UblkError> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Parse config from TOML string
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(s: &str) -> Result<UblkConfig, UblkError> {
+        toml::from_str(s).map_err(|e| UblkError::ConfigError(e.to_string()))
+    }
+
+    /// Load config from file, and file with `.toml` extension is parsed
+    /// as TOML, otherwise it is parsed as JSON
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<UblkConfig, UblkError> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path).map_err(UblkError::OtherIOError)?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "toml")]
+            Some("toml") => Self::from_toml_str(&s),
+            #[cfg(not(feature = "toml"))]
+            Some("toml") => Err(UblkError::ConfigError(
+                "toml config requires feature `toml`".to_string(),
+            )),
+            _ => Self::from_json_str(&s),
+        }
+    }
+}
+
+/// IO handling closure of one queue, created by `UblkTarget::io_factory`
+pub type UblkIoHandler = Box<dyn FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>>;
+
+/// Target initialization closure, see `UblkSession::create_devices()`
+pub type UblkTgtInitFn = Box<dyn FnOnce(&mut UblkDev) -> Result<serde_json::Value, UblkError>>;
+
+/// Queue IO handler factory, see `UblkSession::run_with_factory()`
+pub type UblkIoFactory = Box<dyn Fn(u16) -> UblkIoHandler + Send + Sync>;
+
+/// Target constructor, which creates target from target options
+pub type UblkTgtCtor =
+    Box<dyn Fn(&serde_json::Value) -> Result<UblkTarget, UblkError> + Send + Sync>;
+
+/// One target created by target constructor
+pub struct UblkTarget {
+    /// initialize `UblkDev`, such as setting device parameters
+    pub init: UblkTgtInitFn,
+
+    /// create IO handling closure for each queue
+    pub io_factory: UblkIoFactory,
+}
+
+/// Registry of target constructors, indexed by target type
+///
+/// Target "null" is registered by default, and its option `size` is
+/// device size in bytes.
+pub struct UblkTargetRegistry {
+    ctors: HashMap<String, UblkTgtCtor>,
+}
+
+impl Default for UblkTargetRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UblkTargetRegistry {
+    pub fn new() -> UblkTargetRegistry {
+        let mut r = UblkTargetRegistry {
+            ctors: HashMap::new(),
+        };
+
+        r.register("null", null_tgt_ctor);
+        r
+    }
+
+    /// Register constructor for target type `tgt_type`, and the old one
+    /// is replaced
+    pub fn register<C>(&mut self, tgt_type: &str, ctor: C)
+    where
+        C: Fn(&serde_json::Value) -> Result<UblkTarget, UblkError> + Send + Sync + 'static,
+    {
+        self.ctors.insert(tgt_type.to_string(), Box::new(ctor));
+    }
+
+    /// Return all registered target types
+    pub fn target_types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.ctors.keys().cloned().collect();
+
+        types.sort();
+        types
+    }
+
+    /// Create session and target for this device config
+    pub fn create(&self, cfg: &UblkDevConfig) -> Result<(UblkSession, UblkTarget), UblkError> {
+        let ctor = self.ctors.get(&cfg.target.tgt_type).ok_or_else(|| {
+            UblkError::ConfigError(format!("unknown target type {}", cfg.target.tgt_type))
+        })?;
+        let sess = cfg.to_session()?;
+        let tgt = ctor(&cfg.target.options)?;
+
+        Ok((sess, tgt))
+    }
+
+    /// Create and start the device described by `cfg`
+    pub fn start(&self, cfg: &UblkDevConfig) -> Result<UblkDeviceHandle, UblkError> {
+        let (sess, tgt) = self.create(cfg)?;
+        let (ctrl, dev) = sess.create_devices(tgt.init)?;
+        let io_factory = tgt.io_factory;
+
+        sess.start_with_factory(ctrl, dev, move |q| io_factory(q))
+    }
+
+    /// Create and start all devices in `cfg`
+    ///
+    /// Devices started before the failure are stopped and waited.
+    pub fn start_all(&self, cfg: &UblkConfig) -> Result<Vec<UblkDeviceHandle>, UblkError> {
+        let mut handles: Vec<UblkDeviceHandle> = Vec::new();
+
+        for dev_cfg in &cfg.devices {
+            match self.start(dev_cfg) {
+                Ok(h) => handles.push(h),
+                Err(e) => {
+                    for h in handles {
+                        let _ = h.stop();
+                        let _ = h.wait();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(handles)
+    }
+}
+
+fn null_tgt_ctor(opts: &serde_json::Value) -> Result<UblkTarget, UblkError> {
+    let size = match &opts["size"] {
+        serde_json::Value::Null => 250_u64 << 30,
+        v => v
+            .as_u64()
+            .ok_or_else(|| UblkError::ConfigError("invalid null size".to_string()))?,
+    };
+
+    Ok(UblkTarget {
+        init: Box::new(move |dev: &mut UblkDev| {
+            dev.set_default_params(size);
+            Ok(serde_json::json!({}))
+        }),
+        io_factory: Box::new(|_| {
+            Box::new(|ctx: &UblkQueueCtx, io: &mut UblkIOCtx| {
+                let iod = ctx.get_iod(io.get_tag());
+
+                io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+                Ok(0)
+            })
+        }),
+    })
+}
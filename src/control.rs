@@ -0,0 +1,516 @@
+//! Control socket for inspecting and steering running device
+//!
+//! If `control_socket` is set for `UblkSession`, the session serves
+//! control requests on this unix socket until the device is stopped.
+//!
+//! The protocol is JSON-RPC 2.0, and each request or response is one line
+//! of JSON:
+//!
+//! ```json
+//! {"jsonrpc": "2.0", "id": 1, "method": "stats", "params": {}}
+//! {"jsonrpc": "2.0", "id": 1, "result": {"handler_errors": 0, ...}}
+//! ```
+//!
+//! Methods:
+//!
+//! - `status`: device id, state, block device path, queues and target
+//!
+//! - `stats`: device stats, and stats of each queue if param `queues`
+//!   is true; queues are asked for one stats snapshot, which is
+//!   refreshed periodically too if `stats_interval_ms` is set
+//!
+//! - `queues`: tid, affinity and stats of each queue
+//!
+//! - `set_log_level`: set max log level to param `level`, such as
+//!   "debug", and return the old level; -EOPNOTSUPP is returned if the
+//!   level is above the static max level of `log`, which is off in
+//!   release build
+//!
+//! - `stop`: stop the device after inflight IOs are drained
+//!
+//! - `target`: target command `cmd` with `args`, handled by the hook set
+//!   by `UblkSession::set_target_cmd_hook()`
+//!
+//! - `handover`: hand over the device to the requesting process, which
+//!   is sent by `upgrade::UblkHandover::take_over()`
+//!
+//! Failure is returned as JSON-RPC error, and error code is negative errno
+//! for failure from libublk or target.
+//!
+//! The socket is only accessible by owner, and connection from peer whose
+//! uid is neither the daemon's uid nor root is closed. Each connection is
+//! served in its own pthread.
+
+use super::ctrl::UblkCtrl;
+use super::io::UblkQueueEvent;
+use super::{upgrade, UblkError, UblkSession};
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+const RPC_PARSE_ERROR: i32 = -32700;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_INVALID_PARAMS: i32 = -32602;
+
+/// Control request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UblkRpcRequest {
+    pub jsonrpc: String,
+    pub id: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+/// Error of control request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UblkRpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+/// Control response, in which only one of `result` and `error` is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UblkRpcResponse {
+    pub jsonrpc: String,
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<UblkRpcError>,
+}
+
+impl From<UblkError> for UblkRpcError {
+    fn from(e: UblkError) -> Self {
+        UblkRpcError {
+            code: e.to_errno(),
+            message: format!("{:?}", e),
+        }
+    }
+}
+
+fn invalid_params(msg: &str) -> UblkRpcError {
+    UblkRpcError {
+        code: RPC_INVALID_PARAMS,
+        message: msg.to_string(),
+    }
+}
+
+/// Bind unix socket `path` which is only accessible by owner
+///
+/// Mode of the socket file is taken from the socket inode, so fchmod()
+/// it before bind(), then the socket file never exists with the looser
+/// mode from umask; process umask isn't touched since other pthreads may
+/// create files meantime.
+fn bind_private(path: &str) -> Result<UnixListener, UblkError> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let bytes = path.as_bytes();
+
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    if unsafe { libc::fchmod(fd.as_raw_fd(), 0o600) } < 0 {
+        return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+    }
+    let len = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    if unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len,
+        )
+    } < 0
+    {
+        return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+    }
+    if unsafe { libc::listen(fd.as_raw_fd(), 128) } < 0 {
+        let e = std::io::Error::last_os_error();
+
+        let _ = std::fs::remove_file(path);
+        return Err(UblkError::OtherIOError(e));
+    }
+    Ok(UnixListener::from(fd))
+}
+
+/// Control socket server of one device, which is run in its own pthread
+/// until the server is dropped
+pub(crate) struct UblkControlServer {
+    path: String,
+    stopping: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl UblkControlServer {
+    /// Bind control socket `path`, and start to serve requests for
+    /// device `dev_id`, and `ev_tx` is for asking the session's monitor
+    /// to hand over the device
+    pub(crate) fn start(
+        path: &str,
+        dev_id: u32,
+        sess: UblkSession,
+        ev_tx: mpsc::Sender<UblkQueueEvent>,
+    ) -> Result<UblkControlServer, UblkError> {
+        // remove stale socket left by old daemon, and never steal the
+        // socket of one live daemon
+        if UnixStream::connect(path).is_ok() {
+            return Err(UblkError::OtherError(-libc::EADDRINUSE));
+        }
+        let _ = std::fs::remove_file(path);
+        let listener = bind_private(path)?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let _stopping = stopping.clone();
+        let sess = Arc::new(sess);
+
+        let thread = std::thread::spawn(move || {
+            let mut conns: Vec<(UnixStream, std::thread::JoinHandle<()>)> = Vec::new();
+
+            for stream in listener.incoming() {
+                if _stopping.load(Ordering::Acquire) {
+                    break;
+                }
+                conns.retain(|(_, t)| !t.is_finished());
+                let s = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("dev-{} control accept failed {:?}", dev_id, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = check_peer_cred(&s) {
+                    error!("dev-{} control peer rejected {:?}", dev_id, e);
+                    continue;
+                }
+                let s2 = match s.try_clone() {
+                    Ok(s2) => s2,
+                    Err(_) => continue,
+                };
+                let (sess, ev_tx) = (sess.clone(), ev_tx.clone());
+                let t = std::thread::spawn(move || serve_conn(s, dev_id, &sess, &ev_tx));
+                conns.push((s2, t));
+            }
+
+            // wakeup connections blocked in read, and the response being
+            // sent, such as handover, isn't cut
+            for (s, t) in conns {
+                let _ = s.shutdown(std::net::Shutdown::Read);
+                let _ = t.join();
+            }
+        });
+
+        Ok(UblkControlServer {
+            path: path.to_string(),
+            stopping,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Only allow peer whose uid is same with this process, or root
+pub(crate) fn check_peer_cred(stream: &UnixStream) -> Result<(), UblkError> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = core::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            std::ptr::addr_of_mut!(cred) as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+    }
+    if cred.uid != 0 && cred.uid != unsafe { libc::geteuid() } {
+        return Err(UblkError::OtherError(-libc::EPERM));
+    }
+    Ok(())
+}
+
+impl Drop for UblkControlServer {
+    /// Stop serving requests and remove the control socket
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Release);
+        // wakeup the blocking accept()
+        let _ = UnixStream::connect(&self.path);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve_conn(
+    stream: UnixStream,
+    dev_id: u32,
+    sess: &UblkSession,
+    ev_tx: &mpsc::Sender<UblkQueueEvent>,
+) {
+    let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut handover = None;
+        let resp = match serde_json::from_str::<UblkRpcRequest>(&line) {
+            Ok(req) => {
+                trace!("dev-{} control request {}", dev_id, req.method);
+                let res = if req.method == "handover" {
+                    upgrade::request_handover(ev_tx)
+                        .and_then(|ho| {
+                            let r = ho.to_json()?;
+                            handover = Some(ho);
+                            Ok(r)
+                        })
+                        .map_err(UblkRpcError::from)
+                } else {
+                    handle_request(&req, dev_id, sess)
+                };
+                let (result, error) = match res {
+                    Ok(r) => (Some(r), None),
+                    Err(e) => (None, Some(e)),
+                };
+                UblkRpcResponse {
+                    jsonrpc: "2.0".to_string(),
+                    id: req.id,
+                    result,
+                    error,
+                }
+            }
+            Err(e) => UblkRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                id: 0,
+                result: None,
+                error: Some(UblkRpcError {
+                    code: RPC_PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+            },
+        };
+
+        let mut buf = match serde_json::to_string(&resp) {
+            Ok(b) => b,
+            Err(_) => break,
+        };
+        buf.push('\n');
+
+        // queues have been stopped, so nothing is served after handover
+        if let Some(ho) = handover {
+            if let Err(e) = upgrade::send_with_fds(&writer, buf.as_bytes(), ho.fds()) {
+                error!("dev-{} send handover failed {:?}", dev_id, e);
+            }
+            break;
+        }
+        if writer.write_all(buf.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+fn handle_request(
+    req: &UblkRpcRequest,
+    dev_id: u32,
+    sess: &UblkSession,
+) -> Result<serde_json::Value, UblkRpcError> {
+    match req.method.as_str() {
+        "status" => {
+            let ctrl = UblkCtrl::new_simple(dev_id as i32, 0)?;
+
+            Ok(serde_json::json!({
+                "dev_id": dev_id,
+                "state": ctrl.dev_state_desc(),
+                "bdev_path": format!("{}{}", super::BDEV_PATH, dev_id),
+                "pid": std::process::id(),
+                "nr_queues": ctrl.dev_info.nr_hw_queues,
+                "depth": ctrl.dev_info.queue_depth,
+                "flags": ctrl.dev_info.flags,
+                "target": ctrl.json["target"],
+            }))
+        }
+        "stats" => {
+            let mut res = serde_json::to_value(sess.get_stats()).map_err(UblkError::from)?;
+
+            if req.params["queues"].as_bool() == Some(true) {
+                res["queues"] =
+                    serde_json::to_value(sess.get_queues_stats()).map_err(UblkError::from)?;
+            }
+            Ok(res)
+        }
+        "queues" => {
+            let ctrl = UblkCtrl::new_simple(dev_id as i32, 0)?;
+            let stats = sess.get_queues_stats();
+            let queues: Vec<serde_json::Value> = (0..ctrl.dev_info.nr_hw_queues)
+                .map(|q| {
+                    let mut v = ctrl.json["queues"][q.to_string()].clone();
+
+                    if !v.is_object() {
+                        v = serde_json::json!({ "qid": q });
+                    }
+                    v["stats"] = stats
+                        .get(q as usize)
+                        .and_then(|s| serde_json::to_value(s).ok())
+                        .unwrap_or(serde_json::Value::Null);
+                    v
+                })
+                .collect();
+
+            Ok(serde_json::Value::Array(queues))
+        }
+        "set_log_level" => {
+            let level: log::LevelFilter = req.params["level"]
+                .as_str()
+                .and_then(|l| l.parse().ok())
+                .ok_or_else(|| invalid_params("invalid log level"))?;
+            let old = log::max_level();
+
+            // logs above the static max level are compiled out
+            if level > log::STATIC_MAX_LEVEL {
+                return Err(UblkError::OtherError(-libc::EOPNOTSUPP).into());
+            }
+            log::set_max_level(level);
+            Ok(serde_json::json!({ "old_level": old.to_string() }))
+        }
+        "stop" => {
+            // ublk driver drains inflight IOs before aborting IO commands
+            UblkCtrl::new_simple(dev_id as i32, 0)?.stop()?;
+            Ok(serde_json::Value::Null)
+        }
+        "target" => {
+            let cmd = req.params["cmd"]
+                .as_str()
+                .ok_or_else(|| invalid_params("target command is missing"))?;
+
+            Ok(sess.target_cmd(cmd, &req.params["args"])?)
+        }
+        _ => Err(UblkRpcError {
+            code: RPC_METHOD_NOT_FOUND,
+            message: format!("unknown method {}", req.method),
+        }),
+    }
+}
+
+/// Client of control socket
+pub struct UblkControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl UblkControlClient {
+    /// Connect to control socket `path`
+    pub fn connect<P: AsRef<std::path::Path>>(path: P) -> Result<UblkControlClient, UblkError> {
+        let stream = UnixStream::connect(path).map_err(UblkError::OtherIOError)?;
+        let writer = stream.try_clone().map_err(UblkError::OtherIOError)?;
+
+        Ok(UblkControlClient {
+            reader: BufReader::new(stream),
+            writer,
+            next_id: 1,
+        })
+    }
+
+    /// Send request `method` with `params`, and wait for its result
+    ///
+    /// Error response is returned as `UblkError::RpcError`.
+    pub fn call(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, UblkError> {
+        let req = UblkRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: self.next_id,
+            method: method.to_string(),
+            params,
+        };
+        let mut buf = serde_json::to_string(&req)?;
+
+        self.next_id += 1;
+        buf.push('\n');
+        self.writer
+            .write_all(buf.as_bytes())
+            .map_err(UblkError::OtherIOError)?;
+
+        let mut line = String::new();
+        if self
+            .reader
+            .read_line(&mut line)
+            .map_err(UblkError::OtherIOError)?
+            == 0
+        {
+            return Err(UblkError::OtherError(-libc::ECONNRESET));
+        }
+
+        let resp: UblkRpcResponse = serde_json::from_str(&line)?;
+        match (resp.result, resp.error) {
+            (_, Some(e)) => Err(UblkError::RpcError(e.code, e.message)),
+            (Some(r), None) => Ok(r),
+            (None, None) => Ok(serde_json::Value::Null),
+        }
+    }
+
+    pub fn status(&mut self) -> Result<serde_json::Value, UblkError> {
+        self.call("status", serde_json::json!({}))
+    }
+
+    /// Return device stats, and stats of each queue if `queues` is true
+    pub fn stats(&mut self, queues: bool) -> Result<serde_json::Value, UblkError> {
+        self.call("stats", serde_json::json!({ "queues": queues }))
+    }
+
+    pub fn queues(&mut self) -> Result<serde_json::Value, UblkError> {
+        self.call("queues", serde_json::json!({}))
+    }
+
+    /// Set max log level of the daemon, and return the old level
+    ///
+    /// The daemon fails it if `level` is above the static max level, such
+    /// as any level except `Off` in release build.
+    pub fn set_log_level(&mut self, level: log::LevelFilter) -> Result<String, UblkError> {
+        let res = self.call(
+            "set_log_level",
+            serde_json::json!({ "level": level.to_string() }),
+        )?;
+
+        Ok(res["old_level"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Stop the device gracefully
+    pub fn stop(&mut self) -> Result<(), UblkError> {
+        self.call("stop", serde_json::json!({})).map(|_| ())
+    }
+
+    /// Send target specific command `cmd` with `args`
+    pub fn target_cmd(
+        &mut self,
+        cmd: &str,
+        args: serde_json::Value,
+    ) -> Result<serde_json::Value, UblkError> {
+        self.call("target", serde_json::json!({ "cmd": cmd, "args": args }))
+    }
+}
@@ -3,9 +3,10 @@ use super::{sys, UblkError};
 use bitmaps::Bitmap;
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use log::{error, trace};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 
 const CTRL_PATH: &str = "/dev/ublk-control";
@@ -38,6 +39,164 @@ impl UblkQueueAffinity {
     pub fn to_bits_vec(&self) -> Vec<usize> {
         self.affinity.into_iter().collect()
     }
+
+    /// Build affinity from CPU list, and CPU beyond 1023 is ignored
+    pub fn from_cpus(cpus: &[usize]) -> UblkQueueAffinity {
+        let mut affinity = UblkQueueAffinity::new();
+
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < 1024) {
+            affinity.affinity.set(cpu, true);
+        }
+        affinity
+    }
+
+    /// Retrieve affinity of current pthread
+    pub fn from_current_thread() -> Result<UblkQueueAffinity, UblkError> {
+        let affinity = UblkQueueAffinity::new();
+        let res = unsafe {
+            libc::pthread_getaffinity_np(
+                libc::pthread_self(),
+                affinity.buf_len(),
+                affinity.addr() as *mut libc::cpu_set_t,
+            )
+        };
+        if res != 0 {
+            return Err(UblkError::OtherError(-res));
+        }
+        Ok(affinity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.affinity.is_empty()
+    }
+
+    /// Set current pthread's affinity
+    pub fn apply(&self) -> Result<(), UblkError> {
+        let res = unsafe {
+            libc::pthread_setaffinity_np(
+                libc::pthread_self(),
+                self.buf_len(),
+                self.addr() as *const libc::cpu_set_t,
+            )
+        };
+        if res != 0 {
+            return Err(UblkError::OtherError(-res));
+        }
+        Ok(())
+    }
+
+    /// Return NUMA node if all CPUs in this affinity belong to one node
+    pub fn numa_node(&self) -> Option<u32> {
+        let cpus = self.to_bits_vec();
+
+        if cpus.is_empty() {
+            return None;
+        }
+        ublk_numa_nodes()
+            .into_iter()
+            .find(|(_, node_cpus)| cpus.iter().all(|c| node_cpus.contains(c)))
+            .map(|(node, _)| node)
+    }
+}
+
+/// Parse cpu list in sysfs format, such as "0-3,8,10-11"
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut it = range.splitn(2, '-').map(|c| c.parse::<usize>());
+
+        match (it.next(), it.next()) {
+            (Some(Ok(start)), None) => cpus.push(start),
+            (Some(Ok(start)), Some(Ok(end))) => cpus.extend(start..=end),
+            _ => {}
+        }
+    }
+    cpus
+}
+
+/// Return all online NUMA nodes with CPUs, and CPU list of each node
+///
+/// Empty vector is returned if NUMA topology isn't exported by sysfs.
+pub fn ublk_numa_nodes() -> Vec<(u32, Vec<usize>)> {
+    let mut nodes = Vec::new();
+    let online = match fs::read_to_string("/sys/devices/system/node/online") {
+        Ok(s) => s,
+        Err(_) => return nodes,
+    };
+
+    for node in parse_cpu_list(&online) {
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+
+        if let Ok(list) = fs::read_to_string(path) {
+            let cpus = parse_cpu_list(&list);
+            if !cpus.is_empty() {
+                nodes.push((node as u32, cpus));
+            }
+        }
+    }
+    nodes
+}
+
+/// How to set CPU affinity of each queue pthread
+///
+/// Queue IO buffers are allocated on the NUMA node of the queue pthread
+/// if all CPUs in the applied affinity belong to single node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UblkAffinityPolicy {
+    /// Use the affinity retrieved from ublk driver, which is aligned
+    /// with blk-mq queue mapping
+    #[default]
+    Driver,
+
+    /// Explicit CPU list for each queue, indexed by queue id
+    Cpus(Vec<Vec<usize>>),
+
+    /// All queues are pinned to CPUs of this NUMA node
+    Node(u32),
+
+    /// Queues are pinned to NUMA nodes in round-robin way
+    Spread,
+
+    /// Queue pthread isn't pinned
+    None,
+}
+
+impl UblkAffinityPolicy {
+    /// Return affinity for queue `qid`, and `None` means the queue
+    /// pthread shouldn't be pinned
+    pub fn queue_affinity(
+        &self,
+        ctrl: &mut UblkCtrl,
+        qid: u16,
+    ) -> Result<Option<UblkQueueAffinity>, UblkError> {
+        let cpus = match self {
+            UblkAffinityPolicy::None => return Ok(None),
+            UblkAffinityPolicy::Driver => {
+                let mut affinity = UblkQueueAffinity::new();
+                ctrl.get_queue_affinity(qid as u32, &mut affinity)?;
+                return Ok(Some(affinity));
+            }
+            UblkAffinityPolicy::Cpus(cpus) => cpus.get(qid as usize).cloned(),
+            UblkAffinityPolicy::Node(node) => ublk_numa_nodes()
+                .into_iter()
+                .find(|(n, _)| n == node)
+                .map(|(_, cpus)| cpus),
+            UblkAffinityPolicy::Spread => {
+                let nodes = ublk_numa_nodes();
+                if nodes.is_empty() {
+                    None
+                } else {
+                    Some(nodes[qid as usize % nodes.len()].1.clone())
+                }
+            }
+        };
+
+        match cpus.map(|c| UblkQueueAffinity::from_cpus(&c)) {
+            Some(affinity) if !affinity.is_empty() => Ok(Some(affinity)),
+            _ => Err(UblkError::OtherError(-libc::EINVAL)),
+        }
+    }
 }
 
 #[repr(C)]
@@ -153,6 +312,7 @@ pub struct UblkCtrl {
     dev_flags: u32,
     cmd_token: i32,
     queue_tids: Vec<i32>,
+    queue_affinities: Vec<Option<UblkQueueAffinity>>,
     nr_queues_configured: u16,
     ring: IoUring<squeue::Entry128>,
 }
@@ -253,6 +413,7 @@ impl UblkCtrl {
                 }
                 tids
             },
+            queue_affinities: vec![None; nr_queues as usize],
             nr_queues_configured: 0,
             dev_flags,
         };
@@ -291,7 +452,7 @@ impl UblkCtrl {
         self.dev_flags
     }
 
-    fn dev_state_desc(&self) -> String {
+    pub(crate) fn dev_state_desc(&self) -> String {
         match self.dev_info.state as u32 {
             sys::UBLK_S_DEV_DEAD => "DEAD".to_string(),
             sys::UBLK_S_DEV_LIVE => "LIVE".to_string(),
@@ -357,6 +518,14 @@ impl UblkCtrl {
         self.queue_tids[qid as usize] = tid;
     }
 
+    /// Record the affinity applied to queue pthread, which is exported
+    /// in json, otherwise the affinity from ublk driver is exported
+    ///
+    /// Has to be called before `configure_queue()`
+    pub fn store_queue_affinity(&mut self, qid: u16, affinity: UblkQueueAffinity) {
+        self.queue_affinities[qid as usize] = Some(affinity);
+    }
+
     /// Configure queue affinity and record queue tid
     ///
     /// # Arguments:
@@ -378,6 +547,12 @@ impl UblkCtrl {
         Ok(0)
     }
 
+    /// All queues will be configured again, such as after recovering
+    /// queues in-process
+    pub(crate) fn reset_queues_configured(&mut self) {
+        self.nr_queues_configured = 0;
+    }
+
     pub fn queues_configured(&self) -> bool {
         self.nr_queues_configured == self.dev_info.nr_hw_queues
     }
@@ -735,14 +910,15 @@ impl UblkCtrl {
     /// this kernel code path, such as, reading partition table, so we
     /// have make io handler working before sending START_DEV to kernel
     ///
-    pub fn start_dev_in_queue<F>(
+    pub fn start_dev_in_queue<F, T>(
         &mut self,
         dev: &UblkDev,
-        q: &mut super::io::UblkQueue,
+        q: &mut super::io::UblkQueue<T>,
         mut ops: F,
     ) -> Result<i32, UblkError>
     where
-        F: FnMut(&mut super::io::UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut super::io::UblkIOCtx<T>) -> Result<i32, UblkError>,
+        T: Default,
     {
         let mut started = false;
         let token = self.__start_dev(dev, true)?;
@@ -768,6 +944,44 @@ impl UblkCtrl {
         Ok(0)
     }
 
+    /// Start ublk device served by `UblkEventLoop` from the loop pthread
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device, which has been added to `event_loop`
+    /// * `event_loop`: the event loop serving `dev`
+    /// * `ops`: IO handling closure of `event_loop`
+    ///
+    /// Same with `start_dev_in_queue()`, IO of this device and other
+    /// devices in the loop is handled by `ops` until START_DEV completes,
+    /// and the loop is woken up by completion of START_DEV via polling
+    /// the control ring.
+    pub fn start_dev_in_loop<F>(
+        &mut self,
+        dev: &UblkDev,
+        event_loop: &mut super::io::UblkEventLoop,
+        mut ops: F,
+    ) -> Result<i32, UblkError>
+    where
+        F: FnMut(&super::io::UblkQueueCtx, &mut super::io::UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        let token = self.__start_dev(dev, true)?;
+        let ring_fd = self.ring.as_raw_fd();
+
+        let res = loop {
+            match self.poll_cmd(token) {
+                Ok(_) => break Ok(0),
+                Err(UblkError::UringIOError(res)) if res == -libc::EAGAIN => {}
+                Err(e) => break Err(e),
+            }
+            if let Err(e) = event_loop.process_io_until_readable(ring_fd, &mut ops) {
+                break Err(e);
+            }
+        };
+        event_loop.cancel_fd_poll()?;
+        res
+    }
+
     /// Stop ublk device
     ///
     /// # Arguments:
@@ -783,6 +997,25 @@ impl UblkCtrl {
         self.stop()
     }
 
+    /// Give up this device for handover, and write target `state` into the
+    /// exported json
+    ///
+    /// The device is kept for recovery, so it isn't deleted when this
+    /// UblkCtrl is dropped.
+    pub(crate) fn hand_over(&mut self, state: &serde_json::Value) -> Result<i32, UblkError> {
+        self.json["handover"] = state.clone();
+        self.flush_json()?;
+        self.keep_for_recovery();
+        Ok(0)
+    }
+
+    /// Keep this device for recovery, so it isn't deleted when this
+    /// UblkCtrl is dropped
+    pub(crate) fn keep_for_recovery(&mut self) {
+        self.dev_flags =
+            (self.dev_flags & !super::UBLK_DEV_F_ADD_DEV) | super::UBLK_DEV_F_RECOVER_DEV;
+    }
+
     /// Flush this device's json info as file
     pub fn flush_json(&mut self) -> Result<i32, UblkError> {
         if self.json == serde_json::json!({}) {
@@ -798,9 +1031,19 @@ impl UblkCtrl {
         let run_path = self.run_path();
 
         if let Some(parent_dir) = std::path::Path::new(&run_path).parent() {
-            fs::create_dir_all(parent_dir).map_err(UblkError::OtherIOError)?;
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(parent_dir)
+                .map_err(UblkError::OtherIOError)?;
         }
-        let mut run_file = fs::File::create(&run_path).map_err(UblkError::OtherIOError)?;
+        let mut run_file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(&run_path)
+            .map_err(UblkError::OtherIOError)?;
 
         run_file
             .write_all(self.json.to_string().as_bytes())
@@ -822,8 +1065,14 @@ impl UblkCtrl {
         let mut map: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
 
         for qid in 0..dev.dev_info.nr_hw_queues {
-            let mut affinity = self::UblkQueueAffinity::new();
-            self.get_queue_affinity(qid as u32, &mut affinity)?;
+            let affinity = match self.queue_affinities[qid as usize] {
+                Some(affinity) => affinity,
+                None => {
+                    let mut affinity = self::UblkQueueAffinity::new();
+                    self.get_queue_affinity(qid as u32, &mut affinity)?;
+                    affinity
+                }
+            };
 
             map.insert(
                 format!("{}", qid),
@@ -831,6 +1080,7 @@ impl UblkCtrl {
                     "qid": qid,
                     "tid": self.queue_tids[qid as usize],
                     "affinity": affinity.to_bits_vec(),
+                    "numa_node": affinity.numa_node(),
                 }),
             );
         }
@@ -0,0 +1,311 @@
+//! Daemonize ublk server, and report device state to the parent
+//!
+//! `UblkDaemon::daemonize()` forks the daemon, and the parent waits until
+//! the device is LIVE or fails via `UblkDaemonParent`, so it needn't to
+//! poll device state or sleep.
+
+use super::UblkError;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+
+const UBLK_DAEMON_MSG_DEV_ID: i32 = 1;
+const UBLK_DAEMON_MSG_LIVE: i32 = 2;
+const UBLK_DAEMON_MSG_FAIL: i32 = 3;
+
+/// Options for daemonizing ublk server
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct UblkDaemon {
+    /// write pid of the daemon into this file, which is removed when
+    /// `UblkDaemonChild` is dropped; relative path is resolved against
+    /// the current directory of the calling process
+    #[builder(default, setter(strip_option))]
+    pidfile: Option<String>,
+
+    /// redirect stdout and stderr of the daemon to this file, and stdin
+    /// to `/dev/null`; stdio is inherited if it isn't set, and relative
+    /// path is resolved like `pidfile`
+    #[builder(default, setter(strip_option))]
+    stdio: Option<String>,
+
+    /// send sd_notify style readiness to `$NOTIFY_SOCKET` once the device
+    /// is LIVE
+    #[builder(default)]
+    sd_notify: bool,
+}
+
+/// Result of `UblkDaemon::daemonize()`
+pub enum UblkFork {
+    /// in the calling process
+    Parent(UblkDaemonParent),
+
+    /// in the daemon process
+    Daemon(UblkDaemonChild),
+}
+
+/// Parent side for waiting the daemon's report
+pub struct UblkDaemonParent {
+    pipe: fs::File,
+    dev_id: i32,
+}
+
+/// Daemon side, which owns the pidfile
+pub struct UblkDaemonChild {
+    notifier: UblkDaemonNotifier,
+    pidfile: Option<String>,
+}
+
+/// Report device state from daemon to the parent
+///
+/// It can be cloned and moved to any context, such as `worker_fn` passed
+/// to `UblkSession::run()`.
+#[derive(Clone)]
+pub struct UblkDaemonNotifier {
+    pipe: Arc<OwnedFd>,
+    notify_socket: Option<String>,
+}
+
+fn last_os_error() -> UblkError {
+    UblkError::OtherIOError(std::io::Error::last_os_error())
+}
+
+impl UblkDaemon {
+    /// Fork the daemon, which runs in new session and isn't child of the
+    /// calling process
+    ///
+    /// Return `UblkFork::Parent` in the calling process, and
+    /// `UblkFork::Daemon` in the daemon. If setting up the daemon fails,
+    /// failure is reported to the parent and the daemon exits.
+    ///
+    /// It has to be called before creating any thread.
+    ///
+    /// Same with daemon(7), the daemon changes its working directory to
+    /// `/`, so relative paths used in the daemon are resolved against `/`,
+    /// and the caller has to resolve its own paths, such as the backing
+    /// file, before calling this method. umask is inherited from the
+    /// calling process, so files created by target aren't exposed.
+    pub fn daemonize(&self) -> Result<UblkFork, UblkError> {
+        let mut fds = [0_i32; 2];
+        let pidfile = self.pidfile.as_deref().map(absolute_path).transpose()?;
+        let stdio = self.stdio.as_deref().map(absolute_path).transpose()?;
+
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(last_os_error());
+        }
+        let (rfd, wfd) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(last_os_error());
+        }
+        if pid > 0 {
+            drop(wfd);
+            // the intermediate process exits after forking the daemon
+            unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+            return Ok(UblkFork::Parent(UblkDaemonParent {
+                pipe: fs::File::from(rfd),
+                dev_id: -1,
+            }));
+        }
+
+        drop(rfd);
+        unsafe {
+            libc::setsid();
+            match libc::fork() {
+                0 => {}
+                pid if pid < 0 => libc::_exit(1),
+                _ => libc::_exit(0),
+            }
+            // parent may exit before the daemon writes to the pipe
+            libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+        }
+
+        let notifier = UblkDaemonNotifier {
+            pipe: Arc::new(wfd),
+            notify_socket: if self.sd_notify {
+                std::env::var("NOTIFY_SOCKET").ok()
+            } else {
+                None
+            },
+        };
+
+        match Self::setup_daemon(pidfile.as_deref(), stdio.as_deref()) {
+            Ok(_) => Ok(UblkFork::Daemon(UblkDaemonChild { notifier, pidfile })),
+            Err(e) => {
+                let _ = notifier.notify_failure(&e);
+                unsafe { libc::_exit(1) };
+            }
+        }
+    }
+
+    fn setup_daemon(pidfile: Option<&str>, stdio: Option<&str>) -> Result<(), UblkError> {
+        // don't keep the mount of the current directory busy
+        std::env::set_current_dir("/").map_err(UblkError::OtherIOError)?;
+
+        if let Some(path) = stdio {
+            let null = fs::File::open("/dev/null").map_err(UblkError::OtherIOError)?;
+            let out = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o644)
+                .open(path)
+                .map_err(UblkError::OtherIOError)?;
+
+            unsafe {
+                if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) < 0
+                    || libc::dup2(out.as_raw_fd(), libc::STDOUT_FILENO) < 0
+                    || libc::dup2(out.as_raw_fd(), libc::STDERR_FILENO) < 0
+                {
+                    return Err(last_os_error());
+                }
+            }
+        }
+
+        if let Some(path) = pidfile {
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .mode(0o644)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", std::process::id()))
+                .map_err(UblkError::OtherIOError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve `path` against the current directory, which is changed to `/`
+/// in the daemon
+fn absolute_path(path: &str) -> Result<String, UblkError> {
+    let cwd = std::env::current_dir().map_err(UblkError::OtherIOError)?;
+
+    Ok(cwd.join(path).display().to_string())
+}
+
+impl UblkDaemonParent {
+    fn read_msg(&mut self) -> Result<(i32, i32), UblkError> {
+        let mut buf = [0_u8; 8];
+
+        match self.pipe.read_exact(&mut buf) {
+            Ok(_) => Ok((
+                i32::from_ne_bytes(buf[0..4].try_into().unwrap()),
+                i32::from_ne_bytes(buf[4..8].try_into().unwrap()),
+            )),
+            // daemon exits without reporting LIVE
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(UblkError::OtherError(-libc::ECHILD))
+            }
+            Err(e) => Err(UblkError::OtherIOError(e)),
+        }
+    }
+
+    /// Wait until the device is added, and return its device id
+    pub fn wait_dev_id(&mut self) -> Result<i32, UblkError> {
+        while self.dev_id < 0 {
+            match self.read_msg()? {
+                (UBLK_DAEMON_MSG_DEV_ID, id) | (UBLK_DAEMON_MSG_LIVE, id) => self.dev_id = id,
+                (UBLK_DAEMON_MSG_FAIL, e) => return Err(UblkError::OtherError(e)),
+                _ => {}
+            }
+        }
+        Ok(self.dev_id)
+    }
+
+    /// Wait until the device becomes LIVE, and return its device id
+    ///
+    /// `UblkError::OtherError(-ECHILD)` is returned if the daemon exits
+    /// without reporting LIVE or failure.
+    pub fn wait_live(&mut self) -> Result<i32, UblkError> {
+        loop {
+            match self.read_msg()? {
+                (UBLK_DAEMON_MSG_DEV_ID, id) => self.dev_id = id,
+                (UBLK_DAEMON_MSG_LIVE, id) => {
+                    self.dev_id = id;
+                    return Ok(id);
+                }
+                (UBLK_DAEMON_MSG_FAIL, e) => return Err(UblkError::OtherError(e)),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl UblkDaemonChild {
+    pub fn notifier(&self) -> UblkDaemonNotifier {
+        self.notifier.clone()
+    }
+}
+
+impl Drop for UblkDaemonChild {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pidfile {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl UblkDaemonNotifier {
+    fn send(&self, msg: i32, val: i32) -> Result<(), UblkError> {
+        let mut buf = [0_u8; 8];
+
+        buf[0..4].copy_from_slice(&msg.to_ne_bytes());
+        buf[4..8].copy_from_slice(&val.to_ne_bytes());
+        let ret = unsafe {
+            libc::write(
+                self.pipe.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(last_os_error());
+        }
+        Ok(())
+    }
+
+    fn sd_notify(&self, state: &str) -> Result<(), UblkError> {
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let path = match &self.notify_socket {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let sock = UnixDatagram::unbound().map_err(UblkError::OtherIOError)?;
+        let res = match path.strip_prefix('@') {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                SocketAddr::from_abstract_name(name)
+                    .and_then(|addr| sock.send_to_addr(state.as_bytes(), &addr))
+            }
+            None => sock.send_to(state.as_bytes(), path),
+        };
+        res.map(|_| ()).map_err(UblkError::OtherIOError)
+    }
+
+    /// Report device id after the device is added
+    pub fn notify_dev_id(&self, dev_id: u32) -> Result<(), UblkError> {
+        self.send(UBLK_DAEMON_MSG_DEV_ID, dev_id as i32)
+    }
+
+    /// Report that the device becomes LIVE, and send `READY=1` to
+    /// `$NOTIFY_SOCKET` if `sd_notify` is set
+    pub fn notify_live(&self, dev_id: u32) -> Result<(), UblkError> {
+        self.send(UBLK_DAEMON_MSG_LIVE, dev_id as i32)?;
+        self.sd_notify(&format!(
+            "READY=1\nMAINPID={}\nSTATUS=ublk device {} is live\n",
+            std::process::id(),
+            dev_id
+        ))
+    }
+
+    /// Report failure, and the parent gets `UblkError::OtherError` with
+    /// negative errno of `e`
+    pub fn notify_failure(&self, e: &UblkError) -> Result<(), UblkError> {
+        self.send(UBLK_DAEMON_MSG_FAIL, e.to_errno())
+    }
+}
@@ -1,9 +1,14 @@
-use super::{ctrl::UblkCtrl, sys, UblkError};
+use super::{ctrl::UblkCtrl, ctrl::UblkQueueAffinity, sys, UblkError};
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 /// Return value of IO handling closure.
 ///
@@ -24,20 +29,20 @@ pub const UBLK_IO_S_COMP_BATCH: i32 = 1;
 /// /dev/ublkbN, or plain io_uring IO submitted from ublk target code, still
 /// in the same IO handling closure.
 ///
-/// If target won't use io_uring to handle IO, eventfd needs to be sent from
-/// the real handler context to wakeup ublk queue/io_uring context for
-/// driving the machinery. Eventfd gets minimized support with
-/// `libublk::UBLK_DEV_F_COMP_BATCH`, and native & generic IO offloading will
-/// be added soon.
+/// If target won't use io_uring to handle IO, the real handler context
+/// has to wakeup ublk queue/io_uring context for driving the machinery,
+/// and `UblkCompleter` returned from `UblkQueue::completer()` is provided
+/// for completing IO from other context.
 ///
 /// UblkIOCtx & UblkQueueCtx provide enough information for target code to
 /// handle this CQE and implement target IO handling logic.
 ///
-pub struct UblkIOCtx<'a, 'b, 'd>(
-    &'a mut io_uring::IoUring<io_uring::squeue::Entry>,
-    &'b mut UblkIO,
+pub struct UblkIOCtx<'a, 'b, 'd, T = ()>(
+    &'a mut UblkQueueRing,
+    &'b mut UblkIO<T>,
     &'d UblkCQE<'d>,
     Option<Vec<(u16, i32)>>,
+    &'b mut UblkExtraIos,
 );
 
 /// Check if this userdata is from target IO
@@ -46,7 +51,56 @@ fn is_target_io(user_data: u64) -> bool {
     (user_data & (1_u64 << 63)) != 0
 }
 
-impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
+/// userdata of IO submitted by libublk itself, such as polling eventfd of
+/// completion channel, and handled without calling IO closure
+const UBLK_INTERNAL_IO: u64 = 1_u64 << 62;
+
+/// internal IO ops, stored in the `op` field of userdata
+const UBLK_INTERNAL_OP_COMP_CHAN: u32 = 1;
+const UBLK_INTERNAL_OP_CANCEL: u32 = 2;
+const UBLK_INTERNAL_OP_TIMER: u32 = 3;
+const UBLK_INTERNAL_OP_DEADLINE: u32 = 4;
+const UBLK_INTERNAL_OP_IDLE: u32 = 5;
+const UBLK_INTERNAL_OP_STATS: u32 = 6;
+const UBLK_INTERNAL_OP_STOP: u32 = 7;
+const UBLK_INTERNAL_OP_SIGNAL: u32 = 8;
+const UBLK_INTERNAL_OP_WAKEUP: u32 = 9;
+const UBLK_INTERNAL_OP_POLL_FD: u32 = 10;
+const UBLK_INTERNAL_OP_STATS_REQ: u32 = 11;
+
+#[inline(always)]
+fn is_internal_io(user_data: u64) -> bool {
+    (user_data & UBLK_INTERNAL_IO) != 0
+}
+
+#[inline(always)]
+fn build_internal_user_data(tag: u16, op: u32, data: u32) -> u64 {
+    UblkIOCtx::build_user_data(tag, op, data, false) | UBLK_INTERNAL_IO
+}
+
+#[inline(always)]
+fn user_data_to_tgt_data(user_data: u64) -> u32 {
+    ((user_data >> 24) & 0xffff) as u32
+}
+
+/// userdata of linked SQEs submitted by `UblkIOCtx::submit_chain()`
+const UBLK_CHAIN_IO: u64 = 1_u64 << 61;
+
+#[inline(always)]
+fn is_chain_io(user_data: u64) -> bool {
+    (user_data & UBLK_CHAIN_IO) != 0
+}
+
+/// bits [40, 56) of userdata store queue slot in `UblkEventLoop`
+const UBLK_USER_DATA_SLOT_SHIFT: u32 = 40;
+const UBLK_USER_DATA_SLOT_MASK: u64 = 0xffff_u64 << UBLK_USER_DATA_SLOT_SHIFT;
+
+#[inline(always)]
+fn user_data_to_slot(user_data: u64) -> u16 {
+    ((user_data >> UBLK_USER_DATA_SLOT_SHIFT) & 0xffff) as u16
+}
+
+impl<'a, 'b, 'd, T> UblkIOCtx<'a, 'b, 'd, T> {
     /// Set LBA for UBLK_IO_ZONE_APPEND
     #[inline(always)]
     pub fn set_zone_append_lab(&mut self, lba: u64) {
@@ -56,10 +110,114 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
     /// Return io_uring instance which is shared in queue wide.
     ///
     /// Target IO often needs to handle IO command by io_uring further,
-    /// so io_uring instance has to be exposed.
+    /// so io_uring instance has to be exposed. SQ may be full if target
+    /// submits more than one SQE for one IO, so `try_push()` or
+    /// `push_async()` is preferred for queueing SQE.
+    ///
+    /// If the queue is served by `UblkEventLoop`, userdata of SQE pushed
+    /// to this ring directly has to be built by `tgt_user_data()`.
     #[inline(always)]
     pub fn get_ring(&mut self) -> &mut io_uring::IoUring<io_uring::squeue::Entry> {
-        self.0
+        &mut self.0.ring
+    }
+
+    /// Build userdata of target IO for this IO, same with
+    /// `UblkIOCtx::build_user_data(tag, op, tgt_data, true)`, and slot of
+    /// the queue in `UblkEventLoop` is included, so CQE of SQE pushed to
+    /// `get_ring()` directly can be dispatched to this queue
+    #[inline(always)]
+    pub fn tgt_user_data(&self, op: u32, tgt_data: u32) -> u64 {
+        UblkIOCtx::build_user_data(self.get_tag() as u16, op, tgt_data, true) | self.0.ud_slot
+    }
+
+    /// Push `sqe` to the queue ring, and SQEs are submitted for making room
+    /// if SQ is full; `UblkError::UringPushError` is returned if SQ is
+    /// still full.
+    ///
+    /// # Safety
+    ///
+    /// Same with `io_uring::SubmissionQueue::push()`, buffers referenced
+    /// by `sqe` have to be valid until the IO is completed.
+    #[inline(always)]
+    pub unsafe fn try_push(&mut self, sqe: &squeue::Entry) -> Result<(), UblkError> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(user_data = sqe.get_user_data(), "tgt submit");
+        self.0.try_push(sqe)
+    }
+
+    /// Push `sqe` to the queue ring, and it is kept in overflow list and
+    /// pushed later by queue in case that SQ is full, so it never fails.
+    ///
+    /// The overflow list is one unbounded FIFO queue of the ring: new SQE
+    /// is queued after all overflowed SQEs, and they are moved to SQ in
+    /// order before the queue waits for completion. So SQE pushed here may
+    /// not be seen by kernel until the next round of `process_io()`.
+    ///
+    /// # Safety
+    ///
+    /// Same with `io_uring::SubmissionQueue::push()`
+    #[inline(always)]
+    pub unsafe fn push_async(&mut self, sqe: squeue::Entry) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(user_data = sqe.get_user_data(), "tgt submit");
+        self.0.push_async(sqe)
+    }
+
+    /// Submit `chain` of linked SQEs for this IO
+    ///
+    /// userdata of every SQE is built from this IO's tag, `op` and
+    /// `tgt_data`, and IO closure is called once after CQEs of the whole
+    /// chain are received. `result()` is the 1st failure in the chain, or
+    /// the last SQE's result if all succeed. If the chain is broken by
+    /// short read or write, -ECANCELED is the result.
+    ///
+    /// -EBUSY is returned if the previous chain of this IO isn't done.
+    ///
+    /// # Safety
+    ///
+    /// Same with `io_uring::SubmissionQueue::push()`
+    pub unsafe fn submit_chain(
+        &mut self,
+        chain: UblkIOChain,
+        op: u32,
+        tgt_data: u32,
+    ) -> Result<(), UblkError> {
+        if self.1.chain_pending > 0 {
+            return Err(UblkError::OtherError(-libc::EBUSY));
+        }
+        if chain.is_empty() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let data =
+            UblkIOCtx::build_user_data(self.get_tag() as u16, op, tgt_data, true) | UBLK_CHAIN_IO;
+        let sqes: Vec<squeue::Entry> = chain.sqes.into_iter().map(|e| e.user_data(data)).collect();
+        let nr = sqes.len() as u32;
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(user_data = data, nr, "tgt submit chain");
+        self.0.push_chain(sqes)?;
+        self.1.chain_pending = nr;
+        self.1.chain_res = 0;
+        Ok(())
+    }
+
+    /// Return fixed file for `idx` of `UblkTgt.fds`, which has to be used
+    /// for target IO if the queue is served by `UblkEventLoop`, in which
+    /// every device owns one range of the fixed file table
+    #[inline(always)]
+    pub fn fixed_fd(&self, idx: u32) -> types::Fixed {
+        types::Fixed(self.0.fd_base + idx)
+    }
+
+    /// Return the standalone IOPOLL ring for target IO, None if
+    /// `UblkRingConfig::iopoll` isn't enabled or can't be setup.
+    ///
+    /// Registered files are same with the ring returned from `get_ring()`,
+    /// and CQE of IO submitted to this ring is handled by IO closure too.
+    #[inline(always)]
+    pub fn get_iopoll_ring(&mut self) -> Option<&mut io_uring::IoUring<io_uring::squeue::Entry>> {
+        self.0.iopoll.as_mut()
     }
 
     /// Return CQE's request of this IO, and used for handling target IO by
@@ -116,7 +274,10 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
     /// to manage io buffer.
     #[inline(always)]
     pub fn io_buf_addr(&self) -> *mut u8 {
-        self.1.get_buf_addr()
+        match self.4.buf(self.get_tag()) {
+            Some(buf) => buf,
+            None => self.1.get_buf_addr(),
+        }
     }
 
     /// Called when this IO command is handled, and tell libublk & ublk driver
@@ -130,7 +291,8 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
     /// Add completed IOs represented by (tag, res) to batch list, so that
     /// we can complete them after returning from io handling closure, which
     /// must return `UBLK_IO_S_COMP_BATCH`, so that we know that there are
-    /// IOs in batch list.
+    /// IOs in batch list. Same with `UblkCompleter::complete()`, invalid
+    /// or duplicated completion is dropped.
     ///
     /// # Arguments:
     ///
@@ -144,6 +306,70 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
         }
     }
 
+    /// Return target state of this IO, see `UblkQueue::with_state()`
+    #[inline(always)]
+    pub fn state(&self) -> &T {
+        &self.1.state
+    }
+
+    /// Return mutable target state of this IO, which is reset to
+    /// `T::default()` when this tag is re-fetched from ublk driver
+    #[inline(always)]
+    pub fn state_mut(&mut self) -> &mut T {
+        &mut self.1.state
+    }
+
+    /// Set deadline of target IO submitted with `user_data` for this IO
+    ///
+    /// If the target IO isn't completed in `timeout`, it is canceled by
+    /// `AsyncCancel`, and the ublk IO is completed with -ETIMEDOUT by
+    /// libublk without calling IO closure. For extra IO slot, the handler
+    /// is called with -ETIMEDOUT as result.
+    ///
+    /// The deadline is ended by the 1st CQE matching `user_data`, or the
+    /// aggregated CQE of linked chain. Each IO has only one deadline, and
+    /// the previous one is replaced.
+    pub fn set_deadline(&mut self, user_data: u64, timeout: Duration) {
+        let tag = self.get_tag() as u16;
+        let io = &mut *self.1;
+
+        if io.deadline != 0 {
+            let old = build_internal_user_data(tag, UBLK_INTERNAL_OP_DEADLINE, io.dl_seq as u32);
+            let sqe = opcode::TimeoutRemove::new(old | self.0.ud_slot)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+            unsafe { self.0.push_async(sqe) };
+        }
+
+        io.deadline = user_data & !(UBLK_CHAIN_IO | UBLK_USER_DATA_SLOT_MASK);
+        io.dl_seq = io.dl_seq.wrapping_add(1);
+        io.dl_expired = false;
+        io.dl_ts = timeout.into();
+
+        let sqe = opcode::Timeout::new(&io.dl_ts)
+            .build()
+            .user_data(build_internal_user_data(
+                tag,
+                UBLK_INTERNAL_OP_DEADLINE,
+                io.dl_seq as u32,
+            ));
+        unsafe { self.0.push_async(sqe) };
+    }
+
+    /// Allocate one extra IO slot, see `UblkQueue::alloc_extra_io()`
+    #[inline(always)]
+    pub fn alloc_extra_io(&mut self, buf: *mut u8) -> Option<UblkExtraIO> {
+        self.4.alloc(buf)
+    }
+
+    /// Free extra IO slot `io`, see `UblkQueue::free_extra_io()`
+    #[inline(always)]
+    pub fn free_extra_io(&mut self, io: UblkExtraIO) {
+        self.4.free(io)
+    }
+}
+
+impl UblkIOCtx<'_, '_, '_> {
     /// Build offset for read from or write to per-io-cmd buffer
     ///
     /// # Arguments:
@@ -175,18 +401,23 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
     ///
     /// * `tag`: io tag, length is 16bit
     /// * `op`: io operation code, length is 8bit
-    /// * `tgt_data`: target specific data, at most 39bit (64 - 16 - 8 - 1)
+    /// * `tgt_data`: target specific data, at most 16bit
     /// * `is_target_io`: if this userdata is for handling target io, false if
     ///         if it is only for ublk io command
     ///
-    /// The built userdata is passed to io_uring for parsing io result
+    /// The built userdata is passed to io_uring for parsing io result, and
+    /// bit 40 ~ 55 are reserved for `UblkEventLoop`, bit 61 is reserved for
+    /// `UblkIOCtx::submit_chain()`
     ///
     #[inline(always)]
     #[allow(arithmetic_overflow)]
     pub fn build_user_data(tag: u16, op: u32, tgt_data: u32, is_target_io: bool) -> u64 {
         assert!((op >> 8) == 0 && (tgt_data >> 16) == 0);
 
-        tag as u64 | (op << 16) as u64 | (tgt_data << 24) as u64 | ((is_target_io as u64) << 63)
+        tag as u64
+            | ((op as u64) << 16)
+            | (((tgt_data & 0xffff) as u64) << 24)
+            | ((is_target_io as u64) << 63)
     }
 
     /// Extract tag from userdata
@@ -205,12 +436,19 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
 const UBLK_IO_F_FIRST: u32 = 1u32 << 16;
 const UBLK_IO_F_LAST: u32 = 1u32 << 17;
 
-struct UblkCQE<'d>(&'d cqueue::Entry, u32);
+/// CQE, flags and result, which is the whole chain's result for linked
+/// SQEs
+struct UblkCQE<'d>(&'d cqueue::Entry, u32, i32);
 
 impl<'a> UblkCQE<'a> {
+    #[inline(always)]
+    fn new(cqe: &'a cqueue::Entry, flags: u32) -> UblkCQE<'a> {
+        UblkCQE(cqe, flags, cqe.result())
+    }
+
     #[inline(always)]
     fn result(&self) -> i32 {
-        self.0.result()
+        self.2
     }
     #[inline(always)]
     fn user_data(&self) -> u64 {
@@ -232,6 +470,116 @@ impl<'a> UblkCQE<'a> {
     }
 }
 
+/// SQPOLL setting of queue io_uring
+///
+/// One kernel thread polls the SQ of this ring, so submitting target IO
+/// needn't to enter kernel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UblkSqPoll {
+    /// how long(ms) the SQPOLL thread can be idle before going to sleep
+    pub idle_ms: u32,
+
+    /// pin the SQPOLL thread to this cpu
+    pub cpu: Option<u32>,
+
+    /// share one SQPOLL thread among all queues of this device, the 1st
+    /// created queue ring owns the thread, and the others are attached
+    /// to it via IORING_SETUP_ATTACH_WQ; queue created after the owner
+    /// queue is dropped owns one new SQPOLL thread
+    pub shared: bool,
+}
+
+/// io_uring setup options of ublk queue
+///
+/// Applied when creating each queue's io_uring, and the default is plain
+/// io_uring with IORING_SETUP_COOP_TASKRUN.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UblkRingConfig {
+    /// setup ring with IORING_SETUP_SQPOLL
+    pub sqpoll: Option<UblkSqPoll>,
+
+    /// Polled target IO(IORING_SETUP_IOPOLL), such as O_DIRECT IO on
+    /// NVMe backed file.
+    ///
+    /// ublk io command can't be issued on IOPOLL ring, so one standalone
+    /// IOPOLL ring is created for target IO, see `UblkIOCtx::get_iopoll_ring()`;
+    /// the queue keeps polling it when there is any target IO in-flight.
+    pub iopoll: bool,
+
+    /// IORING_SETUP_SINGLE_ISSUER, only the queue pthread submits IO
+    pub single_issuer: bool,
+
+    /// IORING_SETUP_DEFER_TASKRUN, which requires single issuer, so
+    /// `single_issuer` is implied
+    pub defer_taskrun: bool,
+
+    /// share async worker(and SQPOLL thread) with this io_uring fd
+    #[serde(skip)]
+    pub attach_wq: Option<i32>,
+
+    /// If kernel rejects the setup, retry by dropping flags one by one
+    /// until plain io_uring is created, instead of failing the queue
+    pub fallback: bool,
+}
+
+impl UblkRingConfig {
+    fn builder(&self, cq_depth: u32, wq_fd: Option<i32>) -> io_uring::Builder {
+        let mut builder = IoUring::<squeue::Entry, cqueue::Entry>::builder();
+
+        builder.setup_cqsize(cq_depth);
+        match self.sqpoll {
+            Some(sq) => {
+                builder.setup_sqpoll(sq.idle_ms);
+                if let Some(cpu) = sq.cpu {
+                    builder.setup_sqpoll_cpu(cpu);
+                }
+            }
+            // COOP_TASKRUN can't be used together with SQPOLL, and
+            // TASKRUN_FLAG tells if pending task work has to be run by
+            // entering the ring
+            None => {
+                builder.setup_coop_taskrun().setup_taskrun_flag();
+            }
+        }
+        if self.single_issuer || self.defer_taskrun {
+            builder.setup_single_issuer();
+        }
+        if self.defer_taskrun {
+            builder.setup_defer_taskrun().setup_taskrun_flag();
+        }
+        if let Some(fd) = wq_fd {
+            builder.setup_attach_wq(fd);
+        }
+        builder
+    }
+
+    /// Return one weaker config by dropping the most recent flag, None
+    /// means nothing can be dropped any more
+    fn degrade(&self) -> Option<UblkRingConfig> {
+        let mut cfg = self.clone();
+
+        if cfg.defer_taskrun {
+            cfg.defer_taskrun = false;
+        } else if cfg.single_issuer {
+            cfg.single_issuer = false;
+        } else if cfg.attach_wq.is_some() {
+            cfg.attach_wq = None;
+        } else if cfg.sqpoll.is_some_and(|sq| sq.shared) {
+            cfg.sqpoll = cfg.sqpoll.map(|sq| UblkSqPoll {
+                shared: false,
+                ..sq
+            });
+        } else if cfg.sqpoll.is_some_and(|sq| sq.cpu.is_some()) {
+            cfg.sqpoll = cfg.sqpoll.map(|sq| UblkSqPoll { cpu: None, ..sq });
+        } else if cfg.sqpoll.is_some() {
+            cfg.sqpoll = None;
+        } else {
+            return None;
+        }
+        Some(cfg)
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UblkTgt {
     /// target type
@@ -240,8 +588,9 @@ pub struct UblkTgt {
     /// target device size, will be the actual size of /dev/ublkbN
     pub dev_size: u64,
 
-    /// target specific io_ring flags, default is 0
-    pub ring_flags: u64,
+    /// target specific io_uring setup options
+    #[serde(default)]
+    pub ring: UblkRingConfig,
 
     /// uring SQ depth, default is queue depth
     pub sq_depth: u16,
@@ -250,7 +599,7 @@ pub struct UblkTgt {
     pub cq_depth: u16,
 
     /// extra io slots, usually for meta data handling or eventfd,
-    /// default is 0
+    /// default is 0, and allocated by `UblkQueue::alloc_extra_io()`
     pub extra_ios: u16,
 
     //const struct ublk_tgt_ops *ops;
@@ -274,10 +623,16 @@ pub struct UblkDev {
     /// reserved for supporting new features
     pub flags: u32,
 
-    //fds[0] points to /dev/ublkcN
-    cdev_file: fs::File,
+    /// /dev/ublkcN, which is released and opened again by
+    /// `reopen_cdev()` for in-process recovery
+    cdev_file: Mutex<Option<fs::File>>,
 
     pub tgt: UblkTgt,
+
+    /// dup of the ring fd owning the shared SQPOLL thread, which is held
+    /// until the owner queue is dropped, and the lock serializes creating
+    /// the 1st shared SQPOLL ring
+    sqpoll_ring: Mutex<Option<OwnedFd>>,
 }
 
 unsafe impl Send for UblkDev {}
@@ -305,7 +660,6 @@ impl UblkDev {
             sq_depth: info.queue_depth,
             cq_depth: info.queue_depth,
             fds: [0_i32; 32],
-            ring_flags: 0,
             ..Default::default()
         };
 
@@ -321,9 +675,10 @@ impl UblkDev {
 
         let mut dev = UblkDev {
             dev_info: info,
-            cdev_file,
+            cdev_file: Mutex::new(Some(cdev_file)),
             tgt,
             flags: ctrl.get_dev_flags(),
+            sqpoll_ring: Mutex::new(None),
         };
 
         ctrl.json = ops(&mut dev)?;
@@ -339,6 +694,67 @@ impl UblkDev {
         info!("dev {} deinitialized", id);
     }
 
+    /// Return fd of /dev/ublkcN, which may be changed by `reopen_cdev()`
+    fn cdev_fd(&self) -> Result<RawFd, UblkError> {
+        match &*self.cdev_file.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(f) => Ok(f.as_raw_fd()),
+            None => Err(UblkError::OtherError(-libc::ENODEV)),
+        }
+    }
+
+    /// Target fds for registering to io_uring, and `fds[0]` is replaced
+    /// with current fd of /dev/ublkcN
+    fn tgt_fds(&self) -> Result<Vec<RawFd>, UblkError> {
+        let mut fds = self.tgt.fds[0..self.tgt.nr_fds as usize].to_vec();
+
+        fds[0] = self.cdev_fd()?;
+        Ok(fds)
+    }
+
+    /// Release /dev/ublkcN, call `f`, then open /dev/ublkcN again
+    ///
+    /// ublk driver requires /dev/ublkcN to be released before starting
+    /// user recovery, so this way is for in-process recovery. The file is
+    /// swapped under the lock, so fd number of /dev/ublkcN may change, and
+    /// `tgt.fds[0]` isn't valid after reopening. Queues created later
+    /// register the new fd as fixed file 0.
+    ///
+    /// All queues have to be dropped before calling this method.
+    pub(crate) fn reopen_cdev<F>(&self, f: F) -> Result<i32, UblkError>
+    where
+        F: FnOnce() -> Result<i32, UblkError>,
+    {
+        let mut cdev = self.cdev_file.lock().unwrap_or_else(|e| e.into_inner());
+        let cdev_path = format!("{}{}", super::CDEV_PATH, self.dev_info.dev_id);
+
+        drop(cdev.take());
+        let res = f();
+
+        *cdev = Some(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(cdev_path)
+                .map_err(UblkError::OtherIOError)?,
+        );
+        res
+    }
+
+    /// Release /dev/ublkcN for handing over this device to another
+    /// process, which can't start user recovery until the file is
+    /// released
+    ///
+    /// All queues have to be dropped before calling this method, and no
+    /// queue can be created after it.
+    pub(crate) fn close_cdev(&self) {
+        drop(
+            self.cdev_file
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take(),
+        );
+    }
+
     pub fn set_default_params(&mut self, dev_size: u64) {
         let info = self.dev_info;
 
@@ -365,12 +781,121 @@ impl Drop for UblkDev {
     }
 }
 
+/// Completion channel shared between one queue and its `UblkCompleter`s
+struct UblkCompChan {
+    efd: i32,
+    ios: Mutex<Vec<(u16, i32)>>,
+}
+
+impl Drop for UblkCompChan {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.efd);
+        }
+    }
+}
+
+/// Handle for completing IO from other context
+///
+/// Returned from `UblkQueue::completer()`, and can be cloned and sent to
+/// any thread which handles IO offloaded from the IO closure, such as
+/// compression or encryption worker.
+///
+/// Posted IO is completed in the queue context: the queue is woken up via
+/// one internal eventfd, then all posted IOs are completed in one batch.
+#[derive(Clone)]
+pub struct UblkCompleter {
+    chan: Arc<UblkCompChan>,
+}
+
+impl UblkCompleter {
+    /// Post completion of one IO command
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io tag
+    /// * `res`: IO handling result
+    ///
+    /// The IO is owned by the queue again after it is posted, so the
+    /// caller can't touch its buffer any more. Completion of IO which
+    /// isn't owned by target, such as duplicated one, is dropped with one
+    /// warning, and counted in `UblkQueueStats::dropped_comps`.
+    pub fn complete(&self, tag: u16, res: i32) -> Result<(), UblkError> {
+        let wakeup = {
+            let mut ios = self.chan.ios.lock().unwrap_or_else(|e| e.into_inner());
+
+            ios.push((tag, res));
+            ios.len() == 1
+        };
+
+        // the queue is being woken up if the list isn't empty
+        if wakeup {
+            let val = 1_u64;
+            let ret = unsafe {
+                libc::write(
+                    self.chan.efd,
+                    std::ptr::addr_of!(val) as *const libc::c_void,
+                    core::mem::size_of::<u64>(),
+                )
+            };
+            if ret < 0 {
+                return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct UblkStopChan {
+    efd: i32,
+}
+
+impl Drop for UblkStopChan {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.efd);
+        }
+    }
+}
+
+/// Handle for stopping queue from other context
+///
+/// Returned from `UblkQueue::stop_handle()`, and can be cloned and sent to
+/// any thread.
+#[derive(Clone)]
+pub struct UblkQueueStopHandle {
+    chan: Arc<UblkStopChan>,
+}
+
+impl UblkQueueStopHandle {
+    /// Ask the queue to leave `wait_and_handle_io()`, see
+    /// `UblkQueue::stop_handle()`
+    pub fn stop(&self) -> Result<(), UblkError> {
+        let val = 1_u64;
+        let ret = unsafe {
+            libc::write(
+                self.chan.efd,
+                std::ptr::addr_of!(val) as *const libc::c_void,
+                core::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
 const UBLK_IO_NEED_FETCH_RQ: u32 = 1_u32 << 0;
 const UBLK_IO_NEED_COMMIT_RQ_COMP: u32 = 1_u32 << 1;
 const UBLK_IO_FREE: u32 = 1u32 << 2;
 const UBLK_IO_TO_QUEUE: u32 = 1u32 << 3;
+/// IO comes after the queue is stopped, and it is left to driver
+const UBLK_IO_PARKED: u32 = 1u32 << 4;
+/// IO command is queued to driver, and its CQE isn't received yet
+const UBLK_IO_IN_FLIGHT: u32 = 1u32 << 5;
 
-struct UblkIO {
+struct UblkIO<T = ()> {
     // for holding the allocated buffer
     __buf_addr: *mut u8,
 
@@ -378,9 +903,30 @@ struct UblkIO {
     buf_addr: u64,
     flags: u32,
     result: i32,
+
+    // in-flight SQEs of the linked chain, and result of the chain
+    chain_pending: u32,
+    chain_res: i32,
+
+    // userdata of target IO with deadline, zero if no deadline, and
+    // sequence of the armed deadline timeout
+    deadline: u64,
+    dl_seq: u16,
+    dl_expired: bool,
+    dl_ts: types::Timespec,
+
+    // when the IO command is received, for latency stats
+    start: Instant,
+
+    // span from receiving the IO command to committing it
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+
+    // per-IO target state
+    state: T,
 }
 
-impl UblkIO {
+impl<T> UblkIO<T> {
     #[inline(always)]
     fn get_buf_addr(&self) -> *mut u8 {
         self.__buf_addr
@@ -403,10 +949,154 @@ impl UblkIO {
     /// so ublk driver gets notified and complete IO request on
     /// /dev/ublkbN
     ///
+    /// Chain state is reset too, since the IO may be failed before all
+    /// CQEs of its linked chain are received, such as by deadline or
+    /// handler panic.
     #[inline(always)]
     fn complete(&mut self, res: i32) {
         self.flags |= UBLK_IO_NEED_COMMIT_RQ_COMP | UBLK_IO_FREE | UBLK_IO_TO_QUEUE;
         self.result = res;
+        self.chain_pending = 0;
+        self.chain_res = 0;
+    }
+
+    /// If the IO command is received from driver and isn't completed
+    /// yet, so it is owned by target
+    #[inline(always)]
+    fn is_owned_by_tgt(&self) -> bool {
+        (self.flags & (UBLK_IO_FREE | UBLK_IO_PARKED | UBLK_IO_IN_FLIGHT)) == 0
+    }
+
+    #[inline(always)]
+    fn deadline_matches(&self, user_data: u64) -> bool {
+        self.deadline != 0
+            && (user_data & !(UBLK_CHAIN_IO | UBLK_USER_DATA_SLOT_MASK)) == self.deadline
+    }
+
+    /// One CQE of the linked chain is received, return result of the
+    /// whole chain after all CQEs are received: the 1st failure, or the
+    /// last SQE's result if all succeed
+    ///
+    /// CQE is ignored if no chain is pending: the IO has been completed
+    /// before its chain is done, and the CQE is stale.
+    #[inline(always)]
+    fn chain_cqe(&mut self, res: i32) -> Option<i32> {
+        if self.chain_pending == 0 {
+            return None;
+        }
+        if self.chain_res >= 0 {
+            self.chain_res = res;
+        }
+        self.chain_pending -= 1;
+        if self.chain_pending == 0 {
+            Some(self.chain_res)
+        } else {
+            None
+        }
+    }
+}
+
+/// Chain of linked SQEs for handling one IO
+///
+/// Each SQE is started after the previous one completes, such as write
+/// followed by fsync for FUA, or read followed by write for read-modify-write.
+/// The chain is submitted by `UblkIOCtx::submit_chain()`.
+#[derive(Default)]
+pub struct UblkIOChain {
+    sqes: Vec<squeue::Entry>,
+}
+
+impl UblkIOChain {
+    pub fn new() -> UblkIOChain {
+        Default::default()
+    }
+
+    fn add(mut self, sqe: squeue::Entry, flags: squeue::Flags) -> UblkIOChain {
+        if let Some(prev) = self.sqes.pop() {
+            self.sqes.push(prev.flags(flags));
+        }
+        self.sqes.push(sqe);
+        self
+    }
+
+    /// Append `sqe`, which is started after the previous SQE completes
+    /// successfully; otherwise it is canceled with -ECANCELED
+    pub fn link(self, sqe: squeue::Entry) -> UblkIOChain {
+        self.add(sqe, squeue::Flags::IO_LINK)
+    }
+
+    /// Append `sqe`, which is started after the previous SQE completes
+    /// even though the previous one fails
+    pub fn hardlink(self, sqe: squeue::Entry) -> UblkIOChain {
+        self.add(sqe, squeue::Flags::IO_HARDLINK)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sqes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sqes.is_empty()
+    }
+}
+
+/// Handle of one extra IO slot allocated from `UblkTgt.extra_ios`
+///
+/// Extra slot is in [depth, depth + extra_ios), and doesn't belong to any
+/// ublk tag, so it can be used for target's own IO, such as metadata or
+/// journal writes. Target IO tagged with `user_data()` is completed to
+/// the handler installed by `UblkQueue::set_extra_io_handler()`.
+pub struct UblkExtraIO {
+    tag: u16,
+}
+
+impl UblkExtraIO {
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    /// Build target IO userdata for this extra slot
+    #[inline(always)]
+    pub fn user_data(&self, op: u32, tgt_data: u32) -> u64 {
+        UblkIOCtx::build_user_data(self.tag, op, tgt_data, true)
+    }
+}
+
+/// Allocator of extra IO slots, and buffers attached to them
+struct UblkExtraIos {
+    base: u32,
+    free: Vec<u16>,
+    bufs: Vec<*mut u8>,
+}
+
+impl UblkExtraIos {
+    fn new(base: u32, nr: u16) -> Self {
+        UblkExtraIos {
+            base,
+            free: (0..nr).rev().map(|i| (base + i as u32) as u16).collect(),
+            bufs: vec![std::ptr::null_mut(); nr as usize],
+        }
+    }
+
+    fn alloc(&mut self, buf: *mut u8) -> Option<UblkExtraIO> {
+        let tag = self.free.pop()?;
+
+        self.bufs[(tag as u32 - self.base) as usize] = buf;
+        Some(UblkExtraIO { tag })
+    }
+
+    fn free(&mut self, io: UblkExtraIO) {
+        self.bufs[(io.tag as u32 - self.base) as usize] = std::ptr::null_mut();
+        self.free.push(io.tag);
+    }
+
+    #[inline(always)]
+    fn buf(&self, tag: u32) -> Option<*mut u8> {
+        if tag >= self.base {
+            self.bufs.get((tag - self.base) as usize).copied()
+        } else {
+            None
+        }
     }
 }
 
@@ -417,6 +1107,7 @@ impl UblkIO {
 /// mark it as Copy
 #[derive(Copy, Clone)]
 pub struct UblkQueueCtx {
+    pub dev_id: u32,
     pub depth: u16,
     pub q_id: u16,
 
@@ -441,189 +1132,1936 @@ impl UblkQueueCtx {
     }
 }
 
-const UBLK_QUEUE_STOPPING: u32 = 1_u32 << 0;
-const UBLK_QUEUE_IDLE: u32 = 1_u32 << 1;
-const UBLK_QUEUE_POLL: u32 = 1_u32 << 2;
+/// max times of submitting full SQ for flushing overflowed SQEs before
+/// waiting for completion
+const UBLK_SQ_FULL_MAX_SUBMITS: u32 = 4;
 
-/// UBLK queue abstraction
-///
-/// UblkQueue is the core part of the whole stack, which communicates with
-/// ublk driver via `io_uring cmd`. When any io command representing one
-/// block IO request originating from /dev/ublkbN comes, one uring_cmd CQE
-/// is received in ublk userspace side. Basically the whole stack is driven
-/// by io_uring CQE(uring_cmd or plain io_uring IO submitted from target
-/// code). Here target means the specific ublk device implementation, such
-/// as ublk-loop, ublk-zoned, ublk-nbd, ublk-qcow2, ...
-///
-/// So far, each queue is handled by one its own io_uring.
-///
-pub struct UblkQueue<'a> {
-    flags: u32,
-    q_id: u16,
-    q_depth: u32,
-    io_cmd_buf: u64,
-    //ops: Box<dyn UblkQueueImpl>,
-    pub dev: &'a UblkDev,
-    cmd_inflight: u32,
-    q_state: u32,
-    cqes_idx: usize,
-    cqes_cnt: usize,
-    ios: Vec<UblkIO>,
-    q_ring: IoUring<squeue::Entry>,
+// io_uring_enter() flags, which aren't exported by io-uring crate
+const IORING_ENTER_GETEVENTS: u32 = 1_u32 << 0;
+const IORING_ENTER_SQ_WAKEUP: u32 = 1_u32 << 1;
+
+/// io_uring instances of one ublk queue
+pub struct UblkQueueRing {
+    /// for both ublk io command and target IO
+    ring: IoUring<squeue::Entry>,
+
+    /// SQEs which can't be pushed because SQ is full, and they are pushed
+    /// in order before submitting
+    /// linked SQEs are kept in one group, so they are always pushed
+    /// and submitted together
+    overflow: VecDeque<Vec<squeue::Entry>>,
+    sq_full: u64,
+    sq_overflows: u64,
+
+    /// how many times io_uring_enter() is called for submitting SQEs and
+    /// waiting for CQEs, not including submissions consumed by SQPOLL
+    /// thread without syscall
+    enters: u64,
+
+    /// how many SQEs are queued via this ring, including overflowed ones
+    pushed: u64,
+
+    /// this ring owns the device's shared SQPOLL thread
+    sqpoll_owner: bool,
+
+    /// slot userdata bits and fixed file base of the queue which is using
+    /// this ring, only for `UblkEventLoop`
+    ud_slot: u64,
+    fd_base: u32,
+
+    /// userdata of in-flight SQEs stamped with slot and count of each, so
+    /// SQEs of removed queue can be canceled, only for `UblkEventLoop`
+    slot_sqes: HashMap<u64, u32>,
+
+    /// standalone IOPOLL ring for target IO
+    iopoll: Option<IoUring<squeue::Entry>>,
+    iopoll_inflight: usize,
+
+    /// the applied io_uring setup options
+    cfg: UblkRingConfig,
 }
 
-impl Drop for UblkQueue<'_> {
-    fn drop(&mut self) {
-        let dev = self.dev;
-        trace!("dev {} queue {} dropped", dev.dev_info.dev_id, self.q_id);
+impl UblkQueueRing {
+    /// Build io_uring with `cfg`, which is degraded in case of setup
+    /// failure if `UblkRingConfig::fallback` is set. The 1st ring with
+    /// shared SQPOLL is published via `sqpoll_ring`, and true is returned
+    /// for this ring.
+    fn build_ring(
+        cfg: &mut UblkRingConfig,
+        sq_depth: u32,
+        cq_depth: u32,
+        sqpoll_ring: Option<&Mutex<Option<OwnedFd>>>,
+    ) -> Result<(IoUring<squeue::Entry>, bool), UblkError> {
+        // queues are created concurrently, and only one of them can create
+        // the shared SQPOLL thread
+        let mut owner = sqpoll_ring.map(|m| m.lock().unwrap_or_else(|e| e.into_inner()));
+
+        loop {
+            let shared = cfg.sqpoll.is_some_and(|sq| sq.shared);
+            let wq_fd = match (cfg.attach_wq, owner.as_ref()) {
+                (Some(fd), _) => Some(fd),
+                (None, Some(o)) if shared => (**o).as_ref().map(|fd| fd.as_raw_fd()),
+                _ => None,
+            };
+
+            match cfg.builder(cq_depth, wq_fd).build(sq_depth) {
+                Ok(r) => {
+                    // the 1st shared SQPOLL ring owns the poll thread, and
+                    // its fd is duplicated, so the published fd number
+                    // can't be reused by others
+                    let owned = match owner.as_mut().filter(|_| shared && wq_fd.is_none()) {
+                        Some(o) => {
+                            let fd =
+                                unsafe { libc::fcntl(r.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+                            if fd >= 0 {
+                                **o = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+                            }
+                            fd >= 0
+                        }
+                        None => false,
+                    };
+                    return Ok((r, owned));
+                }
+                Err(e) => match cfg.degrade().filter(|_| cfg.fallback) {
+                    Some(c) => {
+                        warn!("setup ring {:?} failed {}, fallback to {:?}", cfg, e, c);
+                        *cfg = c;
+                    }
+                    None => return Err(UblkError::OtherIOError(e)),
+                },
+            }
+        }
+    }
 
-        if let Err(r) = self.q_ring.submitter().unregister_files() {
-            error!("unregister fixed files failed {}", r);
+    fn __new(ring: IoUring<squeue::Entry>, cfg: UblkRingConfig) -> UblkQueueRing {
+        UblkQueueRing {
+            ring,
+            overflow: VecDeque::new(),
+            sq_full: 0,
+            sq_overflows: 0,
+            enters: 0,
+            pushed: 0,
+            sqpoll_owner: false,
+            ud_slot: 0,
+            fd_base: 0,
+            slot_sqes: HashMap::new(),
+            iopoll: None,
+            iopoll_inflight: 0,
+            cfg,
         }
+    }
 
-        let depth = dev.dev_info.queue_depth as u32;
-        let cmd_buf_sz = UblkQueue::cmd_buf_sz(depth) as usize;
+    fn new(dev: &UblkDev) -> Result<UblkQueueRing, UblkError> {
+        let tgt = &dev.tgt;
+        let mut cfg = tgt.ring.clone();
+        let (ring, sqpoll_owner) = Self::build_ring(
+            &mut cfg,
+            tgt.sq_depth as u32,
+            tgt.cq_depth as u32,
+            Some(&dev.sqpoll_ring),
+        )?;
 
-        //unmap, otherwise our cdev won't be released
-        unsafe {
-            libc::munmap(self.io_cmd_buf as *mut libc::c_void, cmd_buf_sz);
+        let fds = &dev.tgt_fds()?;
+        ring.submitter()
+            .register_files(fds)
+            .map_err(UblkError::OtherIOError)?;
+
+        let iopoll = if cfg.iopoll {
+            let res = IoUring::<squeue::Entry, cqueue::Entry>::builder()
+                .setup_iopoll()
+                .setup_cqsize(tgt.cq_depth as u32)
+                .build(tgt.sq_depth as u32)
+                .and_then(|r| r.submitter().register_files(fds).map(|_| r));
+
+            match res {
+                Ok(r) => Some(r),
+                Err(e) if cfg.fallback => {
+                    warn!("dev {} setup iopoll ring failed {}", dev.dev_info.dev_id, e);
+                    cfg.iopoll = false;
+                    None
+                }
+                Err(e) => return Err(UblkError::OtherIOError(e)),
+            }
+        } else {
+            None
+        };
+
+        let mut q_ring = Self::__new(ring, cfg);
+        q_ring.iopoll = iopoll;
+        q_ring.sqpoll_owner = sqpoll_owner;
+        Ok(q_ring)
+    }
+
+    /// Ring shared by all queues of `UblkEventLoop`, and the fixed file
+    /// table is sparse, so each device can register its files in one range
+    fn new_shared(
+        mut cfg: UblkRingConfig,
+        sq_depth: u32,
+        cq_depth: u32,
+        nr_files: u32,
+    ) -> Result<UblkQueueRing, UblkError> {
+        // IOPOLL ring isn't supported in event loop
+        if cfg.iopoll {
+            if !cfg.fallback {
+                return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+            }
+            warn!("IOPOLL ring isn't supported in event loop");
+            cfg.iopoll = false;
         }
 
-        for i in 0..depth {
-            let io = &self.ios[i as usize];
-            super::ublk_dealloc_buf(
-                io.__buf_addr,
-                dev.dev_info.max_io_buf_bytes as usize,
-                unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize },
-            );
+        let (ring, _) = Self::build_ring(&mut cfg, sq_depth, cq_depth, None)?;
+        ring.submitter()
+            .register_files_sparse(nr_files)
+            .map_err(UblkError::OtherIOError)?;
+
+        Ok(Self::__new(ring, cfg))
+    }
+
+    /// Mark the queue which is going to use this ring
+    #[inline(always)]
+    fn set_owner(&mut self, ud_slot: u64, fd_base: u32) {
+        self.ud_slot = ud_slot;
+        self.fd_base = fd_base;
+    }
+
+    /// Return the applied io_uring setup options, which may be weaker than
+    /// `UblkTgt.ring` in case of `UblkRingConfig::fallback`
+    pub fn get_config(&self) -> &UblkRingConfig {
+        &self.cfg
+    }
+
+    /// Move overflowed SQEs to SQ in order, fails if SQ becomes full
+    fn flush_overflow(&mut self) -> Result<(), squeue::PushError> {
+        while let Some(sqes) = self.overflow.front() {
+            unsafe { self.ring.submission().push_multiple(sqes)? };
+            self.overflow.pop_front();
         }
+        Ok(())
     }
-}
 
-#[inline(always)]
-fn round_up(val: u32, rnd: u32) -> u32 {
-    (val + rnd - 1) & !(rnd - 1)
-}
+    /// SQ is full, so submit SQEs to kernel for making room
+    fn submit_sq_full(&mut self) -> Result<usize, UblkError> {
+        self.sq_full += 1;
+        self.ring.submit().map_err(UblkError::UringSubmissionError)
+    }
 
-impl UblkQueue<'_> {
+    /// Add slot of the owner queue into userdata, so `UblkEventLoop` can
+    /// find the queue for handling the CQE, and the SQE is tracked until
+    /// its last CQE is received
     #[inline(always)]
-    fn cmd_buf_sz(depth: u32) -> u32 {
-        let size = depth * core::mem::size_of::<sys::ublksrv_io_desc>() as u32;
-        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    fn stamp_slot(&mut self, sqe: squeue::Entry) -> squeue::Entry {
+        if self.ud_slot == 0 {
+            sqe
+        } else {
+            let data = sqe.get_user_data() | self.ud_slot;
 
-        round_up(size, page_sz)
+            *self.slot_sqes.entry(data).or_insert(0) += 1;
+            sqe.user_data(data)
+        }
     }
 
+    /// The last CQE of one SQE stamped with slot is received
     #[inline(always)]
-    pub fn make_queue_ctx(&self) -> UblkQueueCtx {
-        UblkQueueCtx {
-            buf_addr: self.io_cmd_buf,
-            depth: self.q_depth as u16,
-            q_id: self.q_id,
+    fn put_slot_sqe(&mut self, user_data: u64) {
+        if let Some(cnt) = self.slot_sqes.get_mut(&user_data) {
+            *cnt -= 1;
+            if *cnt == 0 {
+                self.slot_sqes.remove(&user_data);
+            }
         }
     }
 
-    /// New one ublk queue
+    /// If any SQE of queue `slot` is in-flight
+    fn slot_is_busy(&self, slot: u16) -> bool {
+        self.slot_sqes
+            .keys()
+            .any(|data| user_data_to_slot(*data) == slot)
+    }
+
+    /// Cancel all in-flight SQEs of queue `slot`, and return false if
+    /// there isn't any
+    fn cancel_slot(&mut self, slot: u16) -> bool {
+        // overflowed SQEs aren't submitted yet, so simply discard them
+        let (dropped, kept): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.overflow)
+            .into_iter()
+            .partition(|sqes| user_data_to_slot(sqes[0].get_user_data()) == slot);
+        self.overflow = kept;
+        for sqe in dropped.iter().flatten() {
+            self.put_slot_sqe(sqe.get_user_data());
+        }
+
+        let sqes: Vec<u64> = self
+            .slot_sqes
+            .keys()
+            .filter(|data| user_data_to_slot(**data) == slot)
+            .copied()
+            .collect();
+
+        // CQE of cancel request has no slot, and it is ignored
+        self.set_owner(0, 0);
+        for data in &sqes {
+            let sqe = opcode::AsyncCancel2::new(types::CancelBuilder::user_data(*data).all())
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+            unsafe { self.push_async(sqe) };
+        }
+        !sqes.is_empty()
+    }
+
+    /// Same with `UblkIOCtx::try_push()`, used out of IO closure, such as
+    /// timer callback
     ///
-    /// # Arguments:
+    /// # Safety
     ///
-    /// * `q_id`: queue id, [0, nr_queues)
-    /// * `dev`: ublk device reference
+    /// Same with `io_uring::SubmissionQueue::push()`
+    pub unsafe fn try_push(&mut self, sqe: &squeue::Entry) -> Result<(), UblkError> {
+        if self.ud_slot == 0 {
+            self.__try_push(std::slice::from_ref(sqe))
+        } else {
+            let sqe = self.stamp_slot(sqe.clone());
+            let res = self.__try_push(std::slice::from_ref(&sqe));
+
+            if res.is_err() {
+                self.put_slot_sqe(sqe.get_user_data());
+            }
+            res
+        }
+    }
+
+    /// Push `sqes` after all overflowed SQEs, and submit SQ once if it is
+    /// full. `sqes` are pushed all or nothing.
+    unsafe fn __try_push(&mut self, sqes: &[squeue::Entry]) -> Result<(), UblkError> {
+        let mut retry = true;
+
+        loop {
+            match self
+                .flush_overflow()
+                .and_then(|_| self.ring.submission().push_multiple(sqes))
+            {
+                Ok(_) => {
+                    self.pushed += sqes.len() as u64;
+                    return Ok(());
+                }
+                Err(e) if !retry => return Err(UblkError::UringPushError(e)),
+                Err(_) => {
+                    self.submit_sq_full()?;
+                    retry = false;
+                }
+            }
+        }
+    }
+
+    /// Same with `UblkIOCtx::push_async()`, used out of IO closure, such as
+    /// timer callback, and `sqe` is queued in the overflow list if SQ is
+    /// full
     ///
-    ///ublk queue is handling IO from driver, so far we use dedicated
-    ///io_uring for handling both IO command and IO
-    #[allow(clippy::uninit_vec)]
-    pub fn new(q_id: u16, dev: &UblkDev) -> Result<UblkQueue, UblkError> {
-        let tgt = &dev.tgt;
-        let sq_depth = tgt.sq_depth;
-        let cq_depth = tgt.cq_depth;
+    /// # Safety
+    ///
+    /// Same with `io_uring::SubmissionQueue::push()`
+    pub unsafe fn push_async(&mut self, sqe: squeue::Entry) {
+        let sqe = self.stamp_slot(sqe);
 
-        let ring = IoUring::<squeue::Entry, cqueue::Entry>::builder()
-            .setup_cqsize(cq_depth as u32)
-            .setup_coop_taskrun()
-            .build(sq_depth as u32)
-            .map_err(UblkError::OtherIOError)?;
+        if self.__try_push(std::slice::from_ref(&sqe)).is_err() {
+            self.sq_overflows += 1;
+            self.pushed += 1;
+            self.overflow.push_back(vec![sqe]);
+        }
+    }
 
-        //todo: apply io_uring flags from tgt.ring_flags
+    /// Push linked SQEs, which are kept in overflow list as one group if
+    /// SQ is full
+    unsafe fn push_chain(&mut self, sqes: Vec<squeue::Entry>) -> Result<(), UblkError> {
+        // the whole chain has to be submitted in one batch
+        if sqes.len() > self.ring.submission().capacity() {
+            return Err(UblkError::OtherError(-libc::E2BIG));
+        }
 
-        let depth = dev.dev_info.queue_depth as u32;
-        let cdev_fd = dev.cdev_file.as_raw_fd();
-        let cmd_buf_sz = UblkQueue::cmd_buf_sz(depth) as usize;
+        let sqes: Vec<squeue::Entry> = sqes.into_iter().map(|e| self.stamp_slot(e)).collect();
+        if self.__try_push(&sqes).is_err() {
+            self.sq_overflows += sqes.len() as u64;
+            self.pushed += sqes.len() as u64;
+            self.overflow.push_back(sqes);
+        }
+        Ok(())
+    }
 
-        ring.submitter()
-            .register_files(&tgt.fds[0..tgt.nr_fds as usize])
-            .map_err(UblkError::OtherIOError)?;
+    /// Submit all SQEs including overflowed ones, and wait for `to_wait`
+    /// completions
+    fn submit_and_wait(&mut self, to_wait: usize) -> Result<usize, UblkError> {
+        let mut submitted = 0;
 
-        let off = sys::UBLKSRV_CMD_BUF_OFFSET as i64
-            + q_id as i64
-                * ((sys::UBLK_MAX_QUEUE_DEPTH as usize
-                    * core::mem::size_of::<sys::ublksrv_io_desc>()) as i64);
-        let io_cmd_buf = unsafe {
-            libc::mmap(
-                std::ptr::null_mut::<libc::c_void>(),
-                cmd_buf_sz,
-                libc::PROT_READ,
-                libc::MAP_SHARED | libc::MAP_POPULATE,
-                cdev_fd,
-                off as i64,
+        // SQ may not be drained by submission, such as SQEs consumed by
+        // SQPOLL thread asynchronously, so don't spin here, and overflowed
+        // SQEs left are flushed in next round after waiting for CQEs
+        for _ in 0..UBLK_SQ_FULL_MAX_SUBMITS {
+            if self.flush_overflow().is_ok() {
+                break;
+            }
+            submitted += self.submit_sq_full()?;
+        }
+
+        let (len, need_wakeup, getevents) = {
+            let sq = self.ring.submission();
+
+            // task work is only run in io_uring_enter(GETEVENTS) for
+            // DEFER_TASKRUN, and is flagged by IORING_SQ_TASKRUN otherwise
+            (
+                sq.len(),
+                sq.need_wakeup(),
+                to_wait > 0 || sq.cq_overflow() || sq.taskrun() || self.cfg.defer_taskrun,
             )
         };
-        if io_cmd_buf == libc::MAP_FAILED {
-            return Err(UblkError::MmapError(
-                "io cmd buffer mmap failed".to_string(),
-            ));
+        let mut flags = if getevents { IORING_ENTER_GETEVENTS } else { 0 };
+
+        // nothing to submit or wait, so save one syscall; SQEs are
+        // consumed by the SQPOLL thread if it isn't sleeping
+        if self.cfg.sqpoll.is_some() {
+            if need_wakeup {
+                flags |= IORING_ENTER_SQ_WAKEUP;
+            } else if !getevents {
+                return Ok(submitted + len);
+            }
+        } else if len == 0 && !getevents {
+            return Ok(submitted);
         }
 
-        let nr_ios = depth + tgt.extra_ios as u32;
-        let mut ios = Vec::<UblkIO>::with_capacity(nr_ios as usize);
-        unsafe {
-            ios.set_len(nr_ios as usize);
+        self.enters += 1;
+        Ok(submitted
+            + unsafe {
+                self.ring.submitter().enter::<libc::sigset_t>(
+                    len as u32,
+                    to_wait as u32,
+                    flags,
+                    None,
+                )
+            }
+            .map_err(UblkError::UringSubmissionError)?)
+    }
+
+    /// Submit queued IOPOLL target IOs, and return if there is any IOPOLL
+    /// IO in-flight
+    fn submit_iopoll(&mut self) -> Result<bool, UblkError> {
+        if let Some(r) = self.iopoll.as_mut() {
+            if self.iopoll_inflight > 0 || !r.submission().is_empty() {
+                // IOPOLL ring reaps completion by GETEVENTS in io_uring_enter()
+                self.iopoll_inflight += r
+                    .submit_and_wait(0)
+                    .map_err(UblkError::UringSubmissionError)?;
+            }
         }
+        Ok(self.iopoll_inflight > 0)
+    }
+}
 
-        for i in 0..nr_ios {
-            let io = &mut ios[i as usize];
+const UBLK_QUEUE_STOPPING: u32 = 1_u32 << 0;
+const UBLK_QUEUE_IDLE: u32 = 1_u32 << 1;
+const UBLK_QUEUE_POLL: u32 = 1_u32 << 2;
+const UBLK_QUEUE_ABORTED: u32 = 1_u32 << 3;
+const UBLK_QUEUE_EXIT: u32 = 1_u32 << 4;
 
-            // extra io slot needn't to allocate buffer
-            if i < depth {
-                if (dev.dev_info.flags & (super::sys::UBLK_F_USER_COPY as u64)) == 0 {
-                    io.__buf_addr =
-                        super::ublk_alloc_buf(dev.dev_info.max_io_buf_bytes as usize, unsafe {
-                            libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap()
-                        });
-                } else {
-                    io.__buf_addr = std::ptr::null_mut();
+/// How to handle error returned from IO closure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UblkErrorPolicy {
+    /// Complete the IO command with the error's negative errno
+    #[default]
+    FailIo,
+
+    /// Call IO closure again with same CQE at most the specified times,
+    /// and fail the IO command if it still returns error
+    ///
+    /// The closure is only retried if it neither completes the IO nor
+    /// queues any SQE via `UblkIOCtx` before returning error, otherwise
+    /// target IO could be submitted twice. SQEs pushed to `get_ring()`
+    /// directly can't be seen, so target using it shouldn't use this
+    /// policy.
+    Retry(u32),
+
+    /// Fail the IO command, then abort the queue: `process_io()` returns
+    /// `UblkError::QueueIsDown` after submitting the failed IO
+    AbortQueue,
+}
+
+/// How to handle panic from IO closure
+///
+/// The affected IO command is always failed with -EIO.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UblkPanicPolicy {
+    /// Fail the affected IO command only
+    #[default]
+    FailIo,
+
+    /// Fail all IO commands owned by target too, since IO closure state
+    /// may be broken by the panic. Target IO still in-flight may touch
+    /// the IO buffer after the tag is re-used, so only use it when target
+    /// IO is handled synchronously.
+    FailInflight,
+
+    /// Fail all IO commands owned by target, then abort the queue, which
+    /// is the precondition of recovering the queue
+    AbortQueue,
+}
+
+/// Event reported from ublk queue
+#[derive(Debug, Clone)]
+pub enum UblkQueueEvent {
+    /// IO closure panicked when handling `tag`
+    Panic { q_id: u16, tag: u32, msg: String },
+
+    /// queue pthread exits, `panicked` is true if the queue is aborted by
+    /// panic, or the pthread panicked
+    Exit { q_id: u16, panicked: bool },
+
+    /// signal `signo` is read from `UblkSignalFd` by queue `q_id`, which
+    /// may belong to another device in this process
+    Signal { q_id: u16, signo: i32 },
+
+    /// handover is requested from the control socket, and the result is
+    /// sent to `reply` after all queues are stopped, see `upgrade`
+    Handover {
+        reply: mpsc::Sender<Result<super::upgrade::UblkHandover, UblkError>>,
+    },
+}
+
+/// signalfd shared by all devices in this process
+///
+/// Process-directed signal is queued only once, and it is read by the
+/// first queue polling this signalfd, so each signal is broadcast to all
+/// subscribed event senders as `UblkQueueEvent::Signal`.
+pub struct UblkSignalFd {
+    fd: OwnedFd,
+    mask: libc::sigset_t,
+    subs: Mutex<BTreeMap<u64, mpsc::Sender<UblkQueueEvent>>>,
+}
+
+impl UblkSignalFd {
+    /// Block `signals` in the calling pthread, and create signalfd for
+    /// reading them
+    pub fn new(signals: &[i32]) -> Result<UblkSignalFd, UblkError> {
+        let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+
+        unsafe {
+            libc::sigemptyset(&mut mask);
+            for &sig in signals {
+                libc::sigaddset(&mut mask, sig);
+            }
+        }
+        let sfd = UblkSignalFd {
+            fd: unsafe {
+                let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+                if fd < 0 {
+                    return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
                 }
-                io.flags = UBLK_IO_NEED_FETCH_RQ | UBLK_IO_FREE;
-            } else {
-                io.__buf_addr = std::ptr::null_mut();
-                io.flags = 0;
+                OwnedFd::from_raw_fd(fd)
+            },
+            mask,
+            subs: Mutex::new(BTreeMap::new()),
+        };
+        sfd.block()?;
+        Ok(sfd)
+    }
+
+    /// Block signals of this signalfd in the calling pthread
+    ///
+    /// Signals have to be blocked in all pthreads of this process, otherwise
+    /// they may be delivered to one pthread instead of this signalfd, so
+    /// this should be called before creating other pthreads, which
+    /// inherit the signal mask.
+    pub fn block(&self) -> Result<(), UblkError> {
+        let res =
+            unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &self.mask, std::ptr::null_mut()) };
+        if res != 0 {
+            return Err(UblkError::OtherError(-res));
+        }
+        Ok(())
+    }
+
+    /// Report signals read from this signalfd to `tx`, until the returned
+    /// subscription is dropped
+    pub fn subscribe(self: &Arc<Self>, tx: mpsc::Sender<UblkQueueEvent>) -> UblkSignalSub {
+        let mut subs = self.subs.lock().unwrap_or_else(|e| e.into_inner());
+        let id = subs.keys().next_back().map_or(0, |k| k + 1);
+
+        subs.insert(id, tx);
+        UblkSignalSub {
+            sfd: self.clone(),
+            id,
+        }
+    }
+
+    /// Read all pending signals, and broadcast them to subscribers
+    fn dispatch(&self, q_id: u16) {
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+            let len = core::mem::size_of::<libc::signalfd_siginfo>();
+            let ret = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    std::ptr::addr_of_mut!(info) as *mut libc::c_void,
+                    len,
+                )
+            };
+            if ret != len as isize {
+                break;
+            }
+            trace!("q{}: signal {} received", q_id, info.ssi_signo);
+            for tx in self.subs.lock().unwrap_or_else(|e| e.into_inner()).values() {
+                let _ = tx.send(UblkQueueEvent::Signal {
+                    q_id,
+                    signo: info.ssi_signo as i32,
+                });
             }
-            io.result = -1;
-            io.buf_addr = io.__buf_addr as u64;
         }
+    }
+}
 
-        let mut q = UblkQueue {
-            flags: dev.flags,
-            q_id,
-            q_depth: depth,
-            io_cmd_buf: io_cmd_buf as u64,
-            dev,
-            cmd_inflight: 0,
-            q_state: 0,
-            q_ring: ring,
-            ios,
-            cqes_idx: 0,
-            cqes_cnt: 0,
-        };
-        q.submit_fetch_commands();
+/// Subscription of `UblkSignalFd`, which is cancelled when it is dropped
+pub struct UblkSignalSub {
+    sfd: Arc<UblkSignalFd>,
+    id: u64,
+}
 
-        trace!("dev {} queue {} started", dev.dev_info.dev_id, q_id);
+impl UblkSignalSub {
+    pub fn signal_fd(&self) -> Arc<UblkSignalFd> {
+        self.sfd.clone()
+    }
+}
 
-        Ok(q)
+impl Drop for UblkSignalSub {
+    fn drop(&mut self) {
+        self.sfd
+            .subs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
     }
+}
 
-    fn support_comp_batch(&self) -> bool {
-        self.flags & super::UBLK_DEV_F_COMP_BATCH != 0
+/// Per-queue statistics
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UblkQueueStats {
+    /// how many times IO closure returns error
+    pub handler_errors: u64,
+
+    /// how many IO commands are failed by libublk because of handler error
+    pub failed_ios: u64,
+
+    /// how many times IO closure is called again because of error
+    pub retries: u64,
+
+    /// how many times IO closure panics
+    pub panics: u64,
+
+    /// how many times SQ is found full, then SQEs are submitted for
+    /// making room
+    pub sq_full: u64,
+
+    /// how many SQEs are queued in overflow list because SQ is full
+    pub sq_overflows: u64,
+
+    /// how many times io_uring_enter() is called for submitting and
+    /// waiting; submission consumed by SQPOLL thread without syscall isn't
+    /// counted
+    pub ring_enters: u64,
+
+    /// nanoseconds spent in busy polling, only accounted if busy polling
+    /// is enabled by `UblkQueue::set_busy_poll()`
+    pub poll_spin_ns: u64,
+
+    /// nanoseconds spent in blocking wait for completion, only accounted
+    /// if busy polling is enabled
+    pub poll_sleep_ns: u64,
+
+    /// how many target IOs are timed out, see `UblkIOCtx::set_deadline()`
+    pub io_timeouts: u64,
+
+    /// how many times the queue enters idle state, see
+    /// `UblkQueue::set_idle_timeout()`
+    pub idle_enters: u64,
+
+    /// how many completions from batch list or `UblkCompleter` are
+    /// dropped because the tag is invalid or isn't owned by target, such
+    /// as duplicated ones
+    pub dropped_comps: u64,
+
+    /// IOs and bytes of each `UBLK_IO_OP_*`, indexed by the op
+    pub op_ios: [u64; UBLK_STATS_NR_OPS],
+    pub op_bytes: [u64; UBLK_STATS_NR_OPS],
+
+    /// IOs completed with error, keyed by errno
+    pub errors: BTreeMap<i32, u64>,
+
+    /// IOs owned by target now, and the high-water mark
+    pub inflight: u32,
+    pub inflight_max: u32,
+
+    /// CQEs of target IO
+    pub tgt_ios: u64,
+
+    /// histogram of fetch-to-commit latency: bucket 0 counts IOs done in
+    /// less than 1us, and bucket i counts IOs done in [2^(i-1), 2^i) us;
+    /// the last bucket includes all slower IOs
+    pub lat_hist: [u64; UBLK_STATS_NR_LAT_BUCKETS],
+}
+
+/// Size of per-op counters in `UblkQueueStats`
+pub const UBLK_STATS_NR_OPS: usize = 32;
+
+/// Number of latency histogram buckets in `UblkQueueStats`
+pub const UBLK_STATS_NR_LAT_BUCKETS: usize = 32;
+
+impl UblkQueueStats {
+    /// Add `other` into this one for aggregating stats of queues;
+    /// `inflight_max` is the max of all queues
+    pub fn merge(&mut self, other: &UblkQueueStats) {
+        self.handler_errors += other.handler_errors;
+        self.failed_ios += other.failed_ios;
+        self.retries += other.retries;
+        self.panics += other.panics;
+        self.sq_full += other.sq_full;
+        self.sq_overflows += other.sq_overflows;
+        self.ring_enters += other.ring_enters;
+        self.poll_spin_ns += other.poll_spin_ns;
+        self.poll_sleep_ns += other.poll_sleep_ns;
+        self.io_timeouts += other.io_timeouts;
+        self.idle_enters += other.idle_enters;
+        self.dropped_comps += other.dropped_comps;
+        for i in 0..UBLK_STATS_NR_OPS {
+            self.op_ios[i] += other.op_ios[i];
+            self.op_bytes[i] += other.op_bytes[i];
+        }
+        for (errno, cnt) in &other.errors {
+            *self.errors.entry(*errno).or_insert(0) += cnt;
+        }
+        self.inflight += other.inflight;
+        self.inflight_max = self.inflight_max.max(other.inflight_max);
+        self.tgt_ios += other.tgt_ios;
+        for i in 0..UBLK_STATS_NR_LAT_BUCKETS {
+            self.lat_hist[i] += other.lat_hist[i];
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct UblkDevStatsState {
+    queues: Vec<UblkQueueStats>,
+
+    /// eventfd of each live queue for requesting stats snapshot, and the
+    /// last request answered by the queue
+    reqs: Vec<Option<(Arc<OwnedFd>, u64)>>,
+
+    /// sequence of the last snapshot request
+    req_seq: u64,
+}
+
+/// Statistics of all queues of one device
+///
+/// Queues publish their stats after `UblkQueue::set_stats_publisher()` is
+/// called, when `refresh()` asks for one snapshot, or periodically if
+/// interval is set, and the final stats is published when the queue is
+/// dropped, so stats can be read from any context.
+#[derive(Debug, Default)]
+pub struct UblkDevStats {
+    state: Mutex<UblkDevStatsState>,
+    published: Condvar,
+}
+
+impl UblkDevStats {
+    pub fn new() -> UblkDevStats {
+        Default::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, UblkDevStatsState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Publish stats of queue `q_id`, which answers request `seq`
+    fn publish(&self, q_id: u16, stats: UblkQueueStats, seq: u64) {
+        let mut state = self.lock();
+        let idx = q_id as usize;
+
+        if state.queues.len() <= idx {
+            state.queues.resize(idx + 1, Default::default());
+        }
+        state.queues[idx] = stats;
+        if let Some(Some((_, done))) = state.reqs.get_mut(idx) {
+            *done = seq;
+        }
+        self.published.notify_all();
+    }
+
+    /// Return sequence of the last snapshot request
+    fn req_seq(&self) -> u64 {
+        self.lock().req_seq
+    }
+
+    /// Queue `q_id` answers snapshot request written to `efd`, until it
+    /// is removed
+    fn add_queue(&self, q_id: u16, efd: Arc<OwnedFd>) {
+        let mut state = self.lock();
+        let idx = q_id as usize;
+        let seq = state.req_seq;
+
+        if state.reqs.len() <= idx {
+            state.reqs.resize(idx + 1, None);
+        }
+        state.reqs[idx] = Some((efd, seq));
+    }
+
+    fn remove_queue(&self, q_id: u16) {
+        if let Some(req) = self.lock().reqs.get_mut(q_id as usize) {
+            *req = None;
+        }
+        self.published.notify_all();
+    }
+
+    /// Ask all live queues to publish their stats, and wait until they
+    /// are published or `timeout` expires
+    ///
+    /// Queue answers the request between handling IOs, so it shouldn't be
+    /// called from queue pthread of this device, otherwise it waits until
+    /// `timeout`.
+    pub fn refresh(&self, timeout: Duration) {
+        let mut state = self.lock();
+
+        state.req_seq += 1;
+        let seq = state.req_seq;
+        for (efd, _) in state.reqs.iter().flatten() {
+            let val = 1_u64;
+
+            unsafe {
+                libc::write(
+                    efd.as_raw_fd(),
+                    std::ptr::addr_of!(val) as *const libc::c_void,
+                    core::mem::size_of::<u64>(),
+                );
+            }
+        }
+
+        let _ = self.published.wait_timeout_while(state, timeout, |s| {
+            s.reqs.iter().flatten().any(|(_, done)| *done < seq)
+        });
+    }
+
+    /// Return the last published stats of each queue, indexed by queue id
+    pub fn get_queues_stats(&self) -> Vec<UblkQueueStats> {
+        self.lock().queues.clone()
+    }
+
+    /// Return stats aggregated from all queues
+    pub fn get_stats(&self) -> UblkQueueStats {
+        let mut stats = UblkQueueStats::default();
+
+        for q in self.lock().queues.iter() {
+            stats.merge(q);
+        }
+        stats
+    }
+}
+
+/// Callback of queue timer, which can submit IO via the queue ring
+pub type UblkTimerFn = Box<dyn FnMut(&UblkQueueCtx, &mut UblkQueueRing)>;
+
+type UblkIdleFn = Box<dyn FnMut(&UblkQueueCtx)>;
+
+struct UblkTimer {
+    // has to be stable until the timeout SQE is submitted
+    ts: types::Timespec,
+    periodic: bool,
+    cancelled: bool,
+    cb: UblkTimerFn,
+}
+
+/// UBLK queue abstraction
+///
+/// UblkQueue is the core part of the whole stack, which communicates with
+/// ublk driver via `io_uring cmd`. When any io command representing one
+/// block IO request originating from /dev/ublkbN comes, one uring_cmd CQE
+/// is received in ublk userspace side. Basically the whole stack is driven
+/// by io_uring CQE(uring_cmd or plain io_uring IO submitted from target
+/// code). Here target means the specific ublk device implementation, such
+/// as ublk-loop, ublk-zoned, ublk-nbd, ublk-qcow2, ...
+///
+/// So far, each queue is handled by one its own io_uring.
+///
+pub struct UblkQueue<'a, T = ()> {
+    flags: u32,
+    q_id: u16,
+    q_depth: u32,
+    io_cmd_buf: u64,
+    //ops: Box<dyn UblkQueueImpl>,
+    pub dev: &'a UblkDev,
+    cmd_inflight: u32,
+    /// IO commands received after the queue is stopped, see
+    /// `UblkQueue::stop_handle()`
+    nr_parked: u32,
+    q_state: u32,
+    /// buffer of CQEs reaped in one batch
+    cqes: Vec<cqueue::Entry>,
+    ios: Vec<UblkIO<T>>,
+    q_ring: Rc<RefCell<UblkQueueRing>>,
+
+    /// extra IO slots, and handler of their target IO
+    extra_ios: UblkExtraIos,
+    extra_handler: Option<UblkExtraIOHandler<T>>,
+
+    /// timers indexed by timer id, and the slot is freed after the last
+    /// timeout CQE is received
+    timers: Vec<Option<Box<UblkTimer>>>,
+
+    /// idle detection: the queue is idle if there isn't any IO in
+    /// `idle_timeout`, and io buffers are released
+    idle_timeout: Option<Duration>,
+    idle_ts: Box<types::Timespec>,
+    idle_seq: u16,
+    last_io: Instant,
+    idle_handler: Option<UblkIdleFn>,
+
+    /// parent span of all IO spans of this queue
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+
+    /// stats are published to `stats_sink` every `stats_interval`
+    stats_sink: Option<Arc<UblkDevStats>>,
+    stats_interval: Duration,
+    stats_ts: Box<types::Timespec>,
+
+    /// eventfd for `UblkDevStats::refresh()` asking for stats snapshot
+    stats_req: Option<Arc<OwnedFd>>,
+
+    /// slot userdata bits and fixed file base if this queue is served by
+    /// `UblkEventLoop`, both are zero for standalone queue
+    shared_ring: bool,
+    ud_slot: u64,
+    fd_base: u32,
+    comp_chan: Option<Arc<UblkCompChan>>,
+    stop_chan: Option<Arc<UblkStopChan>>,
+    signal_fd: Option<Arc<UblkSignalFd>>,
+    err_policy: UblkErrorPolicy,
+    panic_policy: UblkPanicPolicy,
+    event_tx: Option<mpsc::Sender<UblkQueueEvent>>,
+    stats: UblkQueueStats,
+
+    /// busy polling window after each completion
+    busy_poll: Option<Duration>,
+    last_comp: Instant,
+    spin_start: Option<Instant>,
+}
+
+type UblkExtraIOHandler<T> = Box<dyn FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>>;
+
+impl<T> Drop for UblkQueue<'_, T> {
+    fn drop(&mut self) {
+        let dev = self.dev;
+        trace!("dev {} queue {} dropped", dev.dev_info.dev_id, self.q_id);
+
+        if let Some(sink) = self.stats_sink.take() {
+            let q_ring = self.q_ring.borrow();
+
+            sink.publish(
+                self.q_id,
+                UblkQueueStats {
+                    sq_full: q_ring.sq_full,
+                    sq_overflows: q_ring.sq_overflows,
+                    ring_enters: q_ring.enters,
+                    ..self.stats.clone()
+                },
+                sink.req_seq(),
+            );
+            sink.remove_queue(self.q_id);
+        }
+
+        // SQEs of queue in the shared ring are canceled and completed by
+        // `UblkEventLoop` before dropping the queue, and the ring is left
+        // to the loop
+        if !self.shared_ring {
+            let q_ring = self.q_ring.borrow();
+
+            if let Err(r) = q_ring.ring.submitter().unregister_files() {
+                error!("unregister fixed files failed {}", r);
+            }
+            // queues created later can't attach to the owner ring any more
+            if q_ring.sqpoll_owner {
+                *dev.sqpoll_ring.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            }
+        }
+
+        let depth = dev.dev_info.queue_depth as u32;
+        let cmd_buf_sz = cmd_buf_sz(depth) as usize;
+
+        //unmap, otherwise our cdev won't be released
+        unsafe {
+            libc::munmap(self.io_cmd_buf as *mut libc::c_void, cmd_buf_sz);
+        }
+
+        for i in 0..depth {
+            let io = &self.ios[i as usize];
+            super::ublk_dealloc_buf(
+                io.__buf_addr,
+                dev.dev_info.max_io_buf_bytes as usize,
+                unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize },
+            );
+        }
+    }
+}
+
+/// Retrieve message from panic payload
+pub(crate) fn panic_msg(p: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = p.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = p.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Prefer to allocate pages of `buf` from NUMA `node`, and pages are
+/// allocated when they are touched for the 1st time
+fn bind_buf_to_node(buf: *mut u8, len: usize, node: u32) {
+    let mut mask = [0_u64; 16];
+
+    if buf.is_null() || node >= 1024 {
+        return;
+    }
+    mask[(node / 64) as usize] |= 1_u64 << (node % 64);
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            buf,
+            len,
+            libc::MPOL_PREFERRED,
+            mask.as_ptr(),
+            1024_u64,
+            0_u32,
+        )
+    };
+    if res < 0 {
+        trace!("bind buffer to numa node {} failed", node);
+    }
+}
+
+#[inline(always)]
+fn round_up(val: u32, rnd: u32) -> u32 {
+    (val + rnd - 1) & !(rnd - 1)
+}
+
+#[inline(always)]
+fn cmd_buf_sz(depth: u32) -> u32 {
+    let size = depth * core::mem::size_of::<sys::ublksrv_io_desc>() as u32;
+    let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+
+    round_up(size, page_sz)
+}
+
+impl UblkQueue<'_> {
+    /// New one ublk queue
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id, [0, nr_queues)
+    /// * `dev`: ublk device reference
+    ///
+    ///ublk queue is handling IO from driver, so far we use dedicated
+    ///io_uring for handling both IO command and IO
+    pub fn new(q_id: u16, dev: &UblkDev) -> Result<UblkQueue, UblkError> {
+        UblkQueue::with_state(q_id, dev)
+    }
+}
+
+impl<T: Default> UblkQueue<'_, T> {
+    #[inline(always)]
+    pub fn make_queue_ctx(&self) -> UblkQueueCtx {
+        UblkQueueCtx {
+            dev_id: self.dev.dev_info.dev_id,
+            buf_addr: self.io_cmd_buf,
+            depth: self.q_depth as u16,
+            q_id: self.q_id,
+        }
+    }
+
+    /// New one ublk queue with per-IO target state of type `T`
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id, [0, nr_queues)
+    /// * `dev`: ublk device reference
+    ///
+    /// Each IO slot owns one `T`, which is reachable via
+    /// `UblkIOCtx::state_mut()` in IO closure, so target needn't to
+    /// maintain its own table indexed by tag. The state is reset to
+    /// `T::default()` when the tag is re-fetched from ublk driver.
+    pub fn with_state(q_id: u16, dev: &UblkDev) -> Result<UblkQueue<'_, T>, UblkError> {
+        let ring = Rc::new(RefCell::new(UblkQueueRing::new(dev)?));
+        let mut q = Self::__new(q_id, dev, ring, false)?;
+
+        q.submit_fetch_commands();
+        trace!("dev {} queue {} started", dev.dev_info.dev_id, q_id);
+
+        Ok(q)
+    }
+
+    fn __new(
+        q_id: u16,
+        dev: &UblkDev,
+        ring: Rc<RefCell<UblkQueueRing>>,
+        shared_ring: bool,
+    ) -> Result<UblkQueue<'_, T>, UblkError> {
+        let tgt = &dev.tgt;
+        let depth = dev.dev_info.queue_depth as u32;
+        let cdev_fd = dev.cdev_fd()?;
+        let cmd_buf_sz = cmd_buf_sz(depth) as usize;
+
+        let off = sys::UBLKSRV_CMD_BUF_OFFSET as i64
+            + q_id as i64
+                * ((sys::UBLK_MAX_QUEUE_DEPTH as usize
+                    * core::mem::size_of::<sys::ublksrv_io_desc>()) as i64);
+        let io_cmd_buf = unsafe {
+            libc::mmap(
+                std::ptr::null_mut::<libc::c_void>(),
+                cmd_buf_sz,
+                libc::PROT_READ,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                cdev_fd,
+                off as i64,
+            )
+        };
+        if io_cmd_buf == libc::MAP_FAILED {
+            return Err(UblkError::MmapError(
+                "io cmd buffer mmap failed".to_string(),
+            ));
+        }
+
+        let nr_ios = depth + tgt.extra_ios as u32;
+        let mut ios = Vec::<UblkIO<T>>::with_capacity(nr_ios as usize);
+
+        // queue pthread affinity has been setup, so allocate io buffers
+        // on its NUMA node
+        let numa_node = UblkQueueAffinity::from_current_thread()
+            .ok()
+            .and_then(|a| a.numa_node());
+
+        for i in 0..nr_ios {
+            // extra io slot needn't to allocate buffer
+            let (buf, flags) = if i < depth {
+                let buf = if (dev.dev_info.flags & (super::sys::UBLK_F_USER_COPY as u64)) == 0 {
+                    let buf =
+                        super::ublk_alloc_buf(dev.dev_info.max_io_buf_bytes as usize, unsafe {
+                            libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap()
+                        });
+                    if let Some(node) = numa_node {
+                        bind_buf_to_node(buf, dev.dev_info.max_io_buf_bytes as usize, node);
+                    }
+                    buf
+                } else {
+                    std::ptr::null_mut()
+                };
+                (buf, UBLK_IO_NEED_FETCH_RQ | UBLK_IO_FREE)
+            } else {
+                (std::ptr::null_mut(), 0)
+            };
+
+            ios.push(UblkIO {
+                __buf_addr: buf,
+                buf_addr: buf as u64,
+                flags,
+                result: -1,
+                chain_pending: 0,
+                chain_res: 0,
+                deadline: 0,
+                dl_seq: 0,
+                dl_expired: false,
+                dl_ts: types::Timespec::new(),
+                start: Instant::now(),
+                #[cfg(feature = "tracing")]
+                span: tracing::Span::none(),
+                state: T::default(),
+            });
+        }
+
+        Ok(UblkQueue {
+            flags: dev.flags,
+            q_id,
+            q_depth: depth,
+            io_cmd_buf: io_cmd_buf as u64,
+            dev,
+            cmd_inflight: 0,
+            nr_parked: 0,
+            q_state: 0,
+            q_ring: ring,
+            shared_ring,
+            ud_slot: 0,
+            fd_base: 0,
+            ios,
+            extra_ios: UblkExtraIos::new(depth, tgt.extra_ios),
+            extra_handler: None,
+            timers: Vec::new(),
+            idle_timeout: None,
+            idle_ts: Box::new(types::Timespec::new()),
+            idle_seq: 0,
+            last_io: Instant::now(),
+            idle_handler: None,
+            stats_sink: None,
+            stats_interval: Duration::ZERO,
+            stats_ts: Box::new(types::Timespec::new()),
+            stats_req: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("ublk_queue", dev_id = dev.dev_info.dev_id, q_id),
+            cqes: Vec::with_capacity(depth as usize),
+            comp_chan: None,
+            stop_chan: None,
+            signal_fd: None,
+            err_policy: UblkErrorPolicy::default(),
+            panic_policy: UblkPanicPolicy::default(),
+            event_tx: None,
+            stats: UblkQueueStats::default(),
+            busy_poll: None,
+            last_comp: Instant::now(),
+            spin_start: None,
+        })
+    }
+
+    /// Return the applied io_uring setup options of this queue
+    pub fn get_ring_config(&self) -> UblkRingConfig {
+        self.q_ring.borrow().get_config().clone()
+    }
+
+    /// Push SQE to the queue ring, and SQ full is handled by the ring
+    #[inline(always)]
+    fn push_sqe(&mut self, sqe: squeue::Entry) {
+        let mut q_ring = self.q_ring.borrow_mut();
+
+        q_ring.set_owner(self.ud_slot, self.fd_base);
+        unsafe {
+            q_ring.push_async(sqe);
+        }
+    }
+
+    fn support_comp_batch(&self) -> bool {
+        self.flags & super::UBLK_DEV_F_COMP_BATCH != 0
+    }
+
+    /// Return one handle for completing IO from other context
+    ///
+    /// The completion channel is created when this method is called for
+    /// the 1st time, and all returned handles share the same channel.
+    pub fn completer(&mut self) -> Result<UblkCompleter, UblkError> {
+        if self.comp_chan.is_none() {
+            let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+            if efd < 0 {
+                return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+            }
+            self.comp_chan = Some(Arc::new(UblkCompChan {
+                efd,
+                ios: Mutex::new(Vec::new()),
+            }));
+            self.arm_comp_chan();
+        }
+
+        Ok(UblkCompleter {
+            chan: Arc::clone(self.comp_chan.as_ref().unwrap()),
+        })
+    }
+
+    fn arm_comp_chan(&mut self) {
+        if let Some(chan) = self.comp_chan.as_ref() {
+            let sqe = opcode::PollAdd::new(types::Fd(chan.efd), libc::POLLIN as u32)
+                .multi(true)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_COMP_CHAN, 0));
+
+            self.push_sqe(sqe);
+        }
+    }
+
+    /// Return one handle for stopping this queue from other context
+    ///
+    /// After `UblkQueueStopHandle::stop()` is called, the queue keeps
+    /// handling IO until no IO command is owned by target, then
+    /// `wait_and_handle_io()` returns and `process_io()` returns
+    /// `UblkError::QueueIsDown`. IO which is being handled is committed,
+    /// and IO coming after the stop isn't passed to IO closure any more,
+    /// so no tag is in the middle of handling, and the queue can be
+    /// stopped under IO load. Target IO on extra IO slots isn't waited.
+    ///
+    /// IO coming after the stop is left to the driver, which requeues it
+    /// for the recovered daemon, or fails it if the device can't be
+    /// recovered, after this queue and `UblkDev` are dropped.
+    ///
+    /// Stopping the queue doesn't release /dev/ublkcN, which is held by
+    /// `UblkDev`, and the driver rejects START_USER_RECOVERY until
+    /// /dev/ublkcN is closed, so `UblkDev` has to be dropped too before
+    /// recovering the device.
+    ///
+    /// The device isn't stopped or deleted.
+    pub fn stop_handle(&mut self) -> Result<UblkQueueStopHandle, UblkError> {
+        if self.stop_chan.is_none() {
+            let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+            if efd < 0 {
+                return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+            }
+            self.stop_chan = Some(Arc::new(UblkStopChan { efd }));
+
+            let sqe = opcode::PollAdd::new(types::Fd(efd), libc::POLLIN as u32)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_STOP, 0));
+            self.push_sqe(sqe);
+        }
+
+        Ok(UblkQueueStopHandle {
+            chan: Arc::clone(self.stop_chan.as_ref().unwrap()),
+        })
+    }
+
+    fn handle_stop_chan(&mut self, e: &UblkCQE) {
+        if e.result() >= 0 {
+            self.q_state |= UBLK_QUEUE_EXIT;
+            trace!(
+                "dev {} queue {} is asked to stop",
+                self.dev.dev_info.dev_id,
+                self.q_id
+            );
+        }
+    }
+
+    /// Poll signalfd `sfd` in this queue's ring, and each signal read from
+    /// it is reported as `UblkQueueEvent::Signal` to all subscribers of
+    /// `sfd`, see `UblkSignalFd::subscribe()`
+    ///
+    /// Signals in the signalfd mask have to be blocked in all pthreads, so
+    /// they are only handled when this queue reads them between IOs.
+    pub fn set_signal_fd(&mut self, sfd: Arc<UblkSignalFd>) {
+        self.signal_fd = Some(sfd);
+        self.arm_signal_fd();
+    }
+
+    fn arm_signal_fd(&mut self) {
+        if let Some(sfd) = self.signal_fd.as_ref() {
+            let sqe = opcode::PollAdd::new(types::Fd(sfd.fd.as_raw_fd()), libc::POLLIN as u32)
+                .multi(true)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_SIGNAL, 0));
+
+            self.push_sqe(sqe);
+        }
+    }
+
+    fn handle_signal_fd(&mut self, e: &UblkCQE) {
+        match self.signal_fd.as_ref() {
+            Some(sfd) => sfd.dispatch(self.q_id),
+            None => return,
+        }
+
+        // multishot poll is terminated, so re-arm it
+        if e.result() >= 0
+            && !cqueue::more(e.0.flags())
+            && (self.q_state & UBLK_QUEUE_STOPPING) == 0
+        {
+            self.arm_signal_fd();
+        }
+    }
+
+    /// If this queue is stopped by `UblkQueueStopHandle`, and no IO
+    /// command is owned by target
+    pub fn is_stopped(&self) -> bool {
+        (self.q_state & UBLK_QUEUE_EXIT) != 0 && self.cmd_inflight + self.nr_parked == self.q_depth
+    }
+
+    /// Complete all IOs posted from `UblkCompleter`
+    fn handle_comp_chan(&mut self, e: &UblkCQE) {
+        let ios = match self.comp_chan.as_ref() {
+            Some(chan) => {
+                let mut val = 0_u64;
+
+                // clear eventfd before taking the list, so any IO posted
+                // after taking the list can wakeup us again
+                unsafe {
+                    libc::read(
+                        chan.efd,
+                        std::ptr::addr_of_mut!(val) as *mut libc::c_void,
+                        core::mem::size_of::<u64>(),
+                    );
+                }
+                std::mem::take(&mut *chan.ios.lock().unwrap_or_else(|e| e.into_inner()))
+            }
+            None => return,
+        };
+
+        for (tag, res) in ios {
+            self.complete_tgt_io(tag, res);
+        }
+
+        // multishot poll is terminated, so re-arm it
+        if e.result() >= 0
+            && !cqueue::more(e.0.flags())
+            && (self.q_state & UBLK_QUEUE_STOPPING) == 0
+        {
+            self.arm_comp_chan();
+        }
+    }
+
+    /// Complete IO posted by target out of its IO closure, or added to
+    /// batch list; invalid or duplicated completion is dropped
+    fn complete_tgt_io(&mut self, tag: u16, res: i32) {
+        if tag as u32 >= self.q_depth {
+            error!("q{}: invalid completed tag {}", self.q_id, tag);
+            self.stats.dropped_comps += 1;
+            return;
+        }
+        // duplicated or stale completion
+        if !self.ios[tag as usize].is_owned_by_tgt() {
+            warn!(
+                "q{}: tag {} isn't owned by target, drop its completion",
+                self.q_id, tag
+            );
+            self.stats.dropped_comps += 1;
+            return;
+        }
+        self.ios[tag as usize].complete(res);
+        self.check_and_queue_io_cmd(tag);
+    }
+
+    fn arm_timer(&mut self, id: u16) {
+        if let Some(Some(timer)) = self.timers.get(id as usize) {
+            let sqe = opcode::Timeout::new(&timer.ts)
+                .build()
+                .user_data(build_internal_user_data(id, UBLK_INTERNAL_OP_TIMER, 0));
+
+            self.push_sqe(sqe);
+        }
+    }
+
+    /// Add one timer to this queue, and return the timer id
+    ///
+    /// # Arguments:
+    ///
+    /// * `timeout`: expiration time, relative to now
+    /// * `periodic`: re-arm the timer after it expires until it is
+    ///   cancelled or the queue is stopping
+    /// * `cb`: called in queue context when the timer expires
+    ///
+    /// Timer is driven by io_uring `Timeout` on the queue ring, so no
+    /// extra thread is needed for periodic flushing or cache expiration.
+    pub fn add_timer<F>(&mut self, timeout: Duration, periodic: bool, cb: F) -> u16
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkQueueRing) + 'static,
+    {
+        let timer = Box::new(UblkTimer {
+            ts: timeout.into(),
+            periodic,
+            cancelled: false,
+            cb: Box::new(cb),
+        });
+        let id = match self.timers.iter().position(|t| t.is_none()) {
+            Some(id) => {
+                self.timers[id] = Some(timer);
+                id
+            }
+            None => {
+                self.timers.push(Some(timer));
+                self.timers.len() - 1
+            }
+        } as u16;
+
+        self.arm_timer(id);
+        id
+    }
+
+    /// Cancel timer `id`, and its callback won't be called any more
+    pub fn cancel_timer(&mut self, id: u16) {
+        if let Some(Some(timer)) = self.timers.get_mut(id as usize) {
+            if !timer.cancelled {
+                let data = build_internal_user_data(id, UBLK_INTERNAL_OP_TIMER, 0);
+                let sqe = opcode::TimeoutRemove::new(data | self.ud_slot)
+                    .build()
+                    .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+
+                timer.cancelled = true;
+                self.push_sqe(sqe);
+            }
+        }
+    }
+
+    fn handle_timer(&mut self, e: &UblkCQE) {
+        let id = UblkIOCtx::user_data_to_tag(e.user_data()) as u16;
+        let qctx = self.make_queue_ctx();
+        let stopping = (self.q_state & UBLK_QUEUE_STOPPING) != 0;
+
+        let rearm = match self.timers.get_mut(id as usize) {
+            Some(Some(timer)) => {
+                if !timer.cancelled && e.result() == -libc::ETIME {
+                    let mut q_ring = self.q_ring.borrow_mut();
+
+                    q_ring.set_owner(self.ud_slot, self.fd_base);
+                    (timer.cb)(&qctx, &mut q_ring);
+                }
+                timer.periodic && !timer.cancelled && !stopping && e.result() == -libc::ETIME
+            }
+            _ => return,
+        };
+
+        if rearm {
+            self.arm_timer(id);
+        } else {
+            self.timers[id as usize] = None;
+        }
+    }
+
+    /// Deadline of target IO expires, so cancel it
+    fn handle_deadline(&mut self, e: &UblkCQE) {
+        let data = e.user_data();
+        let tag = UblkIOCtx::user_data_to_tag(data);
+        let io = &mut self.ios[tag as usize];
+
+        if e.result() != -libc::ETIME
+            || io.deadline == 0
+            || io.dl_seq as u32 != user_data_to_tgt_data(data)
+        {
+            return;
+        }
+
+        let target = if io.chain_pending > 0 {
+            io.deadline | UBLK_CHAIN_IO
+        } else {
+            io.deadline
+        };
+        let sqe = opcode::AsyncCancel::new(target | self.ud_slot)
+            .build()
+            .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+
+        io.dl_expired = true;
+        self.stats.io_timeouts += 1;
+        warn!(
+            "q{}: target io of tag {} is timed out, cancel it",
+            self.q_id, tag
+        );
+        self.push_sqe(sqe);
+    }
+
+    /// Target IO with deadline is completed; return None if the ublk IO
+    /// is completed with -ETIMEDOUT, otherwise result of the CQE
+    fn end_deadline(&mut self, tag: u32, res: i32) -> Option<i32> {
+        let io = &mut self.ios[tag as usize];
+        let expired = io.dl_expired;
+        let dl = build_internal_user_data(tag as u16, UBLK_INTERNAL_OP_DEADLINE, io.dl_seq as u32);
+
+        io.deadline = 0;
+        io.dl_expired = false;
+        if !expired {
+            let sqe = opcode::TimeoutRemove::new(dl | self.ud_slot)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+
+            self.push_sqe(sqe);
+            Some(res)
+        } else if res >= 0 {
+            Some(res)
+        } else if self.fail_io(tag, -libc::ETIMEDOUT) {
+            self.check_and_queue_io_cmd(tag as u16);
+            None
+        } else {
+            Some(-libc::ETIMEDOUT)
+        }
+    }
+
+    /// Enter idle state after there isn't any IO in `timeout`, then
+    /// io buffers are released by `madvise(MADV_DONTNEED)` and the
+    /// handler set by `set_idle_handler()` is called. The queue leaves
+    /// idle state when the next IO comes. Idle detection is disabled if
+    /// `timeout` is None, which is the default.
+    ///
+    /// The check is driven by io_uring `Timeout` on the queue ring, and
+    /// it is stopped when the queue is idle, so idle queue isn't woken up.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+        self.idle_seq = self.idle_seq.wrapping_add(1);
+        self.last_io = Instant::now();
+        if let Some(t) = timeout {
+            self.arm_idle_timer(t);
+        }
+    }
+
+    pub fn get_idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Set handler called when the queue enters idle state, and target
+    /// can shrink its caches in the handler
+    pub fn set_idle_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&UblkQueueCtx) + 'static,
+    {
+        self.idle_handler = Some(Box::new(handler));
+    }
+
+    /// If this queue is idle, see `set_idle_timeout()`
+    pub fn is_idle(&self) -> bool {
+        (self.q_state & UBLK_QUEUE_IDLE) != 0
+    }
+
+    fn arm_idle_timer(&mut self, timeout: Duration) {
+        if (self.q_state & UBLK_QUEUE_STOPPING) != 0 {
+            return;
+        }
+
+        *self.idle_ts = timeout.into();
+        let sqe = opcode::Timeout::new(&*self.idle_ts)
+            .build()
+            .user_data(build_internal_user_data(
+                0,
+                UBLK_INTERNAL_OP_IDLE,
+                self.idle_seq as u32,
+            ));
+        self.push_sqe(sqe);
+    }
+
+    fn handle_idle_timer(&mut self, e: &UblkCQE) {
+        let timeout = match self.idle_timeout {
+            Some(t) => t,
+            None => return,
+        };
+
+        if e.result() != -libc::ETIME
+            || self.idle_seq != user_data_to_tgt_data(e.user_data()) as u16
+        {
+            return;
+        }
+
+        let elapsed = self.last_io.elapsed();
+        if elapsed < timeout {
+            self.arm_idle_timer(timeout - elapsed);
+        } else if self.cmd_inflight < self.q_depth {
+            // some IO is still owned by target
+            self.arm_idle_timer(timeout);
+        } else {
+            self.enter_idle();
+        }
+    }
+
+    fn enter_idle(&mut self) {
+        let buf_size = self.dev.dev_info.max_io_buf_bytes as usize;
+
+        for io in &self.ios[..self.q_depth as usize] {
+            if !io.__buf_addr.is_null() {
+                unsafe {
+                    libc::madvise(
+                        io.__buf_addr as *mut libc::c_void,
+                        buf_size,
+                        libc::MADV_DONTNEED,
+                    );
+                }
+            }
+        }
+
+        if let Some(mut handler) = self.idle_handler.take() {
+            handler(&self.make_queue_ctx());
+            self.idle_handler = Some(handler);
+        }
+
+        self.q_state |= UBLK_QUEUE_IDLE;
+        self.stats.idle_enters += 1;
+        trace!(
+            "dev {} queue {} enters idle",
+            self.dev.dev_info.dev_id,
+            self.q_id
+        );
+    }
+
+    /// One IO comes, so record its time and leave idle state
+    #[inline(always)]
+    fn mark_io_active(&mut self) {
+        if let Some(timeout) = self.idle_timeout {
+            self.last_io = Instant::now();
+            if self.is_idle() {
+                self.q_state &= !UBLK_QUEUE_IDLE;
+                self.arm_idle_timer(timeout);
+            }
+        }
+    }
+
+    fn handle_internal_cqe(&mut self, e: &UblkCQE) {
+        match UblkIOCtx::user_data_to_op(e.user_data()) {
+            UBLK_INTERNAL_OP_COMP_CHAN => self.handle_comp_chan(e),
+            UBLK_INTERNAL_OP_TIMER => self.handle_timer(e),
+            UBLK_INTERNAL_OP_DEADLINE => self.handle_deadline(e),
+            UBLK_INTERNAL_OP_IDLE => self.handle_idle_timer(e),
+            UBLK_INTERNAL_OP_STATS => self.handle_stats_timer(e),
+            UBLK_INTERNAL_OP_STATS_REQ => self.handle_stats_req(e),
+            UBLK_INTERNAL_OP_STOP => self.handle_stop_chan(e),
+            UBLK_INTERNAL_OP_SIGNAL => self.handle_signal_fd(e),
+            UBLK_INTERNAL_OP_CANCEL => {}
+            op => error!("q{}: unknown internal op {}", self.q_id, op),
+        }
+    }
+
+    /// Set how to handle error returned from IO closure, default is
+    /// `UblkErrorPolicy::FailIo`
+    pub fn set_error_policy(&mut self, policy: UblkErrorPolicy) {
+        self.err_policy = policy;
+    }
+
+    pub fn get_error_policy(&self) -> UblkErrorPolicy {
+        self.err_policy
+    }
+
+    /// Set how to handle panic from IO closure, default is
+    /// `UblkPanicPolicy::FailIo`
+    pub fn set_panic_policy(&mut self, policy: UblkPanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    pub fn get_panic_policy(&self) -> UblkPanicPolicy {
+        self.panic_policy
+    }
+
+    /// Report queue events, such as panic of IO closure, via `tx`
+    pub fn set_event_sender(&mut self, tx: mpsc::Sender<UblkQueueEvent>) {
+        self.event_tx = Some(tx);
+    }
+
+    /// Allocate one extra IO slot from `UblkTgt.extra_ios`, None if all
+    /// are in use
+    ///
+    /// `buf` is attached to the slot and returned from
+    /// `UblkIOCtx::io_buf_addr()` when handling CQE of this slot; it is
+    /// owned by target and can be null. Target IO on the slot has to be
+    /// submitted with userdata built by `UblkExtraIO::user_data()`.
+    ///
+    /// Per-IO target state of extra slot isn't reset by libublk.
+    pub fn alloc_extra_io(&mut self, buf: *mut u8) -> Option<UblkExtraIO> {
+        self.extra_ios.alloc(buf)
+    }
+
+    /// Free extra IO slot `io` after all its target IO is completed
+    pub fn free_extra_io(&mut self, io: UblkExtraIO) {
+        self.extra_ios.free(io)
+    }
+
+    /// Handle CQE of target IO on extra IO slots by `handler` instead of
+    /// IO closure, so target metadata IO needn't to be told apart from
+    /// ublk IO in IO closure
+    ///
+    /// `UblkIOCtx::complete_io()` is meaningless for extra slot.
+    pub fn set_extra_io_handler<H>(&mut self, handler: H)
+    where
+        H: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError> + 'static,
+    {
+        self.extra_handler = Some(Box::new(handler));
+    }
+
+    /// If this queue is aborted because of `UblkErrorPolicy::AbortQueue`
+    /// or `UblkPanicPolicy::AbortQueue`
+    pub fn is_aborted(&self) -> bool {
+        (self.q_state & UBLK_QUEUE_ABORTED) != 0
+    }
+
+    /// Publish stats of this queue to `sink` when it is asked by
+    /// `UblkDevStats::refresh()`, every `interval` if it isn't zero, and
+    /// when the queue is dropped
+    ///
+    /// Request from `refresh()` is received by polling one eventfd, and
+    /// periodic publishing is driven by io_uring `Timeout`, both on the
+    /// queue ring.
+    pub fn set_stats_publisher(
+        &mut self,
+        sink: Arc<UblkDevStats>,
+        interval: Duration,
+    ) -> Result<(), UblkError> {
+        let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if efd < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+        let efd = Arc::new(unsafe { OwnedFd::from_raw_fd(efd) });
+
+        sink.publish(self.q_id, self.get_stats(), sink.req_seq());
+        sink.add_queue(self.q_id, efd.clone());
+        self.stats_sink = Some(sink);
+        self.stats_req = Some(efd);
+        self.stats_interval = interval;
+        self.arm_stats_req();
+        self.arm_stats_timer();
+        Ok(())
+    }
+
+    fn arm_stats_req(&mut self) {
+        if let Some(efd) = self.stats_req.as_ref() {
+            let sqe = opcode::PollAdd::new(types::Fd(efd.as_raw_fd()), libc::POLLIN as u32)
+                .multi(true)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_STATS_REQ, 0));
+
+            self.push_sqe(sqe);
+        }
+    }
+
+    fn handle_stats_req(&mut self, e: &UblkCQE) {
+        if let (Some(efd), Some(sink)) = (self.stats_req.as_ref(), self.stats_sink.as_ref()) {
+            let mut val = 0_u64;
+
+            // clear eventfd before taking the snapshot, so request coming
+            // later wakes us up again
+            unsafe {
+                libc::read(
+                    efd.as_raw_fd(),
+                    std::ptr::addr_of_mut!(val) as *mut libc::c_void,
+                    core::mem::size_of::<u64>(),
+                );
+            }
+            let seq = sink.req_seq();
+            sink.publish(self.q_id, self.get_stats(), seq);
+        }
+
+        // multishot poll is terminated, so re-arm it
+        if e.result() >= 0
+            && !cqueue::more(e.0.flags())
+            && (self.q_state & UBLK_QUEUE_STOPPING) == 0
+        {
+            self.arm_stats_req();
+        }
+    }
+
+    fn arm_stats_timer(&mut self) {
+        if self.stats_interval.is_zero() || (self.q_state & UBLK_QUEUE_STOPPING) != 0 {
+            return;
+        }
+
+        *self.stats_ts = self.stats_interval.into();
+        let sqe = opcode::Timeout::new(&*self.stats_ts)
+            .build()
+            .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_STATS, 0));
+        self.push_sqe(sqe);
+    }
+
+    fn handle_stats_timer(&mut self, e: &UblkCQE) {
+        if e.result() != -libc::ETIME {
+            return;
+        }
+        if let Some(sink) = self.stats_sink.as_ref() {
+            sink.publish(self.q_id, self.get_stats(), sink.req_seq());
+        }
+        self.arm_stats_timer();
+    }
+
+    /// One IO command is received from ublk driver
+    #[inline(always)]
+    fn account_io_start(&mut self, tag: u32) {
+        let iod = unsafe { &*self.make_queue_ctx().get_iod(tag) };
+        let op = (iod.op_flags & 0xff) as usize;
+
+        #[cfg(feature = "tracing")]
+        {
+            self.ios[tag as usize].span = tracing::debug_span!(
+                parent: &self.span,
+                "ublk_io",
+                q_id = self.q_id,
+                tag,
+                op,
+                sector = iod.start_sector,
+                len = (iod.nr_sectors as u64) << 9,
+            );
+        }
+
+        let stats = &mut self.stats;
+
+        if op < UBLK_STATS_NR_OPS {
+            stats.op_ios[op] += 1;
+            stats.op_bytes[op] += (iod.nr_sectors as u64) << 9;
+        }
+        stats.inflight += 1;
+        if stats.inflight > stats.inflight_max {
+            stats.inflight_max = stats.inflight;
+        }
+        self.ios[tag as usize].start = Instant::now();
+    }
+
+    /// IO command is committed to ublk driver
+    #[inline(always)]
+    fn account_io_done(&mut self, tag: u16) {
+        let io = &self.ios[tag as usize];
+        let us = io.start.elapsed().as_micros() as u64;
+        let bucket = (64 - us.leading_zeros() as usize).min(UBLK_STATS_NR_LAT_BUCKETS - 1);
+        let stats = &mut self.stats;
+
+        stats.lat_hist[bucket] += 1;
+        stats.inflight = stats.inflight.saturating_sub(1);
+        if io.result < 0 {
+            *stats.errors.entry(-io.result).or_insert(0) += 1;
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            tracing::debug!(parent: &io.span, res = io.result, "commit");
+            self.ios[tag as usize].span = tracing::Span::none();
+        }
+    }
+
+    /// Return statistics snapshot of this queue
+    pub fn get_stats(&self) -> UblkQueueStats {
+        let q_ring = self.q_ring.borrow();
+
+        UblkQueueStats {
+            sq_full: q_ring.sq_full,
+            sq_overflows: q_ring.sq_overflows,
+            ring_enters: q_ring.enters,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Busy poll for completion in `window` after each completion, then
+    /// switch to blocking wait; disabled if `window` is None
+    ///
+    /// In the window, the queue doesn't wait for completion: queued SQEs
+    /// are still submitted via io_uring_enter(), which is skipped only if
+    /// nothing is queued, and CQ is checked again at once. So the pthread
+    /// keeps running in the window, and syscalls are avoided only when SQ
+    /// is consumed by SQPOLL thread too. Time spent in spinning and in
+    /// blocking wait is accounted in `UblkQueueStats::poll_spin_ns` and
+    /// `UblkQueueStats::poll_sleep_ns`.
+    pub fn set_busy_poll(&mut self, window: Option<Duration>) {
+        self.busy_poll = window;
+        self.last_comp = Instant::now();
+        self.spin_start = None;
+    }
+
+    pub fn get_busy_poll(&self) -> Option<Duration> {
+        self.busy_poll
+    }
+
+    /// Check if it is still in busy polling window, and account spin time
+    /// when leaving the window
+    fn busy_polling(&mut self) -> bool {
+        let window = match self.busy_poll {
+            Some(w) => w,
+            None => return false,
+        };
+        let now = Instant::now();
+
+        if now.duration_since(self.last_comp) < window {
+            self.spin_start.get_or_insert(now);
+            true
+        } else {
+            if let Some(start) = self.spin_start.take() {
+                self.stats.poll_spin_ns += now.duration_since(start).as_nanos() as u64;
+            }
+            false
+        }
+    }
+
+    /// Account busy polling when completion is reaped after waiting
+    fn account_busy_poll(&mut self, wait_start: Option<Instant>, reapped: usize) {
+        if self.busy_poll.is_none() {
+            return;
+        }
+        let now = Instant::now();
+
+        if let Some(start) = wait_start {
+            self.stats.poll_sleep_ns += now.duration_since(start).as_nanos() as u64;
+        }
+        if reapped > 0 {
+            if let Some(start) = self.spin_start.take() {
+                self.stats.poll_spin_ns += now.duration_since(start).as_nanos() as u64;
+            }
+            self.last_comp = now;
+        }
     }
 
     pub fn set_poll(&mut self, val: bool) {
@@ -664,18 +3102,11 @@ impl UblkQueue<'_> {
         };
         let data = UblkIOCtx::build_user_data(tag, cmd_op, 0, false);
 
-        let sqe = opcode::UringCmd16::new(types::Fixed(0), cmd_op)
+        let sqe = opcode::UringCmd16::new(types::Fixed(self.fd_base), cmd_op)
             .cmd(unsafe { core::mem::transmute::<sys::ublksrv_io_cmd, [u8; 16]>(io_cmd) })
             .build()
             .user_data(data);
 
-        unsafe {
-            self.q_ring
-                .submission()
-                .push(&sqe)
-                .expect("submission fail");
-        }
-
         trace!(
             "{}: (qid {} tag {} cmd_op {}) iof {} stopping {}",
             "queue_io_cmd",
@@ -685,17 +3116,26 @@ impl UblkQueue<'_> {
             io.flags,
             (self.q_state & UBLK_QUEUE_STOPPING) != 0
         );
+        self.push_sqe(sqe);
 
         1
     }
 
     #[inline(always)]
     fn queue_io_cmd(&mut self, tag: u16) -> i32 {
+        let commit = (self.ios[tag as usize].flags & UBLK_IO_NEED_COMMIT_RQ_COMP) != 0;
         let res = self.__queue_io_cmd(tag);
 
         if res > 0 {
+            if commit {
+                self.account_io_done(tag);
+            }
+
+            let io = &mut self.ios[tag as usize];
+
             self.cmd_inflight += 1;
-            self.ios[tag as usize].flags = 0;
+            io.flags = UBLK_IO_IN_FLIGHT;
+            io.state = T::default();
         }
 
         res
@@ -727,31 +3167,147 @@ impl UblkQueue<'_> {
 
     #[inline(always)]
     fn queue_is_done(&self) -> bool {
-        (self.q_state & UBLK_QUEUE_STOPPING) != 0 && self.queue_is_idle()
+        ((self.q_state & UBLK_QUEUE_STOPPING) != 0 && self.queue_is_idle()) || self.is_stopped()
+    }
+
+    /// Fail the IO command because IO closure returns error
+    fn handle_io_error(&mut self, tag: u32, e: &UblkCQE, err: UblkError) {
+        let res = err.to_errno();
+        let op = if e.is_tgt_io() {
+            UblkIOCtx::user_data_to_op(e.user_data())
+        } else {
+            unsafe { (*self.make_queue_ctx().get_iod(tag)).op_flags & 0xff }
+        };
+
+        error!(
+            "{}: qid {} tag {} op {} tgt_io {}: {:?}, fail io with {}",
+            "handle_io_error",
+            self.q_id,
+            tag,
+            op,
+            e.is_tgt_io(),
+            err,
+            res
+        );
+
+        // the IO may have been completed before returning error
+        self.fail_io(tag, res);
+
+        if self.err_policy == UblkErrorPolicy::AbortQueue {
+            self.q_state |= UBLK_QUEUE_ABORTED;
+        }
+    }
+
+    /// Complete IO command with `res` if it is still owned by target
+    fn fail_io(&mut self, tag: u32, res: i32) -> bool {
+        if tag >= self.q_depth {
+            return false;
+        }
+
+        let io = &mut self.ios[tag as usize];
+        if io.is_owned_by_tgt() {
+            io.complete(res);
+            self.stats.failed_ios += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// IO closure panicked when handling `tag`
+    fn handle_io_panic(&mut self, tag: u32, e: &UblkCQE, msg: String) {
+        error!(
+            "{}: qid {} tag {} tgt_io {}: io closure panicked: {}",
+            "handle_io_panic",
+            self.q_id,
+            tag,
+            e.is_tgt_io(),
+            msg
+        );
+        self.stats.panics += 1;
+        self.fail_io(tag, -libc::EIO);
+
+        if self.panic_policy != UblkPanicPolicy::FailIo {
+            for t in 0..self.q_depth {
+                if t != tag && self.fail_io(t, -libc::EIO) {
+                    self.check_and_queue_io_cmd(t as u16);
+                }
+            }
+        }
+        if self.panic_policy == UblkPanicPolicy::AbortQueue {
+            self.q_state |= UBLK_QUEUE_ABORTED;
+        }
+
+        if let Some(tx) = self.event_tx.as_ref() {
+            let _ = tx.send(UblkQueueEvent::Panic {
+                q_id: self.q_id,
+                tag,
+                msg,
+            });
+        }
     }
 
     #[inline(always)]
     fn call_io_closure<F>(&mut self, mut ops: F, tag: u32, e: &UblkCQE)
     where
-        F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
     {
         let comp_batch = self.support_comp_batch();
-        let mut ctx = UblkIOCtx(
-            &mut self.q_ring,
-            &mut self.ios[tag as usize],
-            e,
-            if comp_batch { Some(Vec::new()) } else { None },
-        );
+        let mut nr_retries = 0;
+
+        #[cfg(feature = "tracing")]
+        let _span = self.ios[tag as usize].span.clone().entered();
+
+        loop {
+            let (res, batch, queued) = {
+                let mut q_ring = self.q_ring.borrow_mut();
+                let pushed = q_ring.pushed;
+
+                q_ring.set_owner(self.ud_slot, self.fd_base);
+                let mut ctx = UblkIOCtx(
+                    &mut q_ring,
+                    &mut self.ios[tag as usize],
+                    e,
+                    if comp_batch { Some(Vec::new()) } else { None },
+                    &mut self.extra_ios,
+                );
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ops(&mut ctx)));
+                let batch = ctx.3.take();
+
+                (res, batch, q_ring.pushed != pushed)
+            };
+
+            let res = match res {
+                Ok(r) => r,
+                Err(p) => {
+                    self.handle_io_panic(tag, e, panic_msg(&*p));
+                    break;
+                }
+            };
+
+            // IOs added to batch list are done even though error is returned
+            if matches!(res, Ok(UBLK_IO_S_COMP_BATCH) | Err(_)) {
+                for (tag, res) in batch.unwrap_or_default() {
+                    self.complete_tgt_io(tag, res);
+                }
+            }
 
-        if let Ok(res) = ops(&mut ctx) {
-            if res == UBLK_IO_S_COMP_BATCH {
-                if let Some(ios) = ctx.3.as_mut() {
-                    for item in ios {
-                        let tag = item.0;
-                        self.ios[tag as usize].complete(item.1);
-                        self.check_and_queue_io_cmd(tag);
+            match res {
+                Err(err) => {
+                    self.stats.handler_errors += 1;
+                    // retrying is only safe if nothing is done for this IO
+                    let owned = tag < self.q_depth && self.ios[tag as usize].is_owned_by_tgt();
+                    if let UblkErrorPolicy::Retry(max) = self.err_policy {
+                        if nr_retries < max && owned && !queued {
+                            nr_retries += 1;
+                            self.stats.retries += 1;
+                            continue;
+                        }
                     }
+                    self.handle_io_error(tag, e, err);
+                    break;
                 }
+                Ok(_) => break,
             }
         }
     }
@@ -760,7 +3316,7 @@ impl UblkQueue<'_> {
     #[allow(unused_assignments)]
     fn handle_cqe<F>(&mut self, ops: F, e: &UblkCQE)
     where
-        F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
     {
         let data = e.user_data();
         let res = e.result();
@@ -778,8 +3334,39 @@ impl UblkQueue<'_> {
             self.q_state,
         );
 
+        if is_internal_io(data) {
+            self.handle_internal_cqe(e);
+            return;
+        }
+
         if is_target_io(data) {
-            let res = e.result();
+            self.stats.tgt_ios += 1;
+            // IO closure is called after the whole chain is done
+            let res = if is_chain_io(data) {
+                match self.ios[tag as usize].chain_cqe(e.result()) {
+                    Some(r) => r,
+                    None => return,
+                }
+            } else {
+                e.result()
+            };
+            let res = if self.ios[tag as usize].deadline_matches(data) {
+                match self.end_deadline(tag, res) {
+                    Some(r) => r,
+                    None => return,
+                }
+            } else {
+                res
+            };
+            let e = &UblkCQE(e.0, e.1, res);
+
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                parent: &self.ios[tag as usize].span,
+                user_data = data,
+                res,
+                "tgt complete"
+            );
 
             if res < 0 && res != -(libc::EAGAIN) {
                 let data = e.user_data();
@@ -792,19 +3379,37 @@ impl UblkQueue<'_> {
                     UblkIOCtx::user_data_to_op(data)
                 );
             }
-            self.call_io_closure(ops, tag, e);
+            if tag >= self.q_depth && self.extra_handler.is_some() {
+                let mut handler = self.extra_handler.take().unwrap();
+
+                self.call_io_closure(&mut handler, tag, e);
+                if self.extra_handler.is_none() {
+                    self.extra_handler = Some(handler);
+                }
+            } else {
+                self.call_io_closure(ops, tag, e);
+            }
             return;
         }
 
         self.cmd_inflight -= 1;
+        self.ios[tag as usize].flags &= !UBLK_IO_IN_FLIGHT;
 
         if res == sys::UBLK_IO_RES_ABORT || ((self.q_state & UBLK_QUEUE_STOPPING) != 0) {
             self.q_state |= UBLK_QUEUE_STOPPING;
             self.ios[tag as usize].flags &= !UBLK_IO_NEED_FETCH_RQ;
         }
 
-        if res == sys::UBLK_IO_RES_OK as i32 {
+        if res == sys::UBLK_IO_RES_OK as i32 && (self.q_state & UBLK_QUEUE_EXIT) != 0 {
+            // not handled after the queue is stopped, so the queue won't
+            // be kept busy by new IO, see `stop_handle()`
             assert!(tag < self.q_depth);
+            self.ios[tag as usize].flags = UBLK_IO_PARKED;
+            self.nr_parked += 1;
+        } else if res == sys::UBLK_IO_RES_OK as i32 {
+            assert!(tag < self.q_depth);
+            self.mark_io_active();
+            self.account_io_start(tag);
             self.call_io_closure(ops, tag, e);
         } else {
             /*
@@ -820,45 +3425,77 @@ impl UblkQueue<'_> {
     }
 
     #[inline(always)]
-    fn reap_one_event<F>(&mut self, ops: F) -> usize
+    /// Handle all ready CQEs, and CQEs coming during handling are reaped
+    /// and handled too. SQEs queued for the whole batch, such as
+    /// COMMIT_AND_FETCH_REQ, are submitted once in the following
+    /// `submit_and_wait()`.
+    fn reap_events<F>(&mut self, mut ops: F) -> usize
     where
-        F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
     {
-        let idx = self.cqes_idx;
-        if idx >= self.cqes_cnt {
-            return 0;
+        let mut cqes = std::mem::take(&mut self.cqes);
+        let mut reapped = 0;
+
+        loop {
+            cqes.clear();
+            cqes.extend(self.q_ring.borrow_mut().ring.completion());
+            if cqes.is_empty() {
+                break;
+            }
+
+            let cnt = cqes.len();
+            for (idx, cqe) in cqes.iter().enumerate() {
+                self.handle_event(&mut ops, cqe, idx, cnt);
+            }
+            reapped += cnt;
         }
+        self.cqes = cqes;
 
-        let cqe = match self.q_ring.completion().next() {
-            None => return 0,
-            Some(r) => r,
-        };
+        reapped
+    }
 
-        let ublk_cqe = UblkCQE(
-            &cqe,
+    /// Handle one CQE which is the `idx`th one in `cnt` reaped CQEs
+    #[inline(always)]
+    fn handle_event<F>(&mut self, ops: F, cqe: &cqueue::Entry, idx: usize, cnt: usize)
+    where
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
+    {
+        let ublk_cqe = UblkCQE::new(
+            cqe,
             if idx == 0 { UBLK_IO_F_FIRST } else { 0 }
-                | if idx + 1 == self.cqes_cnt {
-                    UBLK_IO_F_LAST
-                } else {
-                    0
-                },
+                | if idx + 1 == cnt { UBLK_IO_F_LAST } else { 0 },
         );
         self.handle_cqe(ops, &ublk_cqe);
 
-        let tag = ublk_cqe.get_tag();
-        self.check_and_queue_io_cmd(tag as u16);
+        if !is_internal_io(ublk_cqe.user_data()) {
+            let tag = ublk_cqe.get_tag();
+            self.check_and_queue_io_cmd(tag as u16);
+        }
+    }
 
-        self.cqes_idx += 1;
+    /// Handle all completed IOs from the standalone IOPOLL ring
+    fn reap_iopoll_events<F>(&mut self, mut ops: F) -> usize
+    where
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
+    {
+        let cqes = {
+            let mut q_ring = self.q_ring.borrow_mut();
+            let cqes: Vec<cqueue::Entry> = match q_ring.iopoll.as_mut() {
+                Some(r) => r.completion().collect(),
+                None => return 0,
+            };
 
-        1
-    }
+            q_ring.iopoll_inflight -= cqes.len();
+            cqes
+        };
 
-    #[inline(always)]
-    fn prep_reap_events(&mut self) -> usize {
-        self.cqes_cnt = self.q_ring.completion().len();
-        self.cqes_idx = 0;
+        for cqe in &cqes {
+            let ublk_cqe = UblkCQE::new(cqe, 0);
 
-        self.cqes_cnt
+            self.handle_cqe(&mut ops, &ublk_cqe);
+            self.check_and_queue_io_cmd(ublk_cqe.get_tag() as u16);
+        }
+        cqes.len()
     }
 
     /// Process the incoming IO from io_uring
@@ -896,25 +3533,43 @@ impl UblkQueue<'_> {
     /// handling needs extra computation, which often require to offload IO
     /// in another context. However, when target IO is done in remote offload
     /// context, `io.complete_io(result)` has to be called in the queue/
-    /// io_uring context. One approach is to use eventfd to wakeup & notify
-    /// ublk queue/io_uring. Here, eventfd can be thought as one special target
-    /// IO. Inside IO closure, eventfd is queued by io_uring opcode::PollAdd.
-    /// Once target IO handling is done, write(eventfd) can wakeup/notify ublk
-    /// queue & io_uring, then IO closure can get chance to handle all completed
-    /// IOs. Unfortunately, each IO command(originated from ublk driver) can
-    /// only use its own `UblkIOCtx` to complete itself. But one eventfd is
-    /// often reused for the whole queue, so normally multiple IOs are completed
-    /// when handling single eventfd CQE. Here IO completion batch feature is
-    /// provided, and target code can call `io.add_to_comp_batch()` for each
-    /// completed IO(tag, result) in io closure. Then, all these added IOs will
-    /// be completed automatically.
-    pub fn process_io<F>(&mut self, ops: F) -> Result<i32, UblkError>
+    /// io_uring context. `UblkQueue::completer()` returns one handle which
+    /// can be sent to the offload context, and `UblkCompleter::complete()`
+    /// posts (tag, result) of the handled IO. The queue is woken up by one
+    /// internal eventfd, and all posted IOs are completed in one batch
+    /// without calling IO closure.
+    ///
+    /// Target code can still build its own eventfd, which can be thought
+    /// as one special target IO queued by io_uring opcode::PollAdd inside IO
+    /// closure. Each IO command(originated from ublk driver) can only use its
+    /// own `UblkIOCtx` to complete itself, so IO completion batch feature is
+    /// provided for this case, and target code can call `io.add_to_comp_batch()`
+    /// for each completed IO(tag, result) in io closure. Then, all these added
+    /// IOs will be completed automatically.
+    ///
+    /// If IO closure returns error, the IO command is completed with the
+    /// error's negative errno in case that it isn't completed yet, and the
+    /// error is logged and counted in `UblkQueueStats`. Retrying the closure
+    /// or aborting the queue can be chosen via `UblkQueue::set_error_policy()`.
+    ///
+    /// If busy polling is enabled by `UblkQueue::set_busy_poll()`, CQ is
+    /// polled without sleeping in the window after each completion.
+    ///
+    /// SQ full is handled here too: SQEs which can't be pushed are kept in
+    /// one overflow list, and they are pushed and submitted in order before
+    /// waiting for new completion. If SQ is still full after submitting it
+    /// a few times, the left SQEs are flushed after the wait.
+    ///
+    /// Each call submits all SQEs queued in the previous batch and waits
+    /// for completion in one io_uring_enter(), then handles all ready CQEs
+    /// in one pass, so COMMIT_AND_FETCH_REQ commands of the whole batch
+    /// are submitted together in the next call. The number of handled
+    /// CQEs is returned.
+    pub fn process_io<F>(&mut self, mut ops: F) -> Result<i32, UblkError>
     where
-        F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
     {
-        let to_wait = if self.get_poll() { 0 } else { 1 };
-
-        info!(
+        trace!(
             "dev{}-q{}: to_submit {} inflight cmd {} stopping {}",
             self.dev.dev_info.dev_id,
             self.q_id,
@@ -923,28 +3578,56 @@ impl UblkQueue<'_> {
             (self.q_state & UBLK_QUEUE_STOPPING)
         );
 
-        if self.reap_one_event(ops) > 0 {
-            return Ok(0);
+        // submit the failed IO before aborting queue
+        if self.is_aborted() {
+            self.q_ring.borrow_mut().submit_and_wait(0)?;
+            return Err(UblkError::QueueIsDown("queue is aborted".to_string()));
+        }
+
+        let iopoll_busy = self.q_ring.borrow_mut().submit_iopoll()?;
+        let iopoll_reapped = self.reap_iopoll_events(&mut ops);
+
+        // commit IOs before leaving the loop
+        if self.is_stopped() {
+            self.q_ring.borrow_mut().submit_and_wait(0)?;
         }
 
-        if self.queue_is_done() && self.q_ring.submission().is_empty() {
+        if self.queue_is_done()
+            && self.q_ring.borrow_mut().ring.submission().is_empty()
+            && self.q_ring.borrow().overflow.is_empty()
+            && !iopoll_busy
+        {
             return Err(UblkError::QueueIsDown("queue is done".to_string()));
         }
 
-        let ret = self
-            .q_ring
-            .submit_and_wait(to_wait)
-            .map_err(UblkError::UringSubmissionError)?;
-        let reapped = self.prep_reap_events();
+        // keep polling the IOPOLL ring if there is any in-flight IO
+        let to_wait = if self.get_poll()
+            || iopoll_busy
+            || iopoll_reapped > 0
+            || self.is_stopped()
+            || self.busy_polling()
+        {
+            0
+        } else {
+            1
+        };
+        let wait_start = if to_wait > 0 && self.busy_poll.is_some() {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        let ret = self.q_ring.borrow_mut().submit_and_wait(to_wait)?;
+        let reapped = self.reap_events(&mut ops);
+        self.account_busy_poll(wait_start, reapped);
 
-        info!(
+        trace!(
             "submit result {}, reapped {} stop {} idle {}",
             ret,
             reapped,
             (self.q_state & UBLK_QUEUE_STOPPING),
             (self.q_state & UBLK_QUEUE_IDLE)
         );
-        Ok(reapped as i32)
+        Ok((reapped + iopoll_reapped) as i32)
     }
 
     /// Wait and handle incoming IO
@@ -959,8 +3642,11 @@ impl UblkQueue<'_> {
     #[inline(always)]
     pub fn wait_and_handle_io<F>(&mut self, mut ops: F)
     where
-        F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
+        F: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
     {
+        #[cfg(feature = "tracing")]
+        let _span = self.span.clone().entered();
+
         loop {
             match self.process_io(&mut ops) {
                 Err(_) => break,
@@ -969,3 +3655,485 @@ impl UblkQueue<'_> {
         }
     }
 }
+
+/// Max fixed files of one device served by `UblkEventLoop`, same with
+/// size of `UblkTgt.fds`
+const UBLK_LOOP_DEV_FILES: u32 = 32;
+
+/// How long to wait for SQEs of removed queues being canceled
+const UBLK_LOOP_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const UBLK_LOOP_DRAIN_WAKEUP: Duration = Duration::from_millis(100);
+
+/// One queue served by `UblkEventLoop`
+struct UblkLoopQueue<'a> {
+    queue: UblkQueue<'a>,
+    file_idx: usize,
+}
+
+/// Event loop for serving queues of many ublk devices
+///
+/// `UblkQueue::new()` creates one io_uring for each queue, and each queue
+/// needs one pthread, which isn't efficient for lots of small devices.
+/// UblkEventLoop serves queues of many devices in current pthread with one
+/// shared io_uring:
+///
+/// - slot of the queue is stored in userdata, so each CQE is dispatched
+///   to its queue; target code has to queue SQE via `UblkIOCtx::try_push()`
+///   or `UblkIOCtx::push_async()`, which fill the slot automatically. SQE
+///   pushed to `UblkIOCtx::get_ring()` directly has to take userdata from
+///   `UblkIOCtx::tgt_user_data()`, otherwise its CQE is dropped
+///
+/// - every device owns one range of the fixed file table, so target code
+///   has to use `UblkIOCtx::fixed_fd()` for target IO
+///
+/// - device can be added at any time, and it is removed from the loop by
+///   `remove_dev()` or after all its queues are down, such as the device
+///   is deleted; the device is borrowed by the loop until then
+///
+/// - SQEs of removed queue are canceled, and the queue is freed after all
+///   their CQEs are received; SQEs pushed to `UblkIOCtx::get_ring()`
+///   directly aren't tracked, so target has to complete them before the
+///   queue is removed
+///
+/// - the device has to be started by `UblkCtrl::start_dev_in_loop()`
+///   from the loop pthread, since IO may come before START_DEV completes
+///
+/// The standalone IOPOLL ring isn't supported, and creating the loop or
+/// adding device with `UblkRingConfig::iopoll` fails unless
+/// `UblkRingConfig::fallback` is set. The queue isn't aborted in case of
+/// `UblkErrorPolicy::AbortQueue`, and only the IO is failed.
+pub struct UblkEventLoop<'a> {
+    ring: Rc<RefCell<UblkQueueRing>>,
+    queues: HashMap<u16, UblkLoopQueue<'a>>,
+    next_slot: u16,
+
+    /// (dev_id, nr_live_queues) of device which owns this range of fixed
+    /// file table
+    files: Vec<Option<(u32, u16)>>,
+
+    /// removed queues whose SQEs are being canceled
+    zombies: HashMap<u16, UblkQueue<'a>>,
+
+    /// timespec of wakeup timeout for draining zombie queues
+    wakeup_ts: Box<types::Timespec>,
+
+    /// poll on fd pushed by `process_io_until_readable()` is pending
+    fd_polling: bool,
+}
+
+impl<'a> UblkEventLoop<'a> {
+    /// New one event loop
+    ///
+    /// # Arguments:
+    ///
+    /// * `cfg`: io_uring setup options of the shared ring
+    /// * `sq_depth`: SQ size of the shared ring
+    /// * `cq_depth`: CQ size of the shared ring
+    /// * `max_devs`: max number of devices served by this loop
+    pub fn new(
+        cfg: UblkRingConfig,
+        sq_depth: u32,
+        cq_depth: u32,
+        max_devs: u32,
+    ) -> Result<UblkEventLoop<'a>, UblkError> {
+        let nr_files = max_devs * UBLK_LOOP_DEV_FILES;
+        let ring = UblkQueueRing::new_shared(cfg, sq_depth, cq_depth, nr_files)?;
+
+        Ok(UblkEventLoop {
+            ring: Rc::new(RefCell::new(ring)),
+            queues: HashMap::new(),
+            next_slot: 1,
+            files: vec![None; max_devs as usize],
+            zombies: HashMap::new(),
+            wakeup_ts: Box::new(types::Timespec::from(UBLK_LOOP_DRAIN_WAKEUP)),
+            fd_polling: false,
+        })
+    }
+
+    /// Return the applied io_uring setup options of the shared ring
+    pub fn get_ring_config(&self) -> UblkRingConfig {
+        self.ring.borrow().get_config().clone()
+    }
+
+    /// How many devices are served by this loop
+    pub fn nr_devs(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    fn alloc_slot(&mut self) -> u16 {
+        // slot isn't reused soon, so CQE of removed queue won't be
+        // dispatched to new queue; slot 0 is reserved for userdata
+        // without slot
+        loop {
+            let slot = self.next_slot;
+
+            self.next_slot = self.next_slot.wrapping_add(1);
+            if slot != 0 && !self.queues.contains_key(&slot) && !self.zombies.contains_key(&slot) {
+                return slot;
+            }
+        }
+    }
+
+    /// Release the fixed file range if all queues of the device are gone
+    fn put_files(&mut self, idx: usize, nr_queues: u16) {
+        if let Some((dev_id, nr)) = self.files[idx].as_mut() {
+            *nr -= nr_queues;
+            if *nr == 0 {
+                let fds = [-1_i32; UBLK_LOOP_DEV_FILES as usize];
+                let base = idx as u32 * UBLK_LOOP_DEV_FILES;
+
+                if let Err(r) = self
+                    .ring
+                    .borrow()
+                    .ring
+                    .submitter()
+                    .register_files_update(base, &fds)
+                {
+                    error!("dev {} unregister fixed files failed {}", dev_id, r);
+                }
+                info!("dev {} removed from event loop", dev_id);
+                self.files[idx] = None;
+            }
+        }
+    }
+
+    /// Add all queues of `dev` to this loop
+    ///
+    /// FETCH_REQ commands are submitted before returning, then the device
+    /// can be started by `UblkCtrl::start_dev_in_loop()` from this pthread.
+    pub fn add_dev(&mut self, dev: &'a UblkDev) -> Result<(), UblkError> {
+        let dev_id = dev.dev_info.dev_id;
+        let nr_queues = dev.dev_info.nr_hw_queues;
+
+        if dev.tgt.ring.iopoll {
+            if !dev.tgt.ring.fallback {
+                return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+            }
+            warn!("dev {} IOPOLL ring isn't supported in event loop", dev_id);
+        }
+        if self.files.iter().flatten().any(|(id, _)| *id == dev_id) {
+            return Err(UblkError::OtherError(-libc::EEXIST));
+        }
+        let idx = match self.files.iter().position(|f| f.is_none()) {
+            Some(idx) if self.queues.len() + (nr_queues as usize) <= u16::MAX as usize => idx,
+            _ => return Err(UblkError::OtherError(-libc::ENOSPC)),
+        };
+
+        let fd_base = idx as u32 * UBLK_LOOP_DEV_FILES;
+        let fds = &dev.tgt_fds()?;
+        self.ring
+            .borrow()
+            .ring
+            .submitter()
+            .register_files_update(fd_base, fds)
+            .map_err(UblkError::OtherIOError)?;
+        self.files[idx] = Some((dev_id, nr_queues));
+
+        let mut slots = Vec::new();
+        for q_id in 0..nr_queues {
+            let mut queue = match UblkQueue::<()>::__new(q_id, dev, Rc::clone(&self.ring), true) {
+                Ok(q) => q,
+                Err(e) => {
+                    // nothing is queued to ring yet
+                    for slot in slots {
+                        self.queues.remove(&slot);
+                    }
+                    self.put_files(idx, nr_queues);
+                    return Err(e);
+                }
+            };
+            let slot = self.alloc_slot();
+
+            queue.ud_slot = (slot as u64) << UBLK_USER_DATA_SLOT_SHIFT;
+            queue.fd_base = fd_base;
+            self.queues.insert(
+                slot,
+                UblkLoopQueue {
+                    queue,
+                    file_idx: idx,
+                },
+            );
+            slots.push(slot);
+        }
+
+        for slot in slots {
+            if let Some(q) = self.queues.get_mut(&slot) {
+                q.queue.submit_fetch_commands();
+            }
+        }
+        self.ring.borrow_mut().submit_and_wait(0)?;
+        info!("dev {} added to event loop", dev_id);
+
+        Ok(())
+    }
+
+    /// Return one handle for completing IO of queue `q_id` of device
+    /// `dev_id` from other context, see `UblkQueue::completer()`
+    pub fn completer(&mut self, dev_id: u32, q_id: u16) -> Result<UblkCompleter, UblkError> {
+        match self
+            .queues
+            .values_mut()
+            .find(|q| q.queue.dev.dev_info.dev_id == dev_id && q.queue.q_id == q_id)
+        {
+            Some(q) => q.queue.completer(),
+            None => Err(UblkError::OtherError(-libc::ENOENT)),
+        }
+    }
+
+    /// Remove device `dev_id` from this loop
+    ///
+    /// Same with `UblkQueueStopHandle::stop()`, queues of the device keep
+    /// handling IO by `ops` until no IO command is owned by target, and IO
+    /// of other devices is handled meantime. Then the queues are dropped,
+    /// and fixed files of the device are released. IO of the device coming
+    /// after this call isn't handled, and it is left to the driver.
+    ///
+    /// IO commands and target SQEs of the removed queues are canceled, and
+    /// this method waits a while for their completion, so the device needs
+    /// to be recovered or deleted for serving IO again.
+    ///
+    /// The device isn't stopped or deleted, which can be done by `UblkCtrl`
+    /// after this method returns.
+    pub fn remove_dev<F>(&mut self, dev_id: u32, mut ops: F) -> Result<(), UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        let mut nr = 0;
+
+        for q in self.queues.values_mut() {
+            if q.queue.dev.dev_info.dev_id == dev_id {
+                q.queue.q_state |= UBLK_QUEUE_EXIT;
+                nr += 1;
+            }
+        }
+        if nr == 0 {
+            return Err(UblkError::OtherError(-libc::ENOENT));
+        }
+
+        self.remove_done_queues();
+        while self
+            .queues
+            .values()
+            .any(|q| q.queue.dev.dev_info.dev_id == dev_id)
+        {
+            self.__process_io(&mut ops, 1)?;
+        }
+
+        self.drain_zombies(Some(dev_id), &mut ops)
+    }
+
+    /// Wait for completion of canceled SQEs of removed queues of `dev_id`,
+    /// or of all devices if `dev_id` is None
+    ///
+    /// SQE which can't be canceled may never complete, so the wait is
+    /// bounded, and the left zombie queue is freed by `__reap_events()`
+    /// once its SQEs are done.
+    fn drain_zombies<F>(&mut self, dev_id: Option<u32>, mut ops: F) -> Result<(), UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        let deadline = Instant::now() + UBLK_LOOP_DRAIN_TIMEOUT;
+
+        while self
+            .zombies
+            .values()
+            .any(|q| dev_id.is_none() || dev_id == Some(q.dev.dev_info.dev_id))
+        {
+            if Instant::now() >= deadline {
+                warn!("event loop: SQEs of removed queues aren't completed in time");
+                break;
+            }
+
+            // wake up periodically for checking the deadline
+            let sqe = opcode::Timeout::new(&*self.wakeup_ts)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_WAKEUP, 0));
+            {
+                let mut ring = self.ring.borrow_mut();
+
+                ring.set_owner(0, 0);
+                unsafe { ring.push_async(sqe) };
+            }
+            self.__reap_events(&mut ops, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Drop queue which is removed from loop if none of its SQEs is
+    /// in-flight, otherwise cancel these SQEs and keep the queue until
+    /// they are completed, since kernel may still use its buffers
+    fn retire_queue(&mut self, slot: u16, queue: UblkQueue<'a>) {
+        if self.ring.borrow_mut().cancel_slot(slot) {
+            self.zombies.insert(slot, queue);
+        } else {
+            drop(queue);
+        }
+    }
+
+    /// Drop queues which are down, and release their fixed files
+    fn remove_done_queues(&mut self) {
+        let done: Vec<u16> = self
+            .queues
+            .iter()
+            .filter(|(_, q)| q.queue.queue_is_done())
+            .map(|(slot, _)| *slot)
+            .collect();
+        for slot in done {
+            if let Some(q) = self.queues.remove(&slot) {
+                let idx = q.file_idx;
+
+                self.retire_queue(slot, q.queue);
+                self.put_files(idx, 1);
+            }
+        }
+
+        let ring = self.ring.borrow();
+        let done: Vec<u16> = self
+            .zombies
+            .keys()
+            .filter(|slot| !ring.slot_is_busy(**slot))
+            .copied()
+            .collect();
+        drop(ring);
+        for slot in done {
+            self.zombies.remove(&slot);
+        }
+    }
+
+    pub(crate) fn __process_io<F>(&mut self, ops: F, to_wait: usize) -> Result<i32, UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        if self.queues.is_empty() {
+            return Err(UblkError::QueueIsDown("no device in loop".to_string()));
+        }
+        self.__reap_events(ops, to_wait)
+    }
+
+    fn __reap_events<F>(&mut self, mut ops: F, to_wait: usize) -> Result<i32, UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        self.ring.borrow_mut().submit_and_wait(to_wait)?;
+        let cqes: Vec<cqueue::Entry> = self.ring.borrow_mut().ring.completion().collect();
+        let cnt = cqes.len();
+
+        {
+            let mut ring = self.ring.borrow_mut();
+
+            for cqe in cqes.iter().filter(|cqe| !cqueue::more(cqe.flags())) {
+                ring.put_slot_sqe(cqe.user_data());
+            }
+        }
+
+        for (idx, cqe) in cqes.iter().enumerate() {
+            let data = cqe.user_data();
+            let slot = user_data_to_slot(data);
+
+            // CQE of removed queue is ignored
+            if let Some(q) = self.queues.get_mut(&slot) {
+                let ctx = q.queue.make_queue_ctx();
+
+                q.queue
+                    .handle_event(|io: &mut UblkIOCtx| ops(&ctx, io), cqe, idx, cnt);
+            } else if slot != 0 {
+                continue;
+            } else if !is_internal_io(data) {
+                error!("event loop: CQE {:x} without queue slot is dropped", data);
+            } else if UblkIOCtx::user_data_to_op(data) == UBLK_INTERNAL_OP_POLL_FD {
+                self.fd_polling = false;
+            }
+        }
+
+        self.remove_done_queues();
+        Ok(cnt as i32)
+    }
+
+    /// Handle IO until `fd` becomes readable, such as control ring of the
+    /// device being started from this loop
+    pub(crate) fn process_io_until_readable<F>(
+        &mut self,
+        fd: RawFd,
+        ops: F,
+    ) -> Result<i32, UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        if !self.fd_polling {
+            let sqe = opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as _)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_POLL_FD, 0));
+            let mut ring = self.ring.borrow_mut();
+
+            ring.set_owner(0, 0);
+            unsafe { ring.push_async(sqe) };
+            self.fd_polling = true;
+        }
+        self.__process_io(ops, 1)
+    }
+
+    /// Cancel the poll pushed by `process_io_until_readable()`
+    pub(crate) fn cancel_fd_poll(&mut self) -> Result<(), UblkError> {
+        if self.fd_polling {
+            let data = build_internal_user_data(0, UBLK_INTERNAL_OP_POLL_FD, 0);
+            let sqe = opcode::AsyncCancel::new(data)
+                .build()
+                .user_data(build_internal_user_data(0, UBLK_INTERNAL_OP_CANCEL, 0));
+            let mut ring = self.ring.borrow_mut();
+
+            ring.set_owner(0, 0);
+            unsafe { ring.push_async(sqe) };
+            ring.submit_and_wait(0)?;
+        }
+        Ok(())
+    }
+
+    /// Process the incoming IO of all devices from the shared io_uring
+    ///
+    /// # Arguments:
+    ///
+    /// * `ops`: IO handling closure, which is called with `UblkQueueCtx` of
+    ///   the queue the IO belongs to, and `UblkQueueCtx::dev_id` tells which
+    ///   device it is
+    ///
+    /// Queues which are down are removed after handling all reaped CQEs,
+    /// and `UblkError::QueueIsDown` is returned if there isn't any device.
+    pub fn process_io<F>(&mut self, ops: F) -> Result<i32, UblkError>
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        self.__process_io(ops, 1)
+    }
+
+    /// Wait and handle incoming IO until all devices are removed
+    pub fn wait_and_handle_io<F>(&mut self, mut ops: F)
+    where
+        F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+    {
+        while self.process_io(&mut ops).is_ok() {}
+    }
+}
+
+impl Drop for UblkEventLoop<'_> {
+    fn drop(&mut self) {
+        let slots: Vec<u16> = self.queues.keys().copied().collect();
+
+        for slot in slots {
+            if let Some(q) = self.queues.remove(&slot) {
+                self.retire_queue(slot, q.queue);
+            }
+        }
+        if let Err(r) = self.drain_zombies(None, |_, _| Ok(0)) {
+            error!("event loop: drain removed queues failed {}", r);
+        }
+
+        // kernel may still use buffers of queue whose SQEs aren't done
+        for (_, q) in self.zombies.drain() {
+            error!(
+                "event loop: dev {} queue {} is leaked",
+                q.dev.dev_info.dev_id, q.q_id
+            );
+            std::mem::forget(q);
+        }
+    }
+}
@@ -5,13 +5,19 @@
 //! and introduction doc in
 //! `<https://github.com/ming1/ubdsrv/blob/master/doc/ublk_intro.pdf>`
 
-use log::error;
+use log::{error, info};
 use std::alloc::{alloc, dealloc, Layout};
-use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
 
+pub mod config;
+pub mod control;
 pub mod ctrl;
+pub mod daemon;
 pub mod io;
+pub mod manager;
 pub mod sys;
+pub mod upgrade;
 
 /// feature: support IO batch completion from single IO tag, typical
 /// usecase is to complete IOs from eventfd CQE handler
@@ -25,6 +31,12 @@ pub const UBLK_DEV_F_RECOVER_DEV: u32 = 1u32 << 2;
 
 const UBLK_DEV_F_ALL: u32 = UBLK_DEV_F_COMP_BATCH | UBLK_DEV_F_ADD_DEV | UBLK_DEV_F_RECOVER_DEV;
 
+/// how long `get_stats()` waits for queues to publish one stats snapshot
+const UBLK_STATS_REFRESH_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// signalfd shared by all sessions handling signals in this process
+static SIGNAL_FD: Mutex<Option<Arc<io::UblkSignalFd>>> = Mutex::new(None);
+
 #[derive(thiserror::Error, Debug)]
 pub enum UblkError {
     #[error("failed to read the key file")]
@@ -50,6 +62,37 @@ pub enum UblkError {
 
     #[error("other failure")]
     OtherError(i32),
+
+    #[error("queue thread panicked")]
+    QueuePanic(String),
+
+    #[error("invalid config")]
+    ConfigError(String),
+
+    #[error("control request failure")]
+    RpcError(i32, String),
+}
+
+impl UblkError {
+    /// Convert this error into negative errno, which can be used as
+    /// result of ublk IO command
+    pub fn to_errno(&self) -> i32 {
+        let errno = match self {
+            UblkError::UringIOError(e) | UblkError::OtherError(e) => *e,
+            UblkError::RpcError(e, _) if (-4095..0).contains(e) => *e,
+            UblkError::UringSubmissionError(e) | UblkError::OtherIOError(e) => {
+                e.raw_os_error().unwrap_or(libc::EIO)
+            }
+            UblkError::UringPushError(_) => libc::EAGAIN,
+            _ => libc::EIO,
+        };
+
+        match errno {
+            0 => -libc::EIO,
+            e if e > 0 => -e,
+            e => e,
+        }
+    }
 }
 
 pub const CDEV_PATH: &str = "/dev/ublkc";
@@ -78,11 +121,14 @@ extern crate derive_builder;
 ///
 /// High level API.
 ///
-/// One limit is that IO handling closure doesn't support FnMut, and low
-/// level API doesn't have such limit.
+/// IO handling closure passed to `run()` can't be FnMut, and FnMut
+/// closure of each queue can be created by `run_with_factory()`.
+///
+/// `start()` and `start_with_factory()` return `UblkDeviceHandle`
+/// without blocking, for embedding and managing devices programmatically.
 ///
-#[derive(Default, Builder, Debug)]
-#[builder(setter(into))]
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into), build_fn(private, name = "__build"))]
 #[allow(dead_code)]
 pub struct UblkSession {
     /// target type, such as null, loop, ramdisk, or nbd,...
@@ -116,6 +162,141 @@ pub struct UblkSession {
     /// libublk feature flags: UBLK_DEV_F_*
     #[builder(default = "0")]
     dev_flags: u32,
+
+    /// how to handle error returned from IO handling closure
+    #[builder(default)]
+    err_policy: io::UblkErrorPolicy,
+
+    /// how to handle panic from IO handling closure; if `ctrl_flags`
+    /// includes `UBLK_F_USER_RECOVERY`, queues aborted by panic are
+    /// recovered in-process
+    #[builder(default)]
+    panic_policy: io::UblkPanicPolicy,
+
+    /// how to set CPU affinity of each queue pthread, and the applied
+    /// affinity is recorded in the exported json file
+    #[builder(default)]
+    affinity_policy: ctrl::UblkAffinityPolicy,
+
+    /// busy polling window in microseconds after each completion, and
+    /// busy polling is disabled if it is zero
+    #[builder(default = "0")]
+    busy_poll_us: u32,
+
+    /// queue enters idle state and releases io buffers if there isn't any
+    /// IO in this milliseconds, and idle detection is disabled if it is zero
+    #[builder(default = "0")]
+    idle_timeout_ms: u32,
+
+    /// interval in milliseconds for publishing queue stats periodically,
+    /// which is disabled by default so idle queues aren't woken up;
+    /// `get_stats()` and the control `stats` method ask queues for one
+    /// snapshot anyway
+    #[builder(default = "0")]
+    stats_interval_ms: u32,
+
+    /// dump device stats into the exported json file every
+    /// `stats_interval_ms`
+    #[builder(default)]
+    stats_json: bool,
+
+    /// handle SIGTERM/SIGINT, SIGHUP and SIGUSR1 via signalfd polled by
+    /// queue 0, see `run()`; these signals are blocked in the pthread
+    /// building the session only, so it should be built before creating
+    /// other pthreads, which inherit the signal mask. Pthreads created
+    /// earlier, such as libtest harness or tokio runtime pthreads, still
+    /// take these signals with default disposition, which kills the
+    /// process
+    #[builder(default)]
+    handle_signals: bool,
+
+    #[builder(setter(skip))]
+    stats: Arc<io::UblkDevStats>,
+
+    /// unix socket path for serving control requests, see `control`
+    #[builder(default, setter(strip_option))]
+    control_socket: Option<String>,
+
+    #[builder(setter(skip))]
+    reload_hook: UblkHook<UblkReloadFn>,
+
+    #[builder(setter(skip))]
+    tgt_cmd_hook: UblkHook<UblkTgtCmdFn>,
+
+    #[builder(setter(skip))]
+    handover_hook: UblkHook<UblkHandoverFn>,
+}
+
+/// Target reload hook, called when SIGHUP is received
+pub type UblkReloadFn = dyn Fn(&mut ctrl::UblkCtrl) -> Result<i32, UblkError> + Send + Sync;
+
+/// Target command hook, called with command name and its arguments for
+/// target specific control request, such as snapshot or flush
+pub type UblkTgtCmdFn =
+    dyn Fn(&str, &serde_json::Value) -> Result<serde_json::Value, UblkError> + Send + Sync;
+
+/// Target handover hook, which returns target state for the new process,
+/// see `upgrade`
+pub type UblkHandoverFn =
+    dyn Fn(&mut ctrl::UblkCtrl) -> Result<serde_json::Value, UblkError> + Send + Sync;
+
+struct UblkHook<F: ?Sized>(Option<Arc<F>>);
+
+impl<F: ?Sized> Clone for UblkHook<F> {
+    fn clone(&self) -> Self {
+        UblkHook(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> Default for UblkHook<F> {
+    fn default() -> Self {
+        UblkHook(None)
+    }
+}
+
+impl<F: ?Sized> std::fmt::Debug for UblkHook<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("UblkHook").field(&self.0.is_some()).finish()
+    }
+}
+
+impl UblkSessionBuilder {
+    /// Build the session
+    ///
+    /// If `handle_signals` is set, the signals are blocked in the calling
+    /// pthread before any pthread is spawned by the session. Only the
+    /// calling pthread is affected, and pthreads created before, such as
+    /// libtest harness or tokio runtime pthreads, still take these signals
+    /// with default disposition, which kills the process.
+    pub fn build(&self) -> Result<UblkSession, UblkSessionBuilderError> {
+        let sess = self.__build()?;
+
+        if sess.handle_signals {
+            signal_fd()
+                .and_then(|sfd| sfd.block())
+                .map_err(|e| UblkSessionBuilderError::ValidationError(e.to_string()))?;
+        }
+        Ok(sess)
+    }
+}
+
+/// Return the process-wide signalfd for signals handled by session
+fn signal_fd() -> Result<Arc<io::UblkSignalFd>, UblkError> {
+    let mut sfd = SIGNAL_FD.lock().unwrap_or_else(|e| e.into_inner());
+
+    match sfd.as_ref() {
+        Some(s) => Ok(s.clone()),
+        None => {
+            let s = Arc::new(io::UblkSignalFd::new(&[
+                libc::SIGTERM,
+                libc::SIGINT,
+                libc::SIGHUP,
+                libc::SIGUSR1,
+            ])?);
+            *sfd = Some(s.clone());
+            Ok(s)
+        }
+    }
 }
 
 impl UblkSession {
@@ -142,75 +323,275 @@ impl UblkSession {
         Ok((ctrl, dev))
     }
 
-    fn create_queue_handlers<Q>(
-        &self,
-        ctrl: &mut ctrl::UblkCtrl,
-        dev: &Arc<io::UblkDev>,
-        q_fn: Q,
-    ) -> Vec<std::thread::JoinHandle<()>>
+    /// Set target reload hook, which is called in the session's monitor
+    /// context when SIGHUP is received if `handle_signals` is set
+    pub fn set_reload_hook<R>(&mut self, hook: R)
     where
-        Q: Fn(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError>
+        R: Fn(&mut ctrl::UblkCtrl) -> Result<i32, UblkError> + Send + Sync + 'static,
+    {
+        self.reload_hook = UblkHook(Some(Arc::new(hook)));
+    }
+
+    /// Set target command hook, which handles `target` request from the
+    /// control socket
+    pub fn set_target_cmd_hook<C>(&mut self, hook: C)
+    where
+        C: Fn(&str, &serde_json::Value) -> Result<serde_json::Value, UblkError>
             + Send
             + Sync
-            + Clone
             + 'static,
+    {
+        self.tgt_cmd_hook = UblkHook(Some(Arc::new(hook)));
+    }
+
+    /// Set target handover hook, which is called in the session's monitor
+    /// context before stopping queues for handover, and the returned
+    /// target state is written into the exported json and sent to the new
+    /// process, see `upgrade`
+    pub fn set_handover_hook<O>(&mut self, hook: O)
+    where
+        O: Fn(&mut ctrl::UblkCtrl) -> Result<serde_json::Value, UblkError> + Send + Sync + 'static,
+    {
+        self.handover_hook = UblkHook(Some(Arc::new(hook)));
+    }
+
+    /// Handle target command from the control socket
+    pub(crate) fn target_cmd(
+        &self,
+        cmd: &str,
+        args: &serde_json::Value,
+    ) -> Result<serde_json::Value, UblkError> {
+        match self.tgt_cmd_hook.0.as_ref() {
+            Some(hook) => hook(cmd, args),
+            None => Err(UblkError::OtherError(-libc::EOPNOTSUPP)),
+        }
+    }
+
+    fn stats_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.stats_interval_ms as u64)
+    }
+
+    /// Return stats snapshot of this device, aggregated from all queues
+    ///
+    /// Queues are asked to publish their stats first, and the last
+    /// published stats is returned for queue which doesn't answer in
+    /// `UBLK_STATS_REFRESH_TIMEOUT`, such as one being busy in IO closure; so
+    /// don't call it from queue pthread, which can't answer it.
+    pub fn get_stats(&self) -> io::UblkQueueStats {
+        self.stats.refresh(UBLK_STATS_REFRESH_TIMEOUT);
+        self.stats.get_stats()
+    }
+
+    /// Return stats snapshot of each queue, indexed by queue id
+    pub fn get_queues_stats(&self) -> Vec<io::UblkQueueStats> {
+        self.stats.refresh(UBLK_STATS_REFRESH_TIMEOUT);
+        self.stats.get_queues_stats()
+    }
+
+    /// Write device stats into the exported json file if it still exists
+    fn dump_stats_json(&self, ctrl: &mut ctrl::UblkCtrl) {
+        if !std::path::Path::new(&ctrl.run_path()).exists() {
+            return;
+        }
+
+        match serde_json::to_value(self.get_stats()) {
+            Ok(v) => ctrl.json["stats"] = v,
+            Err(_) => return,
+        }
+        if let Err(e) = ctrl.flush_json() {
+            error!("dev-{} dump stats failed {:?}", ctrl.dev_info.dev_id, e);
+        }
+    }
+
+    fn create_queue_handlers<F, H>(
+        &self,
+        ctrl: &mut ctrl::UblkCtrl,
+        dev: &Arc<io::UblkDev>,
+        shared: &UblkQueueShared<F>,
+    ) -> Result<Vec<UblkQueueJoinHandle>, UblkError>
+    where
+        F: Fn(u16) -> H + Send + Sync + 'static,
+        H: FnMut(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError> + 'static,
     {
         use std::sync::mpsc;
 
         let mut q_threads = Vec::new();
         let nr_queues = dev.dev_info.nr_hw_queues;
 
+        let mut affinities = Vec::new();
+        for q in 0..nr_queues {
+            affinities.push(self.affinity_policy.queue_affinity(ctrl, q)?);
+        }
+
         let (tx, rx) = mpsc::channel();
 
-        for q in 0..nr_queues {
+        for (q, affinity) in (0..nr_queues).zip(affinities) {
             let _dev = Arc::clone(dev);
             let _tx = tx.clone();
 
-            let mut affinity = ctrl::UblkQueueAffinity::new();
-            ctrl.get_queue_affinity(q as u32, &mut affinity).unwrap();
-            let _q_fn = q_fn.clone();
+            let _q_fn = Arc::clone(&shared.factory);
+            let err_policy = self.err_policy;
+            let panic_policy = self.panic_policy;
+            let busy_poll = Some(std::time::Duration::from_micros(self.busy_poll_us as u64))
+                .filter(|w| !w.is_zero());
+            let idle_timeout = Some(std::time::Duration::from_millis(
+                self.idle_timeout_ms as u64,
+            ))
+            .filter(|t| !t.is_zero());
+            let stats = self.stats.clone();
+            let stats_interval = self.stats_interval();
+            let ev_tx = shared.ev_tx.clone();
+            let signal_fd = shared
+                .signal_sub
+                .as_ref()
+                .filter(|_| q == 0)
+                .map(|sub| sub.signal_fd());
 
             q_threads.push(std::thread::spawn(move || {
                 //setup pthread affinity first, so that any allocation may
                 //be affine to cpu/memory
-                unsafe {
-                    libc::pthread_setaffinity_np(
-                        libc::pthread_self(),
-                        affinity.buf_len(),
-                        affinity.addr() as *const libc::cpu_set_t,
-                    );
+                if let Some(affinity) = affinity {
+                    if let Err(e) = affinity.apply() {
+                        error!("queue {} set affinity failed {:?}", q, e);
+                    }
                 }
-                _tx.send((q, unsafe { libc::gettid() })).unwrap();
-
-                let mut queue = io::UblkQueue::new(q, &_dev).unwrap();
-                let queue_closure = {
-                    let ctx = queue.make_queue_ctx();
-                    move |io_ctx: &mut io::UblkIOCtx| _q_fn(&ctx, io_ctx)
-                };
-                queue.wait_and_handle_io(queue_closure);
+                let tid = unsafe { libc::gettid() };
+                let applied = ctrl::UblkQueueAffinity::from_current_thread().unwrap_or_default();
+
+                // the pthread may panic outside of IO closure too, and
+                // it has to be reported for recovering the queue
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let mut queue = match io::UblkQueue::new(q, &_dev)
+                        .and_then(|mut queue| {
+                            queue
+                                .set_stats_publisher(stats, stats_interval)
+                                .map(|_| queue)
+                        })
+                        .and_then(|mut queue| queue.stop_handle().map(|h| (queue, h)))
+                    {
+                        Ok((queue, h)) => {
+                            let _ = _tx.send((q, tid, applied, Ok(h)));
+                            queue
+                        }
+                        Err(e) => {
+                            let _ = _tx.send((q, tid, applied, Err(e)));
+                            return Err(UblkError::QueueIsDown(format!(
+                                "queue {} setup failed",
+                                q
+                            )));
+                        }
+                    };
+                    queue.set_error_policy(err_policy);
+                    queue.set_panic_policy(panic_policy);
+                    queue.set_busy_poll(busy_poll);
+                    queue.set_idle_timeout(idle_timeout);
+                    queue.set_event_sender(ev_tx.clone());
+                    if let Some(sfd) = signal_fd {
+                        queue.set_signal_fd(sfd);
+                    }
+                    let queue_closure = {
+                        let ctx = queue.make_queue_ctx();
+                        let mut handler = _q_fn(q);
+                        move |io_ctx: &mut io::UblkIOCtx| handler(&ctx, io_ctx)
+                    };
+                    queue.wait_and_handle_io(queue_closure);
+                    if queue.is_aborted() && queue.get_stats().panics > 0 {
+                        Err(UblkError::QueuePanic(format!(
+                            "queue {} is aborted by panic",
+                            q
+                        )))
+                    } else {
+                        Ok(())
+                    }
+                }));
+                let res = res.unwrap_or_else(|p| Err(UblkError::QueuePanic(io::panic_msg(&*p))));
+                let panicked = matches!(res, Err(UblkError::QueuePanic(_)));
+                let _ = ev_tx.send(io::UblkQueueEvent::Exit { q_id: q, panicked });
+                res
             }));
         }
+        drop(tx);
 
+        let mut stop_handles = Vec::new();
+        let mut res = Ok(());
         for _q in 0..nr_queues {
-            let (qid, tid) = rx.recv().unwrap();
-            if ctrl.configure_queue(dev, qid, tid).is_err() {
-                println!(
-                    "create_queue_handler: configure queue failed for {}-{}",
-                    dev.dev_info.dev_id, qid
-                );
+            match rx.recv() {
+                Ok((qid, tid, applied, Ok(h))) => {
+                    stop_handles.push(h);
+                    ctrl.store_queue_affinity(qid, applied);
+                    if res.is_ok() {
+                        res = ctrl.configure_queue(dev, qid, tid).map(|_| ());
+                    }
+                }
+                Ok((_, _, _, Err(e))) => {
+                    if res.is_ok() {
+                        res = Err(e);
+                    }
+                }
+                Err(_) => {
+                    if res.is_ok() {
+                        res = Err(UblkError::QueuePanic("queue setup panicked".to_string()));
+                    }
+                    break;
+                }
             }
         }
 
-        q_threads
+        if let Err(e) = res {
+            error!(
+                "dev-{} create queue handlers failed: {:?}",
+                dev.dev_info.dev_id, e
+            );
+            for h in stop_handles {
+                let _ = h.stop();
+            }
+            for qh in q_threads {
+                let _ = qh.join();
+            }
+            return Err(e);
+        }
+
+        *shared
+            .stop_handles
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = stop_handles;
+        Ok(q_threads)
     }
 
     /// Kick off the ublk device, and `/dev/ublkbN` will be created and visible
     /// to userspace.
     ///
-    /// So far, IO handling closure doesn't support FnMut, and please switch to
-    /// low level APIs if your IO handling closure needs to be FnMut.
+    /// `io_closure` is shared by all queues, so it can't be FnMut; please
+    /// switch to `run_with_factory()` if IO handling closure needs to be
+    /// FnMut.
     ///
     /// This function won't return until the device is removed.
+    ///
+    /// Panic from IO handling closure is handled by `panic_policy`. If any
+    /// queue is aborted by panic and `UBLK_F_USER_RECOVERY` is set, ublk
+    /// driver quiesces the device and cancels IO commands of all queues,
+    /// then all queues are re-created in this process after every queue
+    /// pthread exits, and the device becomes live again. If the in-process
+    /// recovery fails, the device is kept quiesced instead of being
+    /// deleted, so it can be recovered later by `UblkManager::recover()`
+    /// or new process. Without `UBLK_F_USER_RECOVERY`, ublk driver removes
+    /// the device instead.
+    ///
+    /// If `handle_signals` is set, SIGTERM, SIGINT, SIGHUP and SIGUSR1 are
+    /// blocked in the pthread building the session and the calling
+    /// pthread, so the session should be built before creating other
+    /// pthreads, which inherit the signal mask; otherwise the signal may
+    /// be delivered to one pthread without the mask and kill the process.
+    /// These signals are read from one signalfd shared by all devices in
+    /// this process, which is polled in queue 0's ring of each device, so
+    /// IO handling isn't interrupted, and every device handles the signal:
+    ///
+    /// - SIGTERM/SIGINT: stop the device after inflight IOs are drained,
+    ///   then delete it
+    ///
+    /// - SIGHUP: call the hook set by `set_reload_hook()`
+    ///
+    /// - SIGUSR1: print device stats, and write them to the exported json
     pub fn run<Q, W>(
         &self,
         ctrl: &mut ctrl::UblkCtrl,
@@ -226,23 +607,470 @@ impl UblkSession {
             + 'static,
         W: Fn(i32) + Send + Sync + 'static,
     {
-        let handles = self.create_queue_handlers(ctrl, dev, io_closure);
+        self.run_with_factory(ctrl, dev, move |_| io_closure.clone(), worker_fn)
+    }
 
-        ctrl.start_dev(dev)?;
+    /// Kick off the ublk device like `run()`, and IO handling closure of
+    /// each queue is created by `factory`
+    ///
+    /// `factory` is called with queue id in the queue pthread after its
+    /// affinity is set, so the returned FnMut closure can own per-queue
+    /// state, which is allocated NUMA-locally and needn't any lock.
+    /// `factory` is called again for re-created queues in case of
+    /// in-process recovery.
+    pub fn run_with_factory<F, H, W>(
+        &self,
+        ctrl: &mut ctrl::UblkCtrl,
+        dev: &Arc<io::UblkDev>,
+        factory: F,
+        worker_fn: W,
+    ) -> Result<std::thread::JoinHandle<()>, UblkError>
+    where
+        F: Fn(u16) -> H + Send + Sync + 'static,
+        H: FnMut(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError> + 'static,
+        W: Fn(i32) + Send + Sync + 'static,
+    {
+        let (ev_tx, ev_rx) = std::sync::mpsc::channel();
+        let signal_sub = self.setup_signals(&ev_tx)?;
+        let shared = UblkQueueShared {
+            factory: Arc::new(factory),
+            ev_tx,
+            signal_sub,
+            stop_handles: Mutex::new(Vec::new()),
+        };
+        let control = self.start_control_server(ctrl, &shared.ev_tx)?;
+        let handles = self.create_queue_handlers(ctrl, dev, &shared)?;
+
+        if let Err(e) = ctrl.start_dev(dev) {
+            let _ = ctrl.stop_dev(dev);
+            for qh in handles {
+                let _ = qh.join();
+            }
+            return Err(e);
+        }
 
         let dev_id = dev.dev_info.dev_id as i32;
         let worker_qh = std::thread::spawn(move || {
             worker_fn(dev_id);
         });
 
-        for qh in handles {
-            qh.join().unwrap_or_else(|_| {
-                eprintln!("dev-{} join queue thread failed", dev.dev_info.dev_id)
-            });
+        for (q, res) in self
+            .monitor_queues(ctrl, dev, &shared, handles, ev_rx, control)?
+            .iter()
+            .enumerate()
+        {
+            if let Err(e) = res {
+                error!("dev-{} queue {} failed: {:?}", dev_id, q, e);
+            }
+        }
+
+        Ok(worker_qh)
+    }
+
+    /// Kick off the ublk device like `run()`, but return immediately
+    ///
+    /// All queues are created and the device is started before returning,
+    /// and `ctrl` and `dev` are moved to one monitor pthread, which handles
+    /// queue recovery and stats dump. The returned `UblkDeviceHandle` can
+    /// stop the device, and wait for the result of each queue.
+    pub fn start<Q>(
+        &self,
+        ctrl: ctrl::UblkCtrl,
+        dev: Arc<io::UblkDev>,
+        io_closure: Q,
+    ) -> Result<UblkDeviceHandle, UblkError>
+    where
+        Q: Fn(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError>
+            + Send
+            + Sync
+            + Clone
+            + 'static,
+    {
+        self.start_with_factory(ctrl, dev, move |_| io_closure.clone())
+    }
+
+    /// Kick off the ublk device like `start()`, and IO handling closure of
+    /// each queue is created by `factory`, see `run_with_factory()`
+    pub fn start_with_factory<F, H>(
+        &self,
+        mut ctrl: ctrl::UblkCtrl,
+        dev: Arc<io::UblkDev>,
+        factory: F,
+    ) -> Result<UblkDeviceHandle, UblkError>
+    where
+        F: Fn(u16) -> H + Send + Sync + 'static,
+        H: FnMut(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError> + 'static,
+    {
+        let (ev_tx, ev_rx) = std::sync::mpsc::channel();
+        let signal_sub = self.setup_signals(&ev_tx)?;
+        let shared = UblkQueueShared {
+            factory: Arc::new(factory),
+            ev_tx,
+            signal_sub,
+            stop_handles: Mutex::new(Vec::new()),
+        };
+        let control = self.start_control_server(&ctrl, &shared.ev_tx)?;
+        let handles = self.create_queue_handlers(&mut ctrl, &dev, &shared)?;
+
+        if let Err(e) = ctrl.start_dev(&dev) {
+            let _ = ctrl.stop_dev(&dev);
+            for qh in handles {
+                let _ = qh.join();
+            }
+            return Err(e);
+        }
+
+        let dev_id = dev.dev_info.dev_id;
+        let sess = self.clone();
+        let monitor = std::thread::spawn(move || {
+            sess.monitor_queues(&mut ctrl, &dev, &shared, handles, ev_rx, control)
+        });
+
+        Ok(UblkDeviceHandle {
+            dev_id,
+            stats: self.stats.clone(),
+            monitor,
+        })
+    }
+
+    /// Wait until all queues exit, and recover queues aborted by panic
+    ///
+    /// Return result of each queue, indexed by queue id, after the device
+    /// is stopped.
+    fn monitor_queues<F, H>(
+        &self,
+        ctrl: &mut ctrl::UblkCtrl,
+        dev: &Arc<io::UblkDev>,
+        shared: &UblkQueueShared<F>,
+        mut handles: Vec<UblkQueueJoinHandle>,
+        ev_rx: std::sync::mpsc::Receiver<io::UblkQueueEvent>,
+        control: Option<control::UblkControlServer>,
+    ) -> Result<Vec<Result<(), UblkError>>, UblkError>
+    where
+        F: Fn(u16) -> H + Send + Sync + 'static,
+        H: FnMut(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError> + 'static,
+    {
+        let dev_id = dev.dev_info.dev_id;
+        let recovery = (self.ctrl_flags & (sys::UBLK_F_USER_RECOVERY as u64)) != 0;
+        let mut nr_exited = 0;
+        let mut panicked = false;
+        let mut deleting = false;
+        let mut handover = None;
+        let mut quiesced = false;
+        let stats_json = self.stats_json && !self.stats_interval().is_zero();
+        while nr_exited < dev.dev_info.nr_hw_queues {
+            let ev = if stats_json {
+                ev_rx.recv_timeout(self.stats_interval())
+            } else {
+                ev_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            };
+
+            match ev {
+                Ok(io::UblkQueueEvent::Panic { q_id, tag, msg }) => {
+                    error!(
+                        "dev-{} queue {} tag {} panicked: {}",
+                        dev_id, q_id, tag, msg
+                    );
+                }
+                Ok(io::UblkQueueEvent::Exit { q_id, panicked: p }) => {
+                    if p {
+                        error!("dev-{} queue {} is aborted by panic", dev_id, q_id);
+                    }
+                    nr_exited += 1;
+                    panicked |= p;
+                }
+                Ok(io::UblkQueueEvent::Signal { signo, .. }) => {
+                    deleting |= self.handle_signal(ctrl, signo, deleting)
+                }
+                Ok(io::UblkQueueEvent::Handover { reply }) => {
+                    if handover.is_some() || deleting {
+                        let _ = reply.send(Err(UblkError::OtherError(-libc::EBUSY)));
+                    } else {
+                        match self.start_handover(ctrl, dev, shared, recovery) {
+                            Ok(ho) => handover = Some((ho, reply)),
+                            Err(e) => {
+                                error!("dev-{} handover failed {:?}", dev_id, e);
+                                let _ = reply.send(Err(e));
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => self.dump_stats_json(ctrl),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if nr_exited == dev.dev_info.nr_hw_queues
+                && panicked
+                && recovery
+                && !deleting
+                && handover.is_none()
+            {
+                for qh in handles.drain(..) {
+                    let _ = qh.join();
+                }
+                match self.recover_queues(ctrl, dev, shared) {
+                    Ok(h) => handles = h,
+                    Err(e) => {
+                        error!("dev-{} in-process recovery failed: {:?}", dev_id, e);
+                        quiesced = true;
+                        break;
+                    }
+                }
+                nr_exited = 0;
+                panicked = false;
+            }
+        }
+
+        let res = handles
+            .into_iter()
+            .map(|qh| {
+                qh.join()
+                    .unwrap_or_else(|p| Err(UblkError::QueuePanic(io::panic_msg(&*p))))
+            })
+            .collect();
+
+        // the control server is waiting for the handover result
+        if let Some((ho, reply)) = handover {
+            if !deleting {
+                // START_USER_RECOVERY of the new process returns -EBUSY
+                // until /dev/ublkcN is released
+                dev.close_cdev();
+                info!("dev-{} is handed over", dev_id);
+                let _ = reply.send(Ok(ho));
+                drop(control);
+                return Ok(res);
+            }
+            let _ = reply.send(Err(UblkError::OtherError(-libc::ECANCELED)));
+        }
+        drop(control);
+
+        // the device is still quiesced by ublk driver, so keep it for
+        // UblkManager::recover() or new process
+        if quiesced {
+            ctrl.keep_for_recovery();
+            return Ok(res);
         }
 
         ctrl.stop_dev(dev)?;
+        if deleting {
+            ctrl.del_dev()?;
+        }
 
-        Ok(worker_qh)
+        Ok(res)
+    }
+
+    /// Serve control requests on `control_socket` if it is set
+    fn start_control_server(
+        &self,
+        ctrl: &ctrl::UblkCtrl,
+        ev_tx: &std::sync::mpsc::Sender<io::UblkQueueEvent>,
+    ) -> Result<Option<control::UblkControlServer>, UblkError> {
+        match self.control_socket.as_ref() {
+            Some(path) => Ok(Some(control::UblkControlServer::start(
+                path,
+                ctrl.dev_info.dev_id,
+                self.clone(),
+                ev_tx.clone(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Block signals handled by session, and subscribe `ev_tx` to the
+    /// process-wide signalfd, which is polled by queue 0 of each device
+    fn setup_signals(
+        &self,
+        ev_tx: &std::sync::mpsc::Sender<io::UblkQueueEvent>,
+    ) -> Result<Option<io::UblkSignalSub>, UblkError> {
+        if !self.handle_signals {
+            return Ok(None);
+        }
+
+        let sfd = signal_fd()?;
+
+        // the mask is blocked when building the session, and block it in
+        // the calling pthread again in case the session is cloned to
+        // other pthread; pthreads of this device inherit the mask
+        sfd.block()?;
+        Ok(Some(sfd.subscribe(ev_tx.clone())))
+    }
+
+    /// Handle signal read from signalfd, return true if the device is
+    /// being stopped and deleted
+    fn handle_signal(&self, ctrl: &mut ctrl::UblkCtrl, signo: i32, deleting: bool) -> bool {
+        let dev_id = ctrl.dev_info.dev_id;
+
+        match signo {
+            libc::SIGTERM | libc::SIGINT => {
+                if deleting {
+                    return true;
+                }
+                // ublk driver drains inflight IOs before aborting IO
+                // commands, then all queues exit
+                info!("dev-{} signal {}: stop and delete device", dev_id, signo);
+                if let Err(e) = ctrl.stop() {
+                    error!("dev-{} stop device failed {:?}", dev_id, e);
+                    return false;
+                }
+                return true;
+            }
+            libc::SIGHUP => {
+                if let Some(hook) = self.reload_hook.0.as_ref() {
+                    if let Err(e) = hook(ctrl) {
+                        error!("dev-{} reload failed {:?}", dev_id, e);
+                    }
+                }
+            }
+            libc::SIGUSR1 => {
+                if let Ok(s) = serde_json::to_string(&self.get_stats()) {
+                    info!("dev-{} stats {}", dev_id, s);
+                }
+                self.dump_stats_json(ctrl);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Prepare handover of this device, then stop all queues after IOs
+    /// being handled are committed
+    ///
+    /// Everything which may fail is done before stopping queues, so the
+    /// device is still served by this process if handover fails.
+    fn start_handover<F>(
+        &self,
+        ctrl: &mut ctrl::UblkCtrl,
+        dev: &Arc<io::UblkDev>,
+        shared: &UblkQueueShared<F>,
+        recovery: bool,
+    ) -> Result<upgrade::UblkHandover, UblkError> {
+        // the device is removed by ublk driver after queues exit
+        if !recovery {
+            return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+        }
+
+        let dev_id = dev.dev_info.dev_id;
+        let state = match self.handover_hook.0.as_ref() {
+            Some(hook) => hook(ctrl)?,
+            None => serde_json::Value::Null,
+        };
+        let fds = upgrade::dup_tgt_fds(&dev.tgt.fds[1..dev.tgt.nr_fds as usize])?;
+        ctrl.hand_over(&state)?;
+
+        info!("dev-{} handover: stop all queues", dev_id);
+        for h in shared
+            .stop_handles
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+        {
+            if let Err(e) = h.stop() {
+                error!("dev-{} stop queue failed {:?}", dev_id, e);
+            }
+        }
+
+        Ok(upgrade::UblkHandover::new(ctrl, &self.name, state, fds))
+    }
+
+    /// Re-create all queues after they are aborted, and ublk driver has
+    /// quiesced the device
+    fn recover_queues<F, H>(
+        &self,
+        ctrl: &mut ctrl::UblkCtrl,
+        dev: &Arc<io::UblkDev>,
+        shared: &UblkQueueShared<F>,
+    ) -> Result<Vec<UblkQueueJoinHandle>, UblkError>
+    where
+        F: Fn(u16) -> H + Send + Sync + 'static,
+        H: FnMut(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError> + 'static,
+    {
+        // START_USER_RECOVERY returns -EBUSY until /dev/ublkcN is released
+        let res = dev.reopen_cdev(|| ctrl.start_user_recover())?;
+        if res < 0 {
+            return Err(UblkError::UringIOError(res));
+        }
+
+        ctrl.reset_queues_configured();
+        let handles = self.create_queue_handlers(ctrl, dev, shared)?;
+
+        // wait until all queues are ready
+        let res = ctrl.end_user_recover(unsafe { libc::getpid() }, false)?;
+        if res < 0 {
+            return Err(UblkError::UringIOError(res));
+        }
+        ctrl.get_info()?;
+
+        Ok(handles)
+    }
+}
+
+type UblkQueueJoinHandle = std::thread::JoinHandle<Result<(), UblkError>>;
+
+/// Shared by all queue pthreads of one device
+struct UblkQueueShared<F> {
+    factory: Arc<F>,
+    ev_tx: std::sync::mpsc::Sender<io::UblkQueueEvent>,
+    signal_sub: Option<io::UblkSignalSub>,
+
+    /// stop handles of current queues, for handover
+    stop_handles: Mutex<Vec<io::UblkQueueStopHandle>>,
+}
+
+/// Handle of one ublk device started by `UblkSession::start()`
+///
+/// Dropping the handle doesn't stop the device, and the monitor pthread
+/// keeps running until the device is stopped.
+pub struct UblkDeviceHandle {
+    dev_id: u32,
+    stats: Arc<io::UblkDevStats>,
+    monitor: std::thread::JoinHandle<Result<Vec<Result<(), UblkError>>, UblkError>>,
+}
+
+impl UblkDeviceHandle {
+    /// Return device id
+    pub fn dev_id(&self) -> u32 {
+        self.dev_id
+    }
+
+    /// Return path of this block device, such as `/dev/ublkb0`
+    pub fn bdev_path(&self) -> String {
+        format!("{}{}", BDEV_PATH, self.dev_id)
+    }
+
+    /// Stop this device, and all queues exit after their IO commands are
+    /// aborted by ublk driver
+    ///
+    /// The device is removed after all queues exit, and `wait()` returns.
+    pub fn stop(&self) -> Result<i32, UblkError> {
+        ctrl::UblkCtrl::new_simple(self.dev_id as i32, 0)?.stop()
+    }
+
+    /// Return true if the device is stopped and all queues have exited
+    pub fn is_finished(&self) -> bool {
+        self.monitor.is_finished()
+    }
+
+    /// Wait until the device is stopped
+    ///
+    /// Return result of each queue indexed by queue id, and queue pthread
+    /// failure or panic is returned as `Err`.
+    pub fn wait(self) -> Result<Vec<Result<(), UblkError>>, UblkError> {
+        self.monitor
+            .join()
+            .unwrap_or_else(|p| Err(UblkError::QueuePanic(io::panic_msg(&*p))))
+    }
+
+    /// Return stats snapshot of this device, aggregated from all queues
+    ///
+    /// Same with `UblkSession::get_stats()`.
+    pub fn get_stats(&self) -> io::UblkQueueStats {
+        self.stats.refresh(UBLK_STATS_REFRESH_TIMEOUT);
+        self.stats.get_stats()
+    }
+
+    /// Return stats snapshot of each queue, indexed by queue id
+    pub fn get_queues_stats(&self) -> Vec<io::UblkQueueStats> {
+        self.stats.refresh(UBLK_STATS_REFRESH_TIMEOUT);
+        self.stats.get_queues_stats()
     }
 }
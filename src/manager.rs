@@ -0,0 +1,231 @@
+//! Manage many ublk devices in one process
+//!
+//! `UblkManager` adds, removes and recovers devices at runtime, and each
+//! device is created from `UblkDevConfig` by target constructor registered
+//! in `UblkTargetRegistry`.
+//!
+//! Every device is started by `UblkSession::start_with_factory()` and owns
+//! its queue pthreads and monitor pthread, so failure of one device, such
+//! as queue panic, doesn't affect other devices.
+
+use super::config::{UblkConfig, UblkDevConfig, UblkTargetRegistry};
+use super::io::UblkQueueStats;
+use super::{ctrl::UblkCtrl, UblkDeviceHandle, UblkError};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+struct UblkManagedDev {
+    cfg: UblkDevConfig,
+    handle: UblkDeviceHandle,
+}
+
+/// Status of one device owned by `UblkManager`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UblkDevStatus {
+    pub dev_id: u32,
+    pub bdev_path: String,
+
+    /// false if all queues of this device have exited, and the device
+    /// can be collected by `UblkManager::reap()`
+    pub running: bool,
+
+    /// config of this device, and `id` is the allocated device id
+    pub config: UblkDevConfig,
+}
+
+/// Result of each queue of one removed device, see `UblkDeviceHandle::wait()`
+pub type UblkDevResult = Result<Vec<Result<(), UblkError>>, UblkError>;
+
+/// Owner of many ublk devices
+///
+/// All methods take `&self`, so the manager can be shared by `Arc` with
+/// any control context. All devices are stopped when the manager is
+/// dropped.
+pub struct UblkManager {
+    registry: UblkTargetRegistry,
+    devices: Mutex<BTreeMap<u32, UblkManagedDev>>,
+}
+
+impl UblkManager {
+    pub fn new(registry: UblkTargetRegistry) -> UblkManager {
+        UblkManager {
+            registry,
+            devices: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn devices(&self) -> std::sync::MutexGuard<'_, BTreeMap<u32, UblkManagedDev>> {
+        self.devices.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self, cfg: &UblkDevConfig) -> Result<u32, UblkError> {
+        let handle = self.registry.start(cfg)?;
+        let dev_id = handle.dev_id();
+        let cfg = UblkDevConfig {
+            id: dev_id as i32,
+            ..cfg.clone()
+        };
+
+        self.devices()
+            .insert(dev_id, UblkManagedDev { cfg, handle });
+        Ok(dev_id)
+    }
+
+    /// Add and start one new device, return its device id
+    pub fn add(&self, cfg: &UblkDevConfig) -> Result<u32, UblkError> {
+        let cfg = UblkDevConfig {
+            dev_flags: (cfg.dev_flags & !super::UBLK_DEV_F_RECOVER_DEV) | super::UBLK_DEV_F_ADD_DEV,
+            ..cfg.clone()
+        };
+
+        self.start(&cfg)
+    }
+
+    /// Add all devices in `cfg`, and return result of each device
+    ///
+    /// Failure of one device doesn't stop adding other devices.
+    pub fn add_all(&self, cfg: &UblkConfig) -> Vec<Result<u32, UblkError>> {
+        cfg.devices.iter().map(|c| self.add(c)).collect()
+    }
+
+    /// Stop and delete device `dev_id`, and return result of each queue
+    pub fn remove(&self, dev_id: u32) -> Result<UblkDevResult, UblkError> {
+        let dev = self
+            .devices()
+            .remove(&dev_id)
+            .ok_or(UblkError::OtherError(-libc::ENODEV))?;
+
+        // the device may have been stopped already
+        let _ = dev.handle.stop();
+        let res = dev.handle.wait();
+
+        // recovered device, or device kept quiesced after in-process
+        // recovery fails, isn't deleted by its session
+        if let Ok(mut ctrl) = UblkCtrl::new_simple(dev_id as i32, 0) {
+            let _ = ctrl.del_dev();
+        }
+        Ok(res)
+    }
+
+    /// Recover device `cfg.id`, which has been quiesced by ublk driver
+    /// after its old daemon exits, and `UBLK_F_USER_RECOVERY` has to be
+    /// set for the device
+    ///
+    /// Device owned by this manager is only left quiesced if its queues
+    /// are aborted by panic and the in-process recovery fails, see
+    /// `UblkSession::run()`; then all its queues have exited, and its old
+    /// handle is replaced.
+    pub fn recover(&self, cfg: &UblkDevConfig) -> Result<u32, UblkError> {
+        if cfg.id < 0 {
+            return Err(UblkError::ConfigError(
+                "device id is required for recovery".to_string(),
+            ));
+        }
+
+        let old = {
+            let mut devs = self.devices();
+
+            match devs.get(&(cfg.id as u32)) {
+                Some(d) if !d.handle.is_finished() => {
+                    return Err(UblkError::OtherError(-libc::EBUSY))
+                }
+                _ => devs.remove(&(cfg.id as u32)),
+            }
+        };
+        if let Some(dev) = old {
+            if let Err(e) = dev.handle.wait() {
+                error!("dev-{} old handle failed {:?}", cfg.id, e);
+            }
+        }
+
+        let res = UblkCtrl::new_simple(cfg.id, 0)?.start_user_recover()?;
+        if res < 0 {
+            return Err(UblkError::UringIOError(res));
+        }
+
+        let cfg = UblkDevConfig {
+            dev_flags: (cfg.dev_flags & !super::UBLK_DEV_F_ADD_DEV) | super::UBLK_DEV_F_RECOVER_DEV,
+            ..cfg.clone()
+        };
+        self.start(&cfg)
+    }
+
+    /// Return status of all devices, sorted by device id
+    pub fn list(&self) -> Vec<UblkDevStatus> {
+        self.devices()
+            .iter()
+            .map(|(&dev_id, dev)| UblkDevStatus {
+                dev_id,
+                bdev_path: dev.handle.bdev_path(),
+                running: !dev.handle.is_finished(),
+                config: dev.cfg.clone(),
+            })
+            .collect()
+    }
+
+    /// Return stats snapshot of device `dev_id`
+    pub fn get_stats(&self, dev_id: u32) -> Option<UblkQueueStats> {
+        self.devices().get(&dev_id).map(|d| d.handle.get_stats())
+    }
+
+    /// Return stats snapshot of all devices, indexed by device id
+    pub fn get_all_stats(&self) -> BTreeMap<u32, UblkQueueStats> {
+        self.devices()
+            .iter()
+            .map(|(&dev_id, d)| (dev_id, d.handle.get_stats()))
+            .collect()
+    }
+
+    /// Return stats aggregated from all devices
+    pub fn get_total_stats(&self) -> UblkQueueStats {
+        let mut stats = UblkQueueStats::default();
+
+        for s in self.get_all_stats().values() {
+            stats.merge(s);
+        }
+        stats
+    }
+
+    /// Collect devices whose queues have all exited, such as device
+    /// deleted by other utilities or aborted by panic without recovery
+    ///
+    /// Device kept quiesced for recovery isn't deleted, and it can still
+    /// be recovered by `recover()`.
+    pub fn reap(&self) -> BTreeMap<u32, UblkDevResult> {
+        let done: Vec<u32> = self
+            .devices()
+            .iter()
+            .filter(|(_, d)| d.handle.is_finished())
+            .map(|(&dev_id, _)| dev_id)
+            .collect();
+
+        done.into_iter()
+            .filter_map(|dev_id| {
+                let dev = self.devices().remove(&dev_id)?;
+                Some((dev_id, dev.handle.wait()))
+            })
+            .collect()
+    }
+
+    /// Stop and delete all devices, and return result of each device
+    pub fn shutdown(&self) -> BTreeMap<u32, UblkDevResult> {
+        let ids: Vec<u32> = self.devices().keys().cloned().collect();
+
+        for &dev_id in &ids {
+            if let Some(d) = self.devices().get(&dev_id) {
+                let _ = d.handle.stop();
+            }
+        }
+        ids.into_iter()
+            .filter_map(|dev_id| Some((dev_id, self.remove(dev_id).ok()?)))
+            .collect()
+    }
+}
+
+impl Drop for UblkManager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
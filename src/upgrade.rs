@@ -0,0 +1,313 @@
+//! Live upgrade of ublk daemon via user recovery handover
+//!
+//! `UBLK_F_USER_RECOVERY` lets one new daemon take over the device after
+//! the old daemon exits, and handover is the planned way of doing that,
+//! so the daemon binary can be upgraded without removing `/dev/ublkbN`:
+//!
+//! 1) the new process calls `UblkHandover::take_over()` with the old
+//!    daemon's `control_socket`
+//!
+//! 2) the old daemon calls the hook set by `UblkSession::set_handover_hook()`
+//!    for target state, and writes the state into the exported json as
+//!    `handover`, then all queues are stopped after IOs being handled are
+//!    committed, and IOs coming after that are left in ublk driver
+//!
+//! 3) the old daemon releases /dev/ublkcN, so ublk driver starts to
+//!    quiesce the device, then sends the state and device info to the new
+//!    process, together with target fds(`UblkTgt::fds[1..]`), such as
+//!    backing files, which are passed via `SCM_RIGHTS`, and the old daemon
+//!    leaves `run()` or its monitor pthread, and the device isn't stopped
+//!
+//! 4) the new process calls `UblkHandover::start_user_recover()`, which
+//!    waits until the device is quiesced, creates the session from
+//!    `UblkHandover::session_builder()`, then ublk driver completes
+//!    END_USER_RECOVERY when the device is started
+//!
+//! Blocked IOs are kept in ublk driver during the handover, and they are
+//! handled by the new process after the device becomes LIVE again.
+//!
+//! The control socket is only accessible by owner, and both sides require
+//! the peer's uid(`SO_PEERCRED`) to be same with its own uid or root, so
+//! device and target fds are never handed over to other users.
+
+use super::control::{check_peer_cred, UblkRpcRequest, UblkRpcResponse};
+use super::ctrl::UblkCtrl;
+use super::io::UblkQueueEvent;
+use super::{UblkError, UblkSessionBuilder};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+
+/// max fds passed in handover, same with size of `UblkTgt::fds`
+const UBLK_HANDOVER_MAX_FDS: usize = 32;
+
+/// Device info and target state sent to the new process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UblkHandoverMsg {
+    dev_id: u32,
+    name: String,
+    nr_queues: u32,
+    depth: u32,
+    io_buf_bytes: u32,
+    ctrl_flags: u64,
+    state: serde_json::Value,
+    nr_fds: usize,
+}
+
+/// Device handed over from the old daemon
+#[derive(Debug)]
+pub struct UblkHandover {
+    msg: UblkHandoverMsg,
+    fds: Vec<OwnedFd>,
+}
+
+fn last_os_error() -> UblkError {
+    UblkError::OtherIOError(std::io::Error::last_os_error())
+}
+
+impl UblkHandover {
+    pub(crate) fn new(
+        ctrl: &UblkCtrl,
+        name: &str,
+        state: serde_json::Value,
+        fds: Vec<OwnedFd>,
+    ) -> UblkHandover {
+        let info = &ctrl.dev_info;
+
+        UblkHandover {
+            msg: UblkHandoverMsg {
+                dev_id: info.dev_id,
+                name: name.to_string(),
+                nr_queues: info.nr_hw_queues as u32,
+                depth: info.queue_depth as u32,
+                io_buf_bytes: info.max_io_buf_bytes,
+                ctrl_flags: info.flags,
+                state,
+                nr_fds: fds.len(),
+            },
+            fds,
+        }
+    }
+
+    /// Take over device from the old daemon serving control socket `path`
+    ///
+    /// Return after the old daemon has stopped all queues and released
+    /// /dev/ublkcN, and the device may not be quiesced by ublk driver yet,
+    /// see `start_user_recover()`.
+    pub fn take_over<P: AsRef<std::path::Path>>(path: P) -> Result<UblkHandover, UblkError> {
+        let mut stream = UnixStream::connect(path).map_err(UblkError::OtherIOError)?;
+
+        // don't take over device or fds from daemon of other users
+        check_peer_cred(&stream)?;
+        let req = UblkRpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: 1,
+            method: "handover".to_string(),
+            params: serde_json::json!({}),
+        };
+        let mut buf = serde_json::to_string(&req)?;
+
+        buf.push('\n');
+        stream
+            .write_all(buf.as_bytes())
+            .map_err(UblkError::OtherIOError)?;
+
+        let (line, fds) = recv_with_fds(&stream)?;
+        let resp: UblkRpcResponse = serde_json::from_slice(&line)?;
+        if let Some(e) = resp.error {
+            return Err(UblkError::RpcError(e.code, e.message));
+        }
+        let msg: UblkHandoverMsg =
+            serde_json::from_value(resp.result.unwrap_or(serde_json::Value::Null))?;
+        if msg.nr_fds != fds.len() {
+            return Err(UblkError::OtherError(-libc::EPROTO));
+        }
+
+        Ok(UblkHandover { msg, fds })
+    }
+
+    pub(crate) fn to_json(&self) -> Result<serde_json::Value, UblkError> {
+        Ok(serde_json::to_value(&self.msg)?)
+    }
+
+    pub(crate) fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    pub fn dev_id(&self) -> u32 {
+        self.msg.dev_id
+    }
+
+    /// Target state returned from the old daemon's handover hook
+    pub fn state(&self) -> &serde_json::Value {
+        &self.msg.state
+    }
+
+    /// Take target fds, in the order of `UblkTgt::fds[1..]` of the old
+    /// daemon
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        std::mem::take(&mut self.fds)
+    }
+
+    /// Start user recovery, which waits until the device is quiesced
+    pub fn start_user_recover(&self) -> Result<i32, UblkError> {
+        let res = UblkCtrl::new_simple(self.msg.dev_id as i32, 0)?.start_user_recover()?;
+
+        if res < 0 {
+            return Err(UblkError::UringIOError(res));
+        }
+        Ok(res)
+    }
+
+    /// Return session builder for recovering this device, and other
+    /// session options can be set before building the session
+    pub fn session_builder(&self) -> UblkSessionBuilder {
+        let mut b = UblkSessionBuilder::default();
+
+        b.name(self.msg.name.clone())
+            .id(self.msg.dev_id as i32)
+            .nr_queues(self.msg.nr_queues)
+            .depth(self.msg.depth)
+            .io_buf_bytes(self.msg.io_buf_bytes)
+            .ctrl_flags(self.msg.ctrl_flags)
+            .dev_flags(super::UBLK_DEV_F_RECOVER_DEV);
+        b
+    }
+}
+
+/// Ask the session's monitor to hand over the device, and wait until all
+/// queues are stopped
+pub(crate) fn request_handover(
+    ev_tx: &mpsc::Sender<UblkQueueEvent>,
+) -> Result<UblkHandover, UblkError> {
+    let (tx, rx) = mpsc::channel();
+
+    ev_tx
+        .send(UblkQueueEvent::Handover { reply: tx })
+        .map_err(|_| UblkError::OtherError(-libc::ENODEV))?;
+    rx.recv()
+        .unwrap_or(Err(UblkError::OtherError(-libc::ENODEV)))
+}
+
+/// Duplicate target fds of `UblkTgt::fds[1..nr_fds]`, which are still
+/// owned by target
+pub(crate) fn dup_tgt_fds(fds: &[RawFd]) -> Result<Vec<OwnedFd>, UblkError> {
+    fds.iter()
+        .map(|&fd| {
+            let nfd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if nfd < 0 {
+                return Err(last_os_error());
+            }
+            Ok(unsafe { OwnedFd::from_raw_fd(nfd) })
+        })
+        .collect()
+}
+
+/// Send `buf` and pass `fds` with `SCM_RIGHTS` along with its first byte
+pub(crate) fn send_with_fds(
+    stream: &UnixStream,
+    buf: &[u8],
+    fds: &[OwnedFd],
+) -> Result<(), UblkError> {
+    if fds.len() > UBLK_HANDOVER_MAX_FDS {
+        return Err(UblkError::OtherError(-libc::E2BIG));
+    }
+
+    let raw: Vec<RawFd> = fds.iter().map(|f| f.as_raw_fd()).collect();
+    let data_len = std::mem::size_of_val(raw.as_slice()) as u32;
+    let mut cmsg_buf = vec![0_u64; unsafe { libc::CMSG_SPACE(data_len) } as usize / 8 + 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !raw.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as usize;
+            std::ptr::copy_nonoverlapping(
+                raw.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                data_len as usize,
+            );
+        }
+    }
+
+    let ret = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(last_os_error());
+    }
+
+    // fds have been passed with the first byte
+    let mut stream = stream;
+    stream
+        .write_all(&buf[ret as usize..])
+        .map_err(UblkError::OtherIOError)
+}
+
+/// Receive one line, and collect fds passed with `SCM_RIGHTS`
+fn recv_with_fds(stream: &UnixStream) -> Result<(Vec<u8>, Vec<OwnedFd>), UblkError> {
+    let data_len = (UBLK_HANDOVER_MAX_FDS * std::mem::size_of::<RawFd>()) as u32;
+    let cmsg_len = unsafe { libc::CMSG_SPACE(data_len) } as usize;
+    let mut cmsg_buf = vec![0_u64; cmsg_len / 8 + 1];
+    let mut line = Vec::new();
+    let mut fds = Vec::new();
+    let mut buf = [0_u8; 4096];
+
+    while !line.ends_with(b"\n") {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_len;
+
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(UblkError::OtherIOError(err));
+        }
+
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    for i in 0..len / std::mem::size_of::<RawFd>() {
+                        let fd = std::ptr::read_unaligned(data.add(i));
+                        fds.push(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if (msg.msg_flags & libc::MSG_CTRUNC) != 0 {
+            return Err(UblkError::OtherError(-libc::EPROTO));
+        }
+
+        // the old daemon closes connection without handover
+        if ret == 0 {
+            return Err(UblkError::OtherError(-libc::ECONNRESET));
+        }
+        line.extend_from_slice(&buf[..ret as usize]);
+    }
+
+    Ok((line, fds))
+}
@@ -1,7 +1,10 @@
 #[cfg(test)]
 mod tests {
-    use libublk::io::{UblkDev, UblkIOCtx, UblkQueue, UblkQueueCtx};
-    use libublk::{ctrl::UblkCtrl, UblkError};
+    use libublk::config::{UblkConfig, UblkTarget, UblkTargetRegistry};
+    use libublk::control::UblkControlClient;
+    use libublk::io::{UblkDev, UblkEventLoop, UblkIOCtx, UblkQueue, UblkQueueCtx};
+    use libublk::manager::UblkManager;
+    use libublk::{ctrl::UblkAffinityPolicy, ctrl::UblkCtrl, UblkError};
     use libublk::{sys, UblkSessionBuilder};
     use std::env;
     use std::path::Path;
@@ -57,82 +60,1232 @@ mod tests {
             UblkCtrl::new(-1, 1, 64, 512_u32 * 1024, 0, libublk::UBLK_DEV_F_ADD_DEV).unwrap();
         let dev_path = format!("{}{}", libublk::CDEV_PATH, ctrl.dev_info.dev_id);
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        assert!(Path::new(&dev_path).exists() == true);
+        assert!(wait_until(5000, || Path::new(&dev_path).exists()));
+    }
+
+    fn null_tgt_init(dev: &mut UblkDev) -> Result<serde_json::Value, UblkError> {
+        dev.set_default_params(32_u64 << 20);
+        Ok(serde_json::json!({}))
+    }
+
+    /// poll `cond` until it is true, and return false after `timeout_ms`
+    fn wait_until(timeout_ms: u64, mut cond: impl FnMut() -> bool) -> bool {
+        let start = std::time::Instant::now();
+
+        while !cond() {
+            if start.elapsed().as_millis() as u64 >= timeout_ms {
+                return false;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// wait until /dev/ublkbN is created by udev after the device is
+    /// started, and return its path
+    fn wait_bdev(dev_id: i32) -> String {
+        let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
+
+        assert!(wait_until(5000, || Path::new(&dev_path).exists()));
+        dev_path
+    }
+
+    fn null_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let iod = ctx.get_iod(io.get_tag());
+        let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+
+        io.complete_io(bytes);
+        Ok(0)
+    }
+
+    fn null_handle_io_batch(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let iod = ctx.get_iod(io.get_tag());
+        let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+
+        io.add_to_comp_batch(io.get_tag() as u16, bytes);
+        Ok(libublk::io::UBLK_IO_S_COMP_BATCH)
+    }
+
+    /// add one ublk device with single queue of depth 64, which is served
+    /// by `serve_queue()`
+    fn add_queue_dev<F>(tgt_init: F) -> (UblkCtrl, UblkDev)
+    where
+        F: FnOnce(&mut UblkDev) -> Result<serde_json::Value, UblkError>,
+    {
+        let mut ctrl = UblkCtrl::new(-1, 1, 64, 512 << 10, 0, libublk::UBLK_DEV_F_ADD_DEV).unwrap();
+        let dev = UblkDev::new("null".to_string(), tgt_init, &mut ctrl).unwrap();
+
+        (ctrl, dev)
+    }
+
+    /// start the device and handle its IO by `qc` in this pthread, until
+    /// it is deleted by one pthread after running `io_fn` with path of
+    /// /dev/ublkbN; handle of that pthread is returned
+    fn serve_queue<T, Q, R>(
+        ctrl: &mut UblkCtrl,
+        dev: &UblkDev,
+        queue: &mut UblkQueue<'_, T>,
+        mut qc: Q,
+        io_fn: R,
+    ) -> std::thread::JoinHandle<()>
+    where
+        T: Default,
+        Q: FnMut(&mut UblkIOCtx<T>) -> Result<i32, UblkError>,
+        R: FnOnce(&str) + Send + 'static,
+    {
+        ctrl.configure_queue(dev, 0, unsafe { libc::gettid() })
+            .unwrap();
+        ctrl.start_dev_in_queue(dev, queue, &mut qc).unwrap();
+
+        let dev_id = ctrl.dev_info.dev_id as i32;
+        let qh = std::thread::spawn(move || {
+            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+
+            io_fn(&wait_bdev(dev_id));
+            ctrl.del().unwrap();
+        });
+
+        queue.wait_and_handle_io(&mut qc);
+        qh
+    }
+
+    /// read `nr` 4K blocks from `dev_path`, each one from its own 1MB
+    /// starting at 16MB, so it isn't served from page cache
+    fn read_bdev(dev_path: &str, nr: u64) {
+        use std::io::{Read, Seek};
+
+        let mut buf = vec![0_u8; 4096];
+        let mut f = std::fs::File::open(dev_path).unwrap();
+
+        for i in 0..nr {
+            f.seek(std::io::SeekFrom::Start((16 + i) << 20)).unwrap();
+            f.read_exact(&mut buf).unwrap();
+        }
+    }
+
+    fn __test_ublk_null(dev_flags: u32) {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .dev_flags(dev_flags)
+            .build()
+            .unwrap();
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(250_u64 << 30);
+            Ok(serde_json::json!({}))
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+            let handle_io =
+                move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+                    if dev_flags & libublk::UBLK_DEV_F_COMP_BATCH != 0 {
+                        null_handle_io_batch(ctx, io)
+                    } else {
+                        null_handle_io(ctx, io)
+                    }
+                };
+
+            sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+
+                assert!(ctrl.get_target_flags_from_json().unwrap() == dev_flags);
+
+                //ublk block device should be observed now
+                assert!(Path::new(&dev_path).exists() == true);
+
+                //ublk exported json file should be observed
+                assert!(Path::new(&ctrl.run_path()).exists() == true);
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
+
+    /// make one ublk-null and test if /dev/ublkbN can be created successfully
+    #[test]
+    fn test_ublk_null() {
+        __test_ublk_null(libublk::UBLK_DEV_F_ADD_DEV);
+    }
+
+    /// make one ublk-null and test if /dev/ublkbN can be created successfully
+    #[test]
+    fn test_ublk_null_comp_batch() {
+        __test_ublk_null(libublk::UBLK_DEV_F_ADD_DEV | libublk::UBLK_DEV_F_COMP_BATCH);
+    }
+
+    /// count SQPOLL threads created by pthread `tid`
+    fn sqpoll_threads(tid: i32) -> usize {
+        let comm = format!("iou-sqp-{}", tid);
+
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|t| std::fs::read_to_string(t.ok()?.path().join("comm")).ok())
+            .filter(|c| c.trim() == comm)
+            .count()
+    }
+
+    /// create queues with shared SQPOLL ring, and fallback to plain
+    /// io_uring if SQPOLL isn't allowed
+    #[test]
+    fn test_ublk_null_sqpoll() {
+        let mut ctrl = UblkCtrl::new(-1, 2, 64, 512 << 10, 0, libublk::UBLK_DEV_F_ADD_DEV).unwrap();
+        let dev = UblkDev::new(
+            "null".to_string(),
+            |dev: &mut UblkDev| {
+                dev.set_default_params(250_u64 << 30);
+                dev.tgt.ring = libublk::io::UblkRingConfig {
+                    sqpoll: Some(libublk::io::UblkSqPoll {
+                        idle_ms: 100,
+                        shared: true,
+                        ..Default::default()
+                    }),
+                    fallback: true,
+                    ..Default::default()
+                };
+                Ok(serde_json::json!({}))
+            },
+            &mut ctrl,
+        )
+        .unwrap();
+
+        let tid = unsafe { libc::gettid() };
+        let q0 = UblkQueue::new(0, &dev).unwrap();
+        let q1 = UblkQueue::new(1, &dev).unwrap();
+        let shared = [&q0, &q1]
+            .iter()
+            .all(|q| q.get_ring_config().sqpoll.is_some_and(|sq| sq.shared));
+
+        if shared {
+            // SQPOLL thread is named after it is started
+            let start = std::time::Instant::now();
+            while sqpoll_threads(tid) == 0 && start.elapsed().as_secs() < 1 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(sqpoll_threads(tid) == 1);
+        }
+    }
+
+    /// make one ublk-null with busy polling over SQPOLL ring
+    #[test]
+    fn test_ublk_null_busy_poll() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(1_u32)
+            .busy_poll_us(50_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(32_u64 << 20);
+            dev.tgt.ring.sqpoll = Some(Default::default());
+            dev.tgt.ring.fallback = true;
+            Ok(serde_json::json!({}))
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, null_handle_io, move |dev_id| {
+                use std::io::Read;
+
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
+
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                for _ in 0..64 {
+                    f.read_exact(&mut buf).unwrap();
+                }
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+
+        // queue spins after each completion, and sleeps out of the window
+        let stats = sess.get_stats();
+        assert!(stats.poll_spin_ns > 0 && stats.poll_sleep_ns > 0);
+    }
+
+    /// make one ublk-null and check stats of IO, which are dumped to the
+    /// exported json file too
+    #[test]
+    fn test_ublk_null_stats() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .stats_interval_ms(50_u32)
+            .stats_json(true)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, null_handle_io, move |dev_id| {
+                use std::io::Read;
+
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
+
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                for _ in 0..64 {
+                    f.read_exact(&mut buf).unwrap();
+                }
+
+                // stats are dumped to json by the stats timer
+                let run_path = ctrl.run_path();
+                assert!(wait_until(5000, || {
+                    std::fs::read_to_string(&run_path)
+                        .ok()
+                        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+                        .is_some_and(|j| {
+                            j["stats"]["op_ios"][sys::UBLK_IO_OP_READ as usize].as_u64() > Some(0)
+                        })
+                }));
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+
+        let stats = sess.get_stats();
+        let read = sys::UBLK_IO_OP_READ as usize;
+        assert!(sess.get_queues_stats().len() == 2);
+        assert!(stats.op_ios[read] > 0 && stats.op_bytes[read] >= stats.op_ios[read] << 9);
+        assert!(stats.lat_hist.iter().sum::<u64>() > 0);
+        assert!(stats.inflight_max > 0 && stats.errors.is_empty());
+    }
+
+    /// run examples/daemon, which reports device id and LIVE to parent,
+    /// and setup failure of daemon is reported too
+    ///
+    /// The daemon is forked from the helper process, instead of this
+    /// multithreaded test harness.
+    #[test]
+    fn test_ublk_daemon() {
+        use std::process::Command;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("ublk.pid").display().to_string();
+        let daemon = get_curr_bin_dir().unwrap().join("examples/daemon");
+
+        // `daemon add` returns after the device becomes LIVE
+        let out = Command::new(&daemon)
+            .args(["add", &pidfile])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let out = String::from_utf8(out.stdout).unwrap();
+        let dev_id: i32 = out.trim().strip_prefix("dev id ").unwrap().parse().unwrap();
+
+        let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+        assert!(ctrl.dev_info.state == sys::UBLK_S_DEV_LIVE as u16);
+        let pid: i32 = std::fs::read_to_string(&pidfile)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert!(ctrl
+            .get_queue_tid(0)
+            .is_ok_and(|_| unsafe { libc::kill(pid, 0) } == 0));
+
+        // daemon runs in `/`, and inherits umask from the caller
+        let cwd = std::fs::read_link(format!("/proc/{}/cwd", pid)).unwrap();
+        assert!(cwd == Path::new("/"));
+        let umask = |pid: &str| {
+            std::fs::read_to_string(format!("/proc/{}/status", pid))
+                .unwrap()
+                .lines()
+                .find(|l| l.starts_with("Umask:"))
+                .map(|l| l.to_string())
+        };
+        assert!(umask(&pid.to_string()) == umask("self"));
+
+        // pidfile is removed after the daemon exits
+        ctrl.del_dev().unwrap();
+        assert!(wait_until(5000, || !Path::new(&pidfile).exists()));
+
+        let stdio = dir.path().join("none/ublk.log").display().to_string();
+        let out = Command::new(&daemon)
+            .args(["add", &pidfile, &stdio])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert!(!Path::new(&pidfile).exists());
+    }
+
+    /// parse device config, and unknown field or target type is rejected
+    #[test]
+    fn test_ublk_config_parse() {
+        let cfg = UblkConfig::from_json_str(
+            r#"{"devices": [
+                {"nr_queues": 2, "depth": 128, "target": {"type": "null"}},
+                {"id": 3, "target": {"type": "null", "options": {"size": 1048576}}}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert!(cfg.devices.len() == 2);
+        assert!(cfg.devices[0].id == -1 && cfg.devices[0].depth == 128);
+        assert!(cfg.devices[0].dev_flags == libublk::UBLK_DEV_F_ADD_DEV);
+        assert!(cfg.devices[1].target.options["size"] == 1048576);
+        assert!(UblkConfig::from_json_str(r#"{"devices": [{"depth": 1}]}"#).is_err());
+        assert!(UblkConfig::from_json_str(
+            r#"{"devices": [{"qd": 1, "target": {"type": "null"}}]}"#
+        )
+        .is_err());
+
+        let reg = UblkTargetRegistry::new();
+        let mut dev_cfg = cfg.devices[0].clone();
+        assert!(reg.target_types() == vec!["null".to_string()]);
+        dev_cfg.target.tgt_type = "nbd".to_string();
+        assert!(reg.create(&dev_cfg).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_ublk_config_toml() {
+        let cfg = UblkConfig::from_toml_str(
+            r#"
+            [[devices]]
+            id = 0
+            nr_queues = 2
+
+            [devices.target]
+            type = "null"
+            options = { size = 1073741824 }
+            "#,
+        )
+        .unwrap();
+
+        assert!(cfg.devices.len() == 1 && cfg.devices[0].nr_queues == 2);
+        assert!(cfg.devices[0].target.options["size"] == 1073741824_u64);
+    }
+
+    /// start ublk-null devices from config via target registry
+    #[test]
+    fn test_ublk_config_start() {
+        let cfg = UblkConfig::from_json_str(
+            r#"{"devices": [
+                {"nr_queues": 2, "target": {"type": "null", "options": {"size": 33554432}}},
+                {"target": {"type": "null"}}
+            ]}"#,
+        )
+        .unwrap();
+        let handles = UblkTargetRegistry::new().start_all(&cfg).unwrap();
+
+        for h in handles {
+            wait_bdev(h.dev_id() as i32);
+            h.stop().unwrap();
+            assert!(h.wait().unwrap().iter().all(|r| r.is_ok()));
+        }
+    }
+
+    /// add many ublk-null devices in one manager, and failure of one
+    /// device doesn't affect others
+    #[test]
+    fn test_ublk_manager() {
+        let mgr = UblkManager::new(UblkTargetRegistry::new());
+        let cfg = UblkConfig::from_json_str(
+            r#"{"devices": [
+                {"nr_queues": 2, "target": {"type": "null", "options": {"size": 33554432}}},
+                {"target": {"type": "nbd"}},
+                {"target": {"type": "null", "options": {"size": 33554432}}},
+                {"target": {"type": "null", "options": {"size": 33554432}}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let res = mgr.add_all(&cfg);
+        assert!(res[1].is_err());
+        let ids: Vec<u32> = res.into_iter().filter_map(|r| r.ok()).collect();
+        assert!(ids.len() == 3);
+
+        for id in &ids {
+            wait_bdev(*id as i32);
+        }
+        let list = mgr.list();
+        assert!(list.len() == 3 && list.iter().all(|d| d.running));
+        assert!(list.iter().all(|d| Path::new(&d.bdev_path).exists()));
+        assert!(list[0].config.id == list[0].dev_id as i32);
+        assert!(mgr.get_all_stats().len() == 3);
+
+        let res = mgr.remove(ids[0]).unwrap().unwrap();
+        assert!(res.len() == 2 && res.iter().all(|r| r.is_ok()));
+        assert!(mgr.remove(ids[0]).is_err());
+        assert!(mgr.list().iter().all(|d| d.running));
+
+        let res = mgr.shutdown();
+        assert!(res.len() == 2 && res.values().all(|r| r.is_ok()));
+        assert!(mgr.list().is_empty());
+    }
+
+    /// IO closure of one device always panics, and other devices owned by
+    /// the same manager keep serving IO
+    #[test]
+    fn test_ublk_manager_panic() {
+        use std::os::unix::fs::FileExt;
+
+        let mut registry = UblkTargetRegistry::new();
+        registry.register("panic", |_opts: &serde_json::Value| {
+            Ok(UblkTarget {
+                init: Box::new(null_tgt_init),
+                io_factory: Box::new(|_q| {
+                    Box::new(
+                        |_ctx: &UblkQueueCtx, _io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+                            panic!("ublk manager panic test")
+                        },
+                    )
+                }),
+            })
+        });
+        let mgr = UblkManager::new(registry);
+        let cfg = UblkConfig::from_json_str(
+            r#"{"devices": [
+                {"target": {"type": "null", "options": {"size": 33554432}}},
+                {"target": {"type": "panic"}},
+                {"target": {"type": "null", "options": {"size": 33554432}}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let ids: Vec<u32> = mgr.add_all(&cfg).into_iter().map(|r| r.unwrap()).collect();
+        let files: Vec<std::fs::File> = ids
+            .iter()
+            .map(|&id| std::fs::File::open(wait_bdev(id as i32)).unwrap())
+            .collect();
+        let mut buf = vec![0_u8; 4096];
+
+        assert!(files[1].read_exact_at(&mut buf, 0).is_err());
+        files[0].read_exact_at(&mut buf, 0).unwrap();
+        files[2].read_exact_at(&mut buf, 0).unwrap();
+        assert!(mgr.list().iter().all(|d| d.running));
+        drop(files);
+
+        let res = mgr.shutdown();
+        assert!(res.len() == 3 && res.values().all(|r| r.is_ok()));
+    }
+
+    /// recover one ublk-null by UblkManager after its daemon process is
+    /// killed, then remove it
+    #[test]
+    fn test_ublk_manager_recover() {
+        use std::io::BufRead;
+        use std::os::unix::fs::FileExt;
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new(get_curr_bin_dir().unwrap().join("examples/manager"))
+            .args(["add", "1", "recovery"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let line = std::io::BufReader::new(child.stdout.take().unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        let dev_id: i32 = line.strip_prefix("dev id ").unwrap().parse().unwrap();
+        wait_bdev(dev_id);
+
+        // ublk driver quiesces the device after /dev/ublkcN is released
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+        assert!(wait_until(5000, || {
+            ctrl.get_info().is_ok() && ctrl.dev_info.state == sys::UBLK_S_DEV_QUIESCED as u16
+        }));
+
+        let mgr = UblkManager::new(UblkTargetRegistry::new());
+        let cfg = UblkConfig::from_json_str(&format!(
+            r#"{{"devices": [{{"id": {}, "nr_queues": 2, "ctrl_flags": {},
+                "target": {{"type": "null", "options": {{"size": 33554432}}}}}}]}}"#,
+            dev_id,
+            sys::UBLK_F_USER_RECOVERY
+        ))
+        .unwrap();
+        assert!(mgr.recover(&cfg.devices[0]).unwrap() == dev_id as u32);
+
+        let f = std::fs::File::open(wait_bdev(dev_id)).unwrap();
+        let mut buf = vec![0_u8; 4096];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        ctrl.get_info().unwrap();
+        assert!(ctrl.dev_info.state == sys::UBLK_S_DEV_LIVE as u16);
+        drop(f);
+
+        let res = mgr.remove(dev_id as u32).unwrap().unwrap();
+        assert!(res.len() == 2 && res.iter().all(|r| r.is_ok()));
+        assert!(!Path::new(&format!("{}{}", libublk::BDEV_PATH, dev_id)).exists());
     }
 
-    fn null_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
-        let iod = ctx.get_iod(io.get_tag());
-        let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+    /// inspect and stop one ublk-null via control socket
+    #[test]
+    fn test_ublk_null_control() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("ublk.sock").display().to_string();
+        let mut sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .control_socket(sock.clone())
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        sess.set_target_cmd_hook(|cmd: &str, args: &serde_json::Value| match cmd {
+            "flush" => Ok(serde_json::json!({ "flushed": args["sync"] })),
+            _ => Err(UblkError::OtherError(-libc::EINVAL)),
+        });
+
+        let (ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+        let handle = sess.start(ctrl, dev, null_handle_io).unwrap();
+
+        let mut client = UblkControlClient::connect(&sock).unwrap();
+        let status = client.status().unwrap();
+        assert!(status["dev_id"] == handle.dev_id());
+        assert!(status["state"] == "LIVE" && status["nr_queues"] == 2);
+        assert!(std::fs::metadata(&sock).unwrap().permissions().mode() & 0o777 == 0o600);
+
+        // the 1st connection is still open, and doesn't block others
+        let mut client2 = UblkControlClient::connect(&sock).unwrap();
+        assert!(client2.status().unwrap()["dev_id"] == handle.dev_id());
+        drop(client2);
+        assert!(client.stats(true).unwrap()["queues"].is_array());
+        assert!(client.queues().unwrap().as_array().unwrap().len() == 2);
+
+        // queues are asked for stats snapshot without periodic publishing
+        {
+            use std::io::Read;
+
+            let mut buf = vec![0_u8; 4096];
+            let mut f = std::fs::File::open(wait_bdev(handle.dev_id() as i32)).unwrap();
+            f.read_exact(&mut buf).unwrap();
+        }
+        let read = sys::UBLK_IO_OP_READ as usize;
+        assert!(client.stats(false).unwrap()["op_ios"][read].as_u64() > Some(0));
+        client.set_log_level(log::LevelFilter::Debug).unwrap();
+
+        let res = client
+            .target_cmd("flush", serde_json::json!({ "sync": true }))
+            .unwrap();
+        assert!(res["flushed"] == true);
+        let res = client.target_cmd("snapshot", serde_json::json!({}));
+        assert!(matches!(res, Err(UblkError::RpcError(e, _)) if e == -libc::EINVAL));
+        let res = client.call("no_such_method", serde_json::json!({}));
+        assert!(matches!(res, Err(UblkError::RpcError(-32601, _))));
+
+        client.stop().unwrap();
+        drop(client);
+        assert!(handle.wait().unwrap().iter().all(|r| r.is_ok()));
+        assert!(!Path::new(&sock).exists());
+    }
+
+    /// hand over one ublk-null with backing fd to new session
+    #[test]
+    fn test_ublk_null_handover() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("ublk.sock").display().to_string();
+        let back_file = tempfile::tempfile().unwrap();
+        let ino = back_file.metadata().unwrap().ino();
+        let mut sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .ctrl_flags(sys::UBLK_F_USER_RECOVERY as u64)
+            .control_socket(sock.clone())
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        sess.set_handover_hook(|_| Ok(serde_json::json!({ "generation": 1 })));
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(32_u64 << 20);
+            dev.tgt.fds[1] = back_file.as_raw_fd();
+            dev.tgt.nr_fds = 2;
+            Ok(serde_json::json!({}))
+        };
+        let (ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        let handle = sess.start(ctrl, dev, null_handle_io).unwrap();
+        let dev_id = handle.dev_id();
+
+        // other users can't take over the device
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(std::fs::metadata(&sock).unwrap().permissions().mode() & 0o777 == 0o600);
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                let ok = libc::setuid(65534) == 0
+                    && libublk::upgrade::UblkHandover::take_over(&sock).is_err();
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            let mut status = 0;
+            assert!(pid > 0 && libc::waitpid(pid, &mut status, 0) == pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        }
+
+        // old session leaves after handover, and the device is kept
+        let mut ho = libublk::upgrade::UblkHandover::take_over(&sock).unwrap();
+        assert!(handle.wait().unwrap().iter().all(|r| r.is_ok()));
+        assert!(ho.dev_id() == dev_id);
+        assert!(ho.state()["generation"] == 1);
+        assert!(Path::new(&format!("/dev/ublkb{}", dev_id)).exists());
+        let ctrl = UblkCtrl::new_simple(dev_id as i32, 0).unwrap();
+        assert!(ctrl.json["handover"]["generation"] == 1);
+
+        let fds = ho.take_fds();
+        assert!(fds.len() == 1);
+        let file = std::fs::File::from(fds.into_iter().next().unwrap());
+        assert!(file.metadata().unwrap().ino() == ino);
+
+        // new session takes over the device via user recovery
+        ho.start_user_recover().unwrap();
+        let sess = ho.session_builder().build().unwrap();
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(32_u64 << 20);
+            dev.tgt.fds[1] = file.as_raw_fd();
+            dev.tgt.nr_fds = 2;
+            Ok(serde_json::json!({}))
+        };
+        let (ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        let handle = sess.start(ctrl, dev, null_handle_io).unwrap();
+
+        let ctrl = UblkCtrl::new_simple(dev_id as i32, 0).unwrap();
+        assert!(ctrl.dev_info.state == sys::UBLK_S_DEV_LIVE as u16);
+
+        handle.stop().unwrap();
+        assert!(handle.wait().unwrap().iter().all(|r| r.is_ok()));
+        UblkCtrl::new_simple(dev_id as i32, 0)
+            .unwrap()
+            .del_dev()
+            .unwrap();
+    }
+
+    /// make one ublk-null handling signals, then reload it by SIGHUP, and
+    /// stop it by SIGTERM
+    #[test]
+    fn test_ublk_null_signal() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let mut sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .handle_signals(true)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        // building the session blocks the signals in this pthread
+        let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) };
+        assert!(unsafe { libc::sigismember(&mask, libc::SIGTERM) } == 1);
+
+        let reloads = std::sync::Arc::new(AtomicU32::new(0));
+        let _reloads = reloads.clone();
+        sess.set_reload_hook(move |_ctrl: &mut UblkCtrl| {
+            _reloads.fetch_add(1, Ordering::Relaxed);
+            Ok(0)
+        });
+
+        // signals are blocked in pthreads created by start(), and are sent
+        // to queue 0 pthread, which reads them from signalfd
+        let handle = std::thread::spawn(move || {
+            let (ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.start(ctrl, dev, null_handle_io).unwrap()
+        })
+        .join()
+        .unwrap();
+        let dev_id = handle.dev_id();
+
+        let tid = UblkCtrl::new_simple(dev_id as i32, 0)
+            .unwrap()
+            .get_queue_tid(0)
+            .unwrap();
+        let kill = |sig: i32| unsafe {
+            libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, sig);
+        };
+
+        kill(libc::SIGHUP);
+        kill(libc::SIGUSR1);
+        assert!(wait_until(5000, || reloads.load(Ordering::Relaxed) == 1));
+        assert!(!handle.is_finished());
+
+        kill(libc::SIGTERM);
+        assert!(handle.wait().unwrap().iter().all(|r| r.is_ok()));
+        assert!(!Path::new(&format!("{}{}", libublk::BDEV_PATH, dev_id)).exists());
+    }
+
+    /// add two ublk-null handling signals by UblkManager in one process,
+    /// and both are deleted by one SIGTERM sent to the process from a
+    /// forked child
+    #[test]
+    fn test_ublk_null_signal_process() {
+        use std::io::BufRead;
+        use std::process::{Command, Stdio};
+
+        let mut child = Command::new(get_curr_bin_dir().unwrap().join("examples/manager"))
+            .args(["add", "2"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let ids: Vec<i32> = std::io::BufReader::new(child.stdout.take().unwrap())
+            .lines()
+            .take(2)
+            .map(|l| l.unwrap().strip_prefix("dev id ").unwrap().parse().unwrap())
+            .collect();
+        assert!(ids.len() == 2);
+        for &id in &ids {
+            wait_bdev(id);
+        }
+
+        let pid = child.id() as i32;
+        unsafe {
+            let cpid = libc::fork();
+            if cpid == 0 {
+                libc::kill(pid, libc::SIGTERM);
+                libc::_exit(0);
+            }
+            assert!(cpid > 0);
+            libc::waitpid(cpid, std::ptr::null_mut(), 0);
+        }
+
+        assert!(child.wait().unwrap().success());
+        for id in ids {
+            assert!(!Path::new(&format!("{}{}", libublk::BDEV_PATH, id)).exists());
+        }
+    }
+
+    /// make one ublk-null with explicit queue CPU list, and the applied
+    /// affinity is exported in json
+    #[test]
+    fn test_ublk_null_affinity() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .affinity_policy(UblkAffinityPolicy::Cpus(vec![vec![0], vec![0]]))
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, null_handle_io, move |dev_id| {
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let json = std::fs::read_to_string(ctrl.run_path()).unwrap();
+                let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+                for q in 0..2 {
+                    let queue = &json["queues"][q.to_string()];
+                    assert!(queue["affinity"] == serde_json::json!([0]));
+                    assert!(queue["numa_node"].is_u64() || queue["numa_node"].is_null());
+                }
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
+
+    /// make one ublk-null with FnMut IO closure created for each queue
+    #[test]
+    fn test_ublk_null_factory() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let qids = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _qids = qids.clone();
+        let factory = move |q: u16| {
+            // per-queue state, which is owned by IO closure
+            let mut tags = std::collections::HashSet::new();
+
+            _qids.lock().unwrap().push(q);
+            move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+                let iod = ctx.get_iod(io.get_tag());
+
+                assert!(ctx.q_id == q);
+                tags.insert(io.get_tag());
+                assert!(tags.len() <= ctx.depth as usize);
+                io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+                Ok(0)
+            }
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run_with_factory(&mut ctrl, &dev, factory, move |dev_id| {
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+
+                assert!(Path::new(&dev_path).exists());
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+
+        let mut qids = qids.lock().unwrap().clone();
+        qids.sort();
+        assert!(qids == vec![0, 1]);
+    }
+
+    /// make one ublk-null by `UblkSession::start()`, then stop it from
+    /// the returned device handle
+    #[test]
+    fn test_ublk_null_handle() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(2_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let (ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+        let dev_id = dev.dev_info.dev_id;
+        let handle = sess.start(ctrl, dev, null_handle_io).unwrap();
+
+        assert!(handle.dev_id() == dev_id);
+        assert!(handle.bdev_path() == format!("{}{}", libublk::BDEV_PATH, dev_id));
+        wait_bdev(dev_id as i32);
+        assert!(!handle.is_finished());
+
+        handle.stop().unwrap();
+        let res = handle.wait().unwrap();
+        assert!(res.len() == 2);
+        assert!(res.iter().all(|r| r.is_ok()));
+        assert!(!Path::new(&format!("{}{}", libublk::BDEV_PATH, dev_id)).exists());
+    }
+
+    /// make one ublk-null with SQ smaller than queue depth, and each IO is
+    /// completed after one target NOP IO, so SQ becomes full easily
+    #[test]
+    fn test_ublk_null_sq_full() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(64_u32)
+            .nr_queues(1_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(32_u64 << 20);
+            dev.tgt.sq_depth = 4;
+            Ok(serde_json::json!({}))
+        };
+        let handle_io = move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+            let tag = io.get_tag();
+
+            if io.is_tgt_io() {
+                let iod = ctx.get_iod(tag);
+                let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+
+                io.complete_io(bytes);
+            } else {
+                let data = UblkIOCtx::build_user_data(tag as u16, 0, 0, true);
+                let sqe = io_uring::opcode::Nop::new().build().user_data(data);
+
+                unsafe { io.push_async(sqe) };
+            }
+            Ok(0)
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+                use std::io::Read;
+
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 1 << 20];
+
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                for _ in 0..8 {
+                    f.read_exact(&mut buf).unwrap();
+                }
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
+
+    fn __test_ublk_null_chain(fail: bool) {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(16_u32)
+            .nr_queues(1_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let handle_io = move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+            let iod = ctx.get_iod(io.get_tag());
+            let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+
+            // called once for the whole chain
+            if io.is_tgt_io() {
+                let res = io.result();
+
+                assert!(UblkIOCtx::user_data_to_op(io.user_data()) == 1);
+                assert!(if fail { res == -libc::EBADF } else { res == 0 });
+                io.complete_io(if res < 0 { res } else { bytes });
+            } else {
+                let first = if fail {
+                    io_uring::opcode::Fsync::new(io_uring::types::Fd(-1)).build()
+                } else {
+                    io_uring::opcode::Nop::new().build()
+                };
+                let chain = libublk::io::UblkIOChain::new()
+                    .link(first)
+                    .link(io_uring::opcode::Nop::new().build());
+
+                unsafe { io.submit_chain(chain, 1, 0)? };
+            }
+            Ok(0)
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+                use std::io::Read;
+
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
+
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                assert!(f.read_exact(&mut buf).is_err() == fail);
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
+
+    /// handle IO by linked SQE chain, and IO closure is called once with
+    /// result of the whole chain
+    #[test]
+    fn test_ublk_null_chain() {
+        __test_ublk_null_chain(false);
+        __test_ublk_null_chain(true);
+    }
+
+    /// error returned from IO closure should fail the IO command
+    #[test]
+    fn test_ublk_null_io_error() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(16_u32)
+            .nr_queues(1_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let handle_io = move |_ctx: &UblkQueueCtx, _io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+            Err(UblkError::OtherError(-libc::EIO))
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+                use std::io::Read;
+
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
+
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                assert!(f.read_exact(&mut buf).is_err());
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
+
+    /// IO closure returning error before doing anything for the IO is
+    /// retried, and the IO succeeds finally
+    #[test]
+    fn test_ublk_null_io_retry() {
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(16_u32)
+            .nr_queues(1_u32)
+            .err_policy(libublk::io::UblkErrorPolicy::Retry(2))
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        // fail the first call for each IO command
+        let factory = |_q: u16| {
+            let mut failed = None;
+
+            move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+                if failed != Some(io.get_tag()) {
+                    failed = Some(io.get_tag());
+                    return Err(UblkError::OtherError(-libc::EAGAIN));
+                }
+                failed = None;
+                null_handle_io(ctx, io)
+            }
+        };
 
-        io.complete_io(bytes);
-        Ok(0)
-    }
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run_with_factory(&mut ctrl, &dev, factory, move |dev_id| {
+                use std::io::Read;
 
-    fn null_handle_io_batch(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
-        let iod = ctx.get_iod(io.get_tag());
-        let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
 
-        io.add_to_comp_batch(io.get_tag() as u16, bytes);
-        Ok(libublk::io::UBLK_IO_S_COMP_BATCH)
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                for _ in 0..16 {
+                    f.read_exact(&mut buf).unwrap();
+                }
+
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+
+        let stats = sess.get_stats();
+        assert!(stats.retries > 0 && stats.retries == stats.handler_errors);
+        assert!(stats.failed_ios == 0);
     }
 
-    fn __test_ublk_null(dev_flags: u32) {
+    /// make one ublk-null which panics in IO closure, and the IO should be
+    /// failed, meantime the queue keeps working
+    #[test]
+    fn test_ublk_null_panic() {
         let sess = UblkSessionBuilder::default()
             .name("null")
-            .depth(64_u32)
-            .nr_queues(2_u32)
-            .dev_flags(dev_flags)
+            .depth(16_u32)
+            .nr_queues(1_u32)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
             .build()
             .unwrap();
 
-        let tgt_init = |dev: &mut UblkDev| {
-            dev.set_default_params(250_u64 << 30);
-            Ok(serde_json::json!({}))
+        let handle_io = move |_ctx: &UblkQueueCtx, _io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+            panic!("ublk-null panic test");
         };
 
         let wh = {
-            let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
-            let handle_io =
-                move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
-                    if dev_flags & libublk::UBLK_DEV_F_COMP_BATCH != 0 {
-                        null_handle_io_batch(ctx, io)
-                    } else {
-                        null_handle_io(ctx, io)
-                    }
-                };
-
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
             sess.run(&mut ctrl, &dev, handle_io, move |dev_id| {
+                use std::io::Read;
+
                 let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-                let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
+                let dev_path = wait_bdev(dev_id);
+                let mut buf = vec![0_u8; 4096];
 
-                std::thread::sleep(std::time::Duration::from_millis(500));
+                let mut f = std::fs::File::open(&dev_path).unwrap();
+                assert!(f.read_exact(&mut buf).is_err());
+                assert!(f.read_exact(&mut buf).is_err());
 
-                assert!(ctrl.get_target_flags_from_json().unwrap() == dev_flags);
+                ctrl.del().unwrap();
+            })
+            .unwrap()
+        };
+        wh.join().unwrap();
+    }
 
-                //ublk block device should be observed now
-                assert!(Path::new(&dev_path).exists() == true);
+    fn __test_ublk_null_panic_policy(policy: libublk::io::UblkPanicPolicy) {
+        use libublk::io::UblkPanicPolicy;
+        use std::os::unix::fs::FileExt;
+        use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+        use std::sync::Arc;
 
-                //ublk exported json file should be observed
-                assert!(Path::new(&ctrl.run_path()).exists() == true);
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .depth(16_u32)
+            .nr_queues(1_u32)
+            .ctrl_flags(sys::UBLK_F_USER_RECOVERY as u64)
+            .panic_policy(policy)
+            .dev_flags(libublk::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        // the 1st IO panics, and IO closure is re-created after the queue
+        // is recovered
+        let panicked = Arc::new(AtomicBool::new(false));
+        let nr_handlers = Arc::new(AtomicU32::new(0));
+        let (p, n) = (panicked.clone(), nr_handlers.clone());
+        let factory = move |_q: u16| {
+            let p = p.clone();
+
+            n.fetch_add(1, Ordering::Relaxed);
+            move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| -> Result<i32, UblkError> {
+                if !p.swap(true, Ordering::Relaxed) {
+                    panic!("ublk-null panic policy test");
+                }
+                null_handle_io(ctx, io)
+            }
+        };
+
+        let wh = {
+            let (mut ctrl, dev) = sess.create_devices(null_tgt_init).unwrap();
+            sess.run_with_factory(&mut ctrl, &dev, factory, move |dev_id| {
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let f = std::fs::File::open(wait_bdev(dev_id)).unwrap();
+                let mut buf = vec![0_u8; 4096];
+
+                assert!(f.read_exact_at(&mut buf, 0).is_err());
+
+                // IO is blocked until the device becomes LIVE again
+                f.read_exact_at(&mut buf, 0).unwrap();
+                ctrl.get_info().unwrap();
+                assert!(ctrl.dev_info.state == sys::UBLK_S_DEV_LIVE as u16);
 
                 ctrl.del().unwrap();
             })
             .unwrap()
         };
         wh.join().unwrap();
-    }
 
-    /// make one ublk-null and test if /dev/ublkbN can be created successfully
-    #[test]
-    fn test_ublk_null() {
-        __test_ublk_null(libublk::UBLK_DEV_F_ADD_DEV);
+        let nr = if matches!(policy, UblkPanicPolicy::AbortQueue) {
+            2
+        } else {
+            1
+        };
+        assert!(panicked.load(Ordering::Relaxed));
+        assert!(nr_handlers.load(Ordering::Relaxed) == nr);
     }
 
-    /// make one ublk-null and test if /dev/ublkbN can be created successfully
+    /// panic with USER_RECOVERY: the queue keeps working after failing
+    /// inflight IOs, or the aborted queue is recovered in process and the
+    /// device becomes LIVE again
     #[test]
-    fn test_ublk_null_comp_batch() {
-        __test_ublk_null(libublk::UBLK_DEV_F_ADD_DEV | libublk::UBLK_DEV_F_COMP_BATCH);
+    fn test_ublk_null_panic_recovery() {
+        __test_ublk_null_panic_policy(libublk::io::UblkPanicPolicy::FailInflight);
+        __test_ublk_null_panic_policy(libublk::io::UblkPanicPolicy::AbortQueue);
     }
 
     fn rd_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx, start: u64) -> Result<i32, UblkError> {
@@ -168,9 +1321,7 @@ mod tests {
 
     fn __test_ublk_ramdisk(dev_id: i32) {
         let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-        let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
-
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        let dev_path = wait_bdev(dev_id);
 
         //ublk block device should be observed now
         assert!(Path::new(&dev_path).exists() == true);
@@ -258,16 +1409,7 @@ mod tests {
     }
 
     fn __test_fn_mut_io_closure() -> std::thread::JoinHandle<()> {
-        let mut ctrl = UblkCtrl::new(-1, 1, 64, 512 << 10, 0, libublk::UBLK_DEV_F_ADD_DEV).unwrap();
-        let ublk_dev = UblkDev::new(
-            "FnMutClosure".to_string(),
-            |dev: &mut UblkDev| {
-                dev.set_default_params(32_u64 << 20);
-                Ok(serde_json::json!({}))
-            },
-            &mut ctrl,
-        )
-        .unwrap();
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
 
         // modify this vector in io handling closure
         let mut q_vec = Vec::<i32>::new();
@@ -275,7 +1417,7 @@ mod tests {
         let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
         let ctx = queue.make_queue_ctx();
         // FuMut closure for handling our io_uring IO
-        let mut qc = move |i: &mut UblkIOCtx| {
+        let qc = move |i: &mut UblkIOCtx| {
             let tag = i.get_tag();
             q_vec.push(tag as i32);
             if q_vec.len() >= 64 {
@@ -287,28 +1429,419 @@ mod tests {
             Ok(0)
         };
 
-        ctrl.configure_queue(&ublk_dev, 0, unsafe { libc::gettid() })
-            .unwrap();
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |_| {});
+        ctrl.stop_dev(&ublk_dev).unwrap();
 
-        ctrl.start_dev_in_queue(&ublk_dev, &mut queue, &mut qc)
-            .unwrap();
+        qh
+    }
 
-        let dev_id = ctrl.dev_info.dev_id as i32;
-        let qh = std::thread::spawn(move || {
-            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-            ctrl.del().unwrap();
+    /// make FnMut closure for IO handling
+    #[test]
+    fn test_fn_mut_io_closure() {
+        __test_fn_mut_io_closure().join().unwrap();
+    }
+
+    fn __test_ublk_null_completer(dup: bool) -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let ctx = queue.make_queue_ctx();
+        let completer = queue.completer().unwrap();
+
+        // IO is handled in worker context, and completed via completer
+        let (tx, rx) = std::sync::mpsc::channel::<(u16, i32)>();
+        let wh = std::thread::spawn(move || {
+            while let Ok((tag, res)) = rx.recv() {
+                completer.complete(tag, res).unwrap();
+
+                // duplicated completion is dropped
+                if dup {
+                    completer.complete(tag, res).unwrap();
+                }
+            }
         });
+        let qc = move |i: &mut UblkIOCtx| {
+            let tag = i.get_tag();
+            let iod = ctx.get_iod(tag);
 
-        queue.wait_and_handle_io(&mut qc);
+            tx.send((tag as u16, unsafe { (*iod).nr_sectors << 9 } as i32))
+                .unwrap();
+            Ok(0)
+        };
+
+        // the worker exits after `qc` is dropped
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |path| {
+            read_bdev(path, 16)
+        });
         ctrl.stop_dev(&ublk_dev).unwrap();
+        wh.join().unwrap();
+
+        // every IO is committed once, and each duplicated one is dropped
+        let stats = queue.get_stats();
+        let nr_ios: u64 = stats.op_ios.iter().sum();
+        assert!(nr_ios >= 16 && stats.inflight == 0);
+        assert!(stats.lat_hist.iter().sum::<u64>() == nr_ios);
+        assert!(stats.dropped_comps == if dup { nr_ios } else { 0 });
 
         qh
     }
 
-    /// make FnMut closure for IO handling
+    /// complete IO from another thread via UblkCompleter
     #[test]
-    fn test_fn_mut_io_closure() {
-        __test_fn_mut_io_closure().join().unwrap();
+    fn test_ublk_null_completer() {
+        __test_ublk_null_completer(false).join().unwrap();
+    }
+
+    /// completing the same tag twice doesn't commit it twice
+    #[test]
+    fn test_ublk_null_completer_dup() {
+        __test_ublk_null_completer(true).join().unwrap();
+    }
+
+    fn __test_ublk_null_state() -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        // count of handled CQEs for each IO
+        let mut queue = UblkQueue::<u32>::with_state(0, &ublk_dev).unwrap();
+        let ctx = queue.make_queue_ctx();
+        let qc = move |io: &mut UblkIOCtx<u32>| {
+            let tag = io.get_tag();
+
+            *io.state_mut() += 1;
+            if io.is_tgt_io() {
+                let iod = ctx.get_iod(tag);
+
+                // state is reset when the tag is re-fetched
+                assert!(*io.state() == 2);
+                io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+            } else {
+                let data = UblkIOCtx::build_user_data(tag as u16, 0, 0, true);
+                let sqe = io_uring::opcode::Nop::new().build().user_data(data);
+
+                assert!(*io.state() == 1);
+                unsafe { io.push_async(sqe) };
+            }
+            Ok(0)
+        };
+
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |path| {
+            read_bdev(path, 16)
+        });
+        ctrl.stop_dev(&ublk_dev).unwrap();
+
+        qh
+    }
+
+    /// per-IO typed target state
+    #[test]
+    fn test_ublk_null_state() {
+        __test_ublk_null_state().join().unwrap();
+    }
+
+    fn __test_ublk_null_extra_io() -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(|dev: &mut UblkDev| {
+            dev.tgt.extra_ios = 1;
+            null_tgt_init(dev)
+        });
+        let depth = ctrl.dev_info.queue_depth;
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let mut buf = vec![0_u8; 4096];
+        let buf_addr = buf.as_mut_ptr();
+        let meta = queue.alloc_extra_io(buf_addr).unwrap();
+        assert!(meta.tag() == depth);
+        assert!(queue.alloc_extra_io(std::ptr::null_mut()).is_none());
+
+        // metadata IO is completed to its own handler
+        let nr_meta = std::rc::Rc::new(std::cell::Cell::new(0));
+        let nr = nr_meta.clone();
+        queue.set_extra_io_handler(move |io: &mut UblkIOCtx| {
+            assert!(io.get_tag() == depth as u32);
+            assert!(io.io_buf_addr() == buf_addr);
+            nr.set(nr.get() + 1);
+            Ok(0)
+        });
+
+        let ctx = queue.make_queue_ctx();
+        let data = meta.user_data(1, 0);
+        let qc = move |io: &mut UblkIOCtx| {
+            let iod = ctx.get_iod(io.get_tag());
+            let sqe = io_uring::opcode::Nop::new().build().user_data(data);
+
+            assert!(!io.is_tgt_io());
+            unsafe { io.push_async(sqe) };
+            io.complete_io(unsafe { (*iod).nr_sectors << 9 } as i32);
+            Ok(0)
+        };
+
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |_| {});
+        ctrl.stop_dev(&ublk_dev).unwrap();
+
+        assert!(nr_meta.get() > 0);
+        queue.free_extra_io(meta);
+        assert!(queue.alloc_extra_io(std::ptr::null_mut()).is_some());
+        drop(buf);
+
+        qh
+    }
+
+    /// target metadata IO on extra IO slot
+    #[test]
+    fn test_ublk_null_extra_io() {
+        __test_ublk_null_extra_io().join().unwrap();
+    }
+
+    fn __test_ublk_null_timeout(nr_deadlines: u32) -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let ticks = std::rc::Rc::new(std::cell::Cell::new(0));
+        let t = ticks.clone();
+        queue.add_timer(std::time::Duration::from_millis(10), true, move |_, _| {
+            t.set(t.get() + 1)
+        });
+
+        // cancelled timer never fires
+        let id = queue.add_timer(std::time::Duration::from_millis(10), false, |_, _| {
+            panic!("cancelled timer fires")
+        });
+        queue.cancel_timer(id);
+
+        // target IO never completes, so it is canceled after deadline
+        let ts = io_uring::types::Timespec::new().sec(100);
+        let qc = move |io: &mut UblkIOCtx| {
+            let data = UblkIOCtx::build_user_data(io.get_tag() as u16, 1, 0, true);
+            let sqe = io_uring::opcode::Timeout::new(&ts).build().user_data(data);
+
+            assert!(!io.is_tgt_io());
+            unsafe { io.push_async(sqe) };
+
+            // each one replaces the previous, and only the last one fires
+            for _ in 0..nr_deadlines {
+                io.set_deadline(data, std::time::Duration::from_millis(10));
+            }
+            Ok(0)
+        };
+
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |path| {
+            use std::io::Read;
+
+            let mut buf = vec![0_u8; 4096];
+            let mut f = std::fs::File::open(path).unwrap();
+            assert!(f.read_exact(&mut buf).is_err());
+        });
+        ctrl.stop_dev(&ublk_dev).unwrap();
+
+        assert!(ticks.get() > 0);
+        assert!(queue.get_stats().io_timeouts > 0);
+
+        qh
+    }
+
+    /// queue timer and target IO deadline
+    #[test]
+    fn test_ublk_null_timeout() {
+        __test_ublk_null_timeout(1).join().unwrap();
+    }
+
+    /// deadline sequence of one tag is still matched after it wraps 8bit
+    #[test]
+    fn test_ublk_null_timeout_rearm() {
+        __test_ublk_null_timeout(300).join().unwrap();
+    }
+
+    fn __test_ublk_null_idle() -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let nr_idle = std::rc::Rc::new(std::cell::Cell::new(0));
+        let n = nr_idle.clone();
+
+        // idle sequence wraps 8bit before the timer is armed finally
+        for _ in 0..300 {
+            queue.set_idle_timeout(None);
+        }
+        queue.set_idle_timeout(Some(std::time::Duration::from_millis(20)));
+        queue.set_idle_handler(move |_| n.set(n.get() + 1));
+
+        let ctx = queue.make_queue_ctx();
+        let qc = move |io: &mut UblkIOCtx| null_handle_io(&ctx, io);
+
+        // enter idle, then leave it by IO not cached, and enter idle again
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, |path| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            read_bdev(path, 1);
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        });
+        ctrl.stop_dev(&ublk_dev).unwrap();
+
+        assert!(queue.get_stats().idle_enters >= 2);
+        assert!(nr_idle.get() as u64 == queue.get_stats().idle_enters);
+
+        qh
+    }
+
+    /// idle queue releases io buffers
+    #[test]
+    fn test_ublk_null_idle() {
+        __test_ublk_null_idle().join().unwrap();
+    }
+
+    fn __test_ublk_null_stop() -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let stop = queue.stop_handle().unwrap();
+        let ctx = queue.make_queue_ctx();
+        let qc = move |io: &mut UblkIOCtx| null_handle_io(&ctx, io);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, move |path| {
+            read_bdev(path, 1);
+            stop.stop().unwrap();
+
+            // the device is still live after the queue leaves its loop
+            rx.recv().unwrap();
+            assert!(Path::new(path).exists());
+        });
+        assert!(queue.is_stopped());
+        tx.send(()).unwrap();
+        ctrl.stop_dev(&ublk_dev).unwrap();
+
+        qh
+    }
+
+    /// leave queue loop by stop handle
+    #[test]
+    fn test_ublk_null_stop() {
+        __test_ublk_null_stop().join().unwrap();
+    }
+
+    fn __test_ublk_null_stop_busy() -> std::thread::JoinHandle<()> {
+        let (mut ctrl, ublk_dev) = add_queue_dev(null_tgt_init);
+
+        let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
+        let stop = queue.stop_handle().unwrap();
+        let ctx = queue.make_queue_ctx();
+        let qc = move |io: &mut UblkIOCtx| null_handle_io(&ctx, io);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let qh = serve_queue(&mut ctrl, &ublk_dev, &mut queue, qc, move |path| {
+            use std::process::{Command, Stdio};
+
+            let dd = format!(
+                "while dd if={} of=/dev/null bs=4k iflag=direct status=none; do :; done",
+                path
+            );
+
+            // keep the queue busy until it is stopped
+            let mut jobs: Vec<_> = (0..4)
+                .map(|_| {
+                    Command::new("sh")
+                        .args(["-c", &dd])
+                        .stderr(Stdio::null())
+                        .spawn()
+                        .unwrap()
+                })
+                .collect();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            stop.stop().unwrap();
+
+            // IO left by the stopped queue is failed after the queue and
+            // device are dropped, then all dd jobs exit
+            rx.recv().unwrap();
+            for job in jobs.iter_mut() {
+                job.wait().unwrap();
+            }
+        });
+        assert!(queue.is_stopped());
+        drop(queue);
+        drop(ublk_dev);
+        tx.send(()).unwrap();
+
+        qh
+    }
+
+    /// leave queue loop by stop handle while IO is still coming
+    #[test]
+    fn test_ublk_null_stop_busy() {
+        __test_ublk_null_stop_busy().join().unwrap();
+    }
+
+    fn __test_ublk_event_loop() -> Vec<std::thread::JoinHandle<()>> {
+        let mut ctrls = Vec::new();
+        let mut devs = Vec::new();
+
+        for _ in 0..2 {
+            let mut ctrl =
+                UblkCtrl::new(-1, 2, 32, 512 << 10, 0, libublk::UBLK_DEV_F_ADD_DEV).unwrap();
+
+            devs.push(UblkDev::new("null".to_string(), null_tgt_init, &mut ctrl).unwrap());
+            ctrls.push(ctrl);
+        }
+
+        // devices are borrowed by the loop, so they are dropped after it
+        let mut event_loop = UblkEventLoop::new(Default::default(), 128, 256, 4).unwrap();
+        for (ctrl, dev) in ctrls.iter_mut().zip(devs.iter()) {
+            event_loop.add_dev(dev).unwrap();
+            for q in 0..2 {
+                ctrl.configure_queue(dev, q, unsafe { libc::gettid() })
+                    .unwrap();
+            }
+            // IO may come before START_DEV completes, so handle it in loop
+            ctrl.start_dev_in_loop(dev, &mut event_loop, null_handle_io)
+                .unwrap();
+        }
+        assert!(event_loop.nr_devs() == 2);
+
+        // remove the 1st device from loop at runtime, and it isn't stopped
+        let removed = ctrls[0].dev_info.dev_id;
+        event_loop.remove_dev(removed, null_handle_io).unwrap();
+        assert!(event_loop.nr_devs() == 1);
+        assert!(event_loop.remove_dev(removed, null_handle_io).is_err());
+        ctrls[0].get_info().unwrap();
+        assert!(ctrls[0].dev_info.state == sys::UBLK_S_DEV_LIVE as u16);
+
+        // device removal waits until /dev/ublkcN is released, so delete
+        // each device in its own context
+        let qhs = ctrls
+            .iter()
+            .map(|ctrl| {
+                let dev_id = ctrl.dev_info.dev_id;
+
+                std::thread::spawn(move || {
+                    use std::io::Read;
+
+                    let mut ctrl = UblkCtrl::new_simple(dev_id as i32, 0).unwrap();
+                    let dev_path = wait_bdev(dev_id as i32);
+                    let mut buf = vec![0_u8; 4096];
+
+                    if dev_id != removed {
+                        let mut f = std::fs::File::open(&dev_path).unwrap();
+                        f.read_exact(&mut buf).unwrap();
+                    }
+                    ctrl.del().unwrap();
+                })
+            })
+            .collect();
+
+        event_loop.wait_and_handle_io(null_handle_io);
+        assert!(event_loop.nr_devs() == 0);
+        drop(event_loop);
+        for (ctrl, dev) in ctrls.iter_mut().zip(devs.iter()) {
+            ctrl.stop_dev(dev).unwrap();
+        }
+
+        qhs
+    }
+
+    /// serve two ublk-null devices from one pthread and io_uring, and
+    /// remove one of them at runtime
+    #[test]
+    fn test_ublk_event_loop() {
+        for qh in __test_ublk_event_loop() {
+            qh.join().unwrap();
+        }
     }
 
     fn get_curr_bin_dir() -> Option<std::path::PathBuf> {
@@ -357,10 +1890,8 @@ mod tests {
             .stdout(Stdio::from(file))
             .spawn()
             .expect("fail to add ublk ramdisk");
-        cmd.wait().unwrap();
-
-        //this magic wait makes a difference
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+        // `ramdisk add` returns after the device becomes LIVE
+        assert!(cmd.wait().unwrap().success());
         let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
 
         let id_regx = regex::Regex::new(r"dev id (\d+)").unwrap();